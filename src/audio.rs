@@ -0,0 +1,553 @@
+#[cfg(feature = "audio")]
+extern crate cpal;
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::hardware::apu::{Apu, StereoSample, SAMPLE_RATE};
+use crate::wav::WavWriter;
+
+/*
+
+Audio output. The APU produces samples at 1048576 Hz, on their way to the
+audio device they get:
+  1. Low-pass filtered below 65536 Hz and decimated by 8, which brings them
+     down to 131072 Hz without folding anything into the audible range
+  2. Resampled to the device rate with a windowed sinc filter that cuts
+     off everything above 45% of the device rate, so nothing aliases
+  3. Queued in a ring buffer that the audio device callback reads from
+
+The emulation and the audio device never run at exactly the same speed.
+Dynamic rate control nudges the resampling ratio by up to 0.5% depending
+on how full the ring buffer is, which keeps it around half full without
+audible pitch changes. If the buffer still runs dry the last sample is
+held instead of dropping to zero, which would click.
+
+Recordings go through the same resampler, without rate control, into
+16 bit WAV files at 44100 Hz. Next to the mixed output every channel can
+be recorded on its own, into <name>.ch1.wav to <name>.ch4.wav.
+
+Talking to the audio device uses cpal and is only built with the "audio"
+feature, because it needs the ALSA development files on Linux. Without it
+the emulator runs silently.
+
+*/
+
+const DECIMATION: usize = 8;
+// Length of the decimation filter, enough for its transition to end before
+// the first frequency that would alias below the resampler cutoff
+const DECIMATION_TAPS: usize = 96;
+// Amount of zero crossings of the sinc on each side of the kernel
+const ZERO_CROSSINGS: f64 = 16.0;
+// Kernel table entries per input sample
+const KERNEL_RESOLUTION: usize = 64;
+const CUTOFF: f64 = 0.45;
+const MAX_RATE_DEVIATION: f64 = 0.005;
+#[cfg(feature = "audio")]
+const BUFFER_MILLISECONDS: u32 = 100;
+pub const RECORDING_SAMPLE_RATE: u32 = 44100;
+
+/// Resamples APU output to a different rate
+pub struct Resampler
+{
+    decimated_rate: f64,
+    output_rate: f64,
+    adjustment: f64,
+    half_width: usize,
+    kernel: Vec<f32>,
+    decimation_taps: Vec<f32>,
+    // The last DECIMATION_TAPS input samples
+    decimation_input: VecDeque<StereoSample>,
+    decimation_count: usize,
+    history: Vec<StereoSample>,
+    // Time of the next output sample, in decimated samples from the start of history
+    position: f64,
+}
+
+impl Resampler
+{
+    pub fn new(input_rate: u32, output_rate: u32) -> Resampler
+    {
+        let decimated_rate = input_rate as f64 / DECIMATION as f64;
+        // Cutoff frequency in cycles per decimated sample
+        let cutoff = (CUTOFF * output_rate as f64 / decimated_rate).min(0.5);
+        let half_width = (ZERO_CROSSINGS / (2.0 * cutoff)).ceil() as usize;
+        let kernel = (0..=half_width * KERNEL_RESOLUTION)
+            .map(|index| windowed_sinc(index as f64 / KERNEL_RESOLUTION as f64, cutoff, half_width as f64) as f32)
+            .collect();
+        // Cuts off at the Nyquist frequency of the decimated rate, normalized to keep the level
+        let center = (DECIMATION_TAPS - 1) as f64 / 2.0;
+        let taps: Vec<f64> = (0..DECIMATION_TAPS)
+            .map(|index| windowed_sinc(index as f64 - center, 0.5 / DECIMATION as f64, DECIMATION_TAPS as f64 / 2.0))
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        Resampler {
+            decimated_rate,
+            output_rate: output_rate as f64,
+            adjustment: 1.0,
+            half_width,
+            kernel,
+            decimation_taps: taps.iter().map(|tap| (tap / sum) as f32).collect(),
+            decimation_input: vec![[0.0; 2]; DECIMATION_TAPS].into(),
+            decimation_count: 0,
+            // Start out with silence, so the first samples have a history
+            history: vec![[0.0; 2]; half_width],
+            position: half_width as f64,
+        }
+    }
+
+    /// Changes the output rate by the given factor, used for dynamic rate control
+    pub fn set_adjustment(&mut self, adjustment: f64)
+    {
+        self.adjustment = adjustment;
+    }
+
+    fn kernel_at(&self, distance: f64) -> f32
+    {
+        let position = distance.abs() * KERNEL_RESOLUTION as f64;
+        let index = position as usize;
+        if index + 1 >= self.kernel.len()
+        {
+            return 0.0;
+        }
+        let fraction = (position - index as f64) as f32;
+        self.kernel[index] + (self.kernel[index + 1] - self.kernel[index]) * fraction
+    }
+
+    fn interpolate(&self, position: f64) -> StereoSample
+    {
+        let center = position as usize;
+        let mut sample = [0.0; 2];
+        for index in center + 1 - self.half_width..=center + self.half_width
+        {
+            let weight = self.kernel_at(position - index as f64);
+            sample[0] += self.history[index][0] * weight;
+            sample[1] += self.history[index][1] * weight;
+        }
+        sample
+    }
+
+    /// Resamples the given samples and appends them interleaved to output
+    pub fn process(&mut self, samples: &[StereoSample], output: &mut Vec<f32>)
+    {
+        for &sample in samples
+        {
+            self.decimation_input.pop_front();
+            self.decimation_input.push_back(sample);
+            self.decimation_count += 1;
+            if self.decimation_count == DECIMATION
+            {
+                self.decimation_count = 0;
+                let mut filtered = [0.0; 2];
+                for (input, tap) in self.decimation_input.iter().zip(&self.decimation_taps)
+                {
+                    filtered[0] += input[0] * tap;
+                    filtered[1] += input[1] * tap;
+                }
+                self.history.push(filtered);
+            }
+        }
+
+        let step = self.decimated_rate / (self.output_rate * self.adjustment);
+        while (self.position as usize) + self.half_width < self.history.len()
+        {
+            let sample = self.interpolate(self.position);
+            output.extend_from_slice(&sample);
+            self.position += step;
+        }
+
+        // Only keep what the kernel still needs
+        let consumed = (self.position as usize).saturating_sub(self.half_width);
+        self.history.drain(..consumed);
+        self.position -= consumed as f64;
+    }
+}
+
+/// Low-pass filter kernel at distance x from its center, cutoff in cycles per sample,
+/// with a Blackman window that ends half_width samples from the center
+fn windowed_sinc(x: f64, cutoff: f64, half_width: f64) -> f64
+{
+    let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * cutoff * x).sin() / (2.0 * PI * cutoff * x) };
+    let phase = PI * (x / half_width + 1.0);
+    let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+    2.0 * cutoff * sinc * window
+}
+
+/// How much faster the output has to be produced for the given ring buffer fill level
+pub fn rate_adjustment(fill: f32) -> f64
+{
+    1.0 + MAX_RATE_DEVIATION * (1.0 - 2.0 * fill.clamp(0.0, 1.0) as f64)
+}
+
+/// Ring buffer of interleaved stereo samples, shared with the audio device
+#[derive(Clone)]
+pub struct SampleQueue
+{
+    samples: Arc<Mutex<VecDeque<f32>>>,
+    capacity: usize,
+}
+
+impl SampleQueue
+{
+    pub fn new(capacity: usize) -> SampleQueue
+    {
+        SampleQueue {
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Appends samples, whatever does not fit gets dropped
+    pub fn push(&self, samples: &[f32])
+    {
+        let mut queue = self.samples.lock().unwrap();
+        let free = (self.capacity - queue.len()) & !1;
+        queue.extend(samples.iter().take(free));
+    }
+
+    /// Fills output with stereo frames of the given channel count, holding the
+    /// last frame if the queue runs dry. Mono devices get both sides mixed.
+    pub fn pop_frames(&self, output: &mut [f32], channels: usize)
+    {
+        let mut queue = self.samples.lock().unwrap();
+        let mut last = [0.0; 2];
+        for frame in output.chunks_mut(channels)
+        {
+            if queue.len() >= 2
+            {
+                last = [queue.pop_front().unwrap(), queue.pop_front().unwrap()];
+            }
+            for (channel, value) in frame.iter_mut().enumerate()
+            {
+                *value = if channels == 1 { (last[0] + last[1]) / 2.0 } else if channel < 2 { last[channel] } else { 0.0 };
+            }
+        }
+        // Put the held frame back, so the next callback continues from it
+        if queue.is_empty()
+        {
+            queue.extend(last.iter());
+        }
+    }
+
+    pub fn fill_level(&self) -> f32
+    {
+        self.samples.lock().unwrap().len() as f32 / self.capacity as f32
+    }
+}
+
+pub struct AudioOutput
+{
+    resampler: Resampler,
+    queue: SampleQueue,
+    buffer: Vec<f32>,
+    #[cfg(feature = "audio")]
+    _stream: cpal::Stream,
+}
+
+impl AudioOutput
+{
+    #[cfg(feature = "audio")]
+    pub fn open() -> Result<AudioOutput, String>
+    {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let host = cpal::default_host();
+        let device = host.default_output_device().ok_or("No audio output device found")?;
+        let supported = device.default_output_config().map_err(|error| error.to_string())?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+        let channels = config.channels as usize;
+        let output_rate = config.sample_rate.0;
+        let queue = SampleQueue::new((output_rate * BUFFER_MILLISECONDS / 1000) as usize * 2);
+
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone(), channels),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone(), channels),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone(), channels),
+            format => return Err(format!("Unsupported sample format {}", format))
+        }.map_err(|error| error.to_string())?;
+        stream.play().map_err(|error| error.to_string())?;
+
+        Ok(AudioOutput {
+            resampler: Resampler::new(SAMPLE_RATE, output_rate),
+            queue,
+            buffer: vec![],
+            _stream: stream,
+        })
+    }
+
+    #[cfg(not(feature = "audio"))]
+    pub fn open() -> Result<AudioOutput, String>
+    {
+        Err("Built without the audio feature, sound is disabled".to_string())
+    }
+
+    /// Resamples APU output and hands it to the audio device
+    pub fn queue_samples(&mut self, samples: &[StereoSample])
+    {
+        self.resampler.set_adjustment(rate_adjustment(self.queue.fill_level()));
+        self.buffer.clear();
+        self.resampler.process(samples, &mut self.buffer);
+        self.queue.push(&self.buffer);
+    }
+}
+
+struct Track
+{
+    resampler: Resampler,
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl Track
+{
+    fn create(path: &Path) -> io::Result<Track>
+    {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Track {
+            resampler: Resampler::new(SAMPLE_RATE, RECORDING_SAMPLE_RATE),
+            writer: WavWriter::new(file, RECORDING_SAMPLE_RATE)?,
+        })
+    }
+
+    fn record(&mut self, samples: &[StereoSample], buffer: &mut Vec<f32>) -> io::Result<()>
+    {
+        buffer.clear();
+        self.resampler.process(samples, buffer);
+        self.writer.write_samples(buffer)
+    }
+}
+
+/// Records APU output into WAV files
+pub struct AudioRecorder
+{
+    path: PathBuf,
+    mixed: Track,
+    channels: Vec<Track>,
+    channel_buffer: Vec<StereoSample>,
+    buffer: Vec<f32>,
+}
+
+impl AudioRecorder
+{
+    pub fn create(path: &Path, per_channel: bool) -> io::Result<AudioRecorder>
+    {
+        let channels = if per_channel
+        {
+            (1..=4).map(|channel| Track::create(&path.with_extension(format!("ch{}.wav", channel))))
+                .collect::<io::Result<Vec<Track>>>()?
+        }
+        else
+        {
+            vec![]
+        };
+        Ok(AudioRecorder {
+            path: path.to_path_buf(),
+            mixed: Track::create(path)?,
+            channels,
+            channel_buffer: vec![],
+            buffer: vec![],
+        })
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    /// Makes the APU collect everything this recorder needs
+    pub fn enable_output(&self, apu: &mut Apu)
+    {
+        apu.output_enabled = true;
+        apu.channel_output_enabled = !self.channels.is_empty();
+    }
+
+    pub fn record(&mut self, samples: &[StereoSample], channel_samples: &[[StereoSample; 4]]) -> io::Result<()>
+    {
+        self.mixed.record(samples, &mut self.buffer)?;
+        for (channel, track) in self.channels.iter_mut().enumerate()
+        {
+            self.channel_buffer.clear();
+            self.channel_buffer.extend(channel_samples.iter().map(|channels| channels[channel]));
+            track.record(&self.channel_buffer, &mut self.buffer)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()>
+    {
+        self.mixed.writer.finish()?;
+        for track in self.channels
+        {
+            track.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "audio")]
+fn build_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, queue: SampleQueue, channels: usize)
+    -> Result<cpal::Stream, cpal::BuildStreamError>
+    where T: cpal::SizedSample + cpal::FromSample<f32>
+{
+    use cpal::traits::DeviceTrait;
+
+    let mut frames: Vec<f32> = vec![];
+    device.build_output_stream(
+        config,
+        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+            frames.resize(output.len(), 0.0);
+            queue.pop_frames(&mut frames, channels);
+            for (value, sample) in output.iter_mut().zip(frames.iter())
+            {
+                *value = T::from_sample(*sample);
+            }
+        },
+        |error| log::error!("Audio stream error: {error}", error=error),
+        None)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn resample(resampler: &mut Resampler, samples: &[StereoSample]) -> Vec<f32>
+    {
+        let mut output = vec![];
+        resampler.process(samples, &mut output);
+        output
+    }
+
+    #[test]
+    fn produces_output_at_the_requested_rate()
+    {
+        let mut resampler = Resampler::new(SAMPLE_RATE, 48000);
+
+        let output = resample(&mut resampler, &vec![[0.0; 2]; SAMPLE_RATE as usize]);
+
+        // One second of input, minus the samples still waiting for the kernel
+        let frames = output.len() / 2;
+        assert!(frames <= 48000 && frames > 47900, "{} frames", frames);
+    }
+
+    #[test]
+    fn adjustment_changes_output_rate()
+    {
+        let mut resampler = Resampler::new(SAMPLE_RATE, 48000);
+        resampler.set_adjustment(1.005);
+
+        let output = resample(&mut resampler, &vec![[0.0; 2]; SAMPLE_RATE as usize]);
+
+        assert!(output.len() / 2 > 48100);
+    }
+
+    #[test]
+    fn constant_signal_keeps_its_level()
+    {
+        let mut resampler = Resampler::new(SAMPLE_RATE, 44100);
+
+        let output = resample(&mut resampler, &vec![[0.5, -0.25]; SAMPLE_RATE as usize / 10]);
+
+        let last = &output[output.len() - 2..];
+        assert!((last[0] - 0.5).abs() < 0.001, "{}", last[0]);
+        assert!((last[1] + 0.25).abs() < 0.001, "{}", last[1]);
+    }
+
+    #[test]
+    fn frequencies_above_the_output_range_are_removed()
+    {
+        let mut resampler = Resampler::new(SAMPLE_RATE, 48000);
+        // A 32768 Hz square wave would alias to an audible tone
+        let input: Vec<StereoSample> = (0..SAMPLE_RATE as usize / 10)
+            .map(|index| if (index / 16) % 2 == 0 { [1.0; 2] } else { [-1.0; 2] })
+            .collect();
+
+        let output = resample(&mut resampler, &input);
+
+        let peak = output[output.len() / 2..].iter().fold(0.0f32, |peak, value| peak.max(value.abs()));
+        assert!(peak < 0.01, "{}", peak);
+    }
+
+    #[test]
+    fn frequencies_near_the_decimated_rate_do_not_alias()
+    {
+        let mut resampler = Resampler::new(SAMPLE_RATE, 48000);
+        // A 120 kHz tone would fold down to 11 kHz when decimating without a filter
+        let input: Vec<StereoSample> = (0..SAMPLE_RATE as usize / 10)
+            .map(|index| [(2.0 * PI * 120_000.0 * index as f64 / SAMPLE_RATE as f64).sin() as f32; 2])
+            .collect();
+
+        let output = resample(&mut resampler, &input);
+
+        let peak = output[output.len() / 2..].iter().fold(0.0f32, |peak, value| peak.max(value.abs()));
+        assert!(peak < 0.001, "{}", peak);
+    }
+
+    #[test]
+    fn rate_adjustment_depends_on_fill_level()
+    {
+        assert_eq!(1.0 + MAX_RATE_DEVIATION, rate_adjustment(0.0));
+        assert_eq!(1.0, rate_adjustment(0.5));
+        assert_eq!(1.0 - MAX_RATE_DEVIATION, rate_adjustment(1.5));
+    }
+
+    #[test]
+    fn recorder_writes_mixed_and_channel_files()
+    {
+        let directory = std::env::temp_dir().join(format!("rboy-recorder-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("sound.wav");
+        let mut recorder = AudioRecorder::create(&path, true).unwrap();
+
+        recorder.record(&vec![[0.5; 2]; SAMPLE_RATE as usize / 10], &vec![[[0.1; 2]; 4]; SAMPLE_RATE as usize / 10]).unwrap();
+        recorder.finish().unwrap();
+
+        let mixed = std::fs::read(&path).unwrap();
+        let channel = std::fs::read(directory.join("sound.ch4.wav")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        // Roughly 4410 frames of 4 bytes each
+        assert!(mixed.len() > 44 + 4300 * 4, "{}", mixed.len());
+        assert_eq!(mixed.len(), channel.len());
+    }
+
+    #[test]
+    fn queue_drops_what_does_not_fit()
+    {
+        let queue = SampleQueue::new(4);
+
+        queue.push(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+
+        assert_eq!(1.0, queue.fill_level());
+    }
+
+    #[test]
+    fn queue_holds_last_frame_when_empty()
+    {
+        let queue = SampleQueue::new(8);
+        queue.push(&[0.1, 0.2]);
+        let mut output = [0.0; 6];
+
+        queue.pop_frames(&mut output, 3);
+
+        assert_eq!([0.1, 0.2, 0.0, 0.1, 0.2, 0.0], output);
+        let mut output = [0.0; 2];
+        queue.pop_frames(&mut output, 2);
+        assert_eq!([0.1, 0.2], output);
+    }
+
+    #[test]
+    fn mono_devices_get_both_sides()
+    {
+        let queue = SampleQueue::new(8);
+        queue.push(&[0.2, 0.4, 0.0, -0.6]);
+        let mut output = [0.0; 2];
+
+        queue.pop_frames(&mut output, 1);
+
+        assert_eq!([0.3, -0.3], output);
+    }
+}
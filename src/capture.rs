@@ -0,0 +1,96 @@
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::RgbImage;
+
+use crate::hardware::gameboy::GameBoy;
+use crate::hardware::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/*
+
+Captures of the emulation, screenshots as PNG files and the file names of
+everything the frontend captures.
+
+Screenshots can be scaled up by a whole number, every pixel becomes a
+square of pixels so the picture stays sharp.
+
+*/
+
+pub const MAX_SCALE: u32 = 8;
+
+/// File name for captures, made from the ROM title and the current time.
+/// Gets a number appended if the file exists already.
+pub fn file_name(rom_name: &str, extension: &str) -> String
+{
+    let title: String = rom_name.trim_matches(char::from(0)).trim().chars()
+        .map(|character| if character.is_ascii_alphanumeric() { character } else { '_' })
+        .collect();
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    let mut name = format!("{}-{}.{}", title, timestamp, extension);
+    let mut number = 1;
+    while Path::new(&name).exists()
+    {
+        number += 1;
+        name = format!("{}-{}-{}.{}", title, timestamp, number, extension);
+    }
+    name
+}
+
+/// The RGB framebuffer as a picture, every pixel scaled to a square of scale pixels
+pub fn screen_image(framebuffer: &[u8], scale: u32) -> RgbImage
+{
+    let scale = scale.clamp(1, MAX_SCALE);
+    RgbImage::from_fn(SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale, |x, y| {
+        let index = ((y / scale) as usize * SCREEN_WIDTH + (x / scale) as usize) * 3;
+        image::Rgb([framebuffer[index], framebuffer[index + 1], framebuffer[index + 2]])
+    })
+}
+
+/// Saves the current picture of the screen as a PNG file
+pub fn save_screenshot(gameboy: &GameBoy, path: &Path, scale: u32) -> io::Result<()>
+{
+    screen_image(gameboy.framebuffer(), scale).save(path)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn names_use_the_title()
+    {
+        let name = file_name("TETRIS\0\0 DX", "png");
+
+        assert!(name.starts_with("TETRIS___DX-"), "{}", name);
+        assert!(name.ends_with(".png"), "{}", name);
+    }
+
+    #[test]
+    fn scaling_repeats_pixels()
+    {
+        let mut framebuffer = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3];
+        framebuffer[3..6].copy_from_slice(&[10, 20, 30]);
+
+        let picture = screen_image(&framebuffer, 2);
+
+        assert_eq!((320, 288), picture.dimensions());
+        assert_eq!(image::Rgb([0, 0, 0]), *picture.get_pixel(1, 1));
+        assert_eq!(image::Rgb([10, 20, 30]), *picture.get_pixel(2, 0));
+        assert_eq!(image::Rgb([10, 20, 30]), *picture.get_pixel(3, 1));
+        assert_eq!(image::Rgb([0, 0, 0]), *picture.get_pixel(4, 0));
+    }
+
+    #[test]
+    fn screenshot_is_a_png()
+    {
+        let path = std::env::temp_dir().join(format!("rboy-screenshot-{}.png", std::process::id()));
+
+        save_screenshot(&GameBoy::default(), &path, 1).unwrap();
+        let picture = image::open(&path).unwrap().to_rgb();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((160, 144), picture.dimensions());
+    }
+}
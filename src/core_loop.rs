@@ -1,20 +1,497 @@
 extern crate piston_window;
 
 use piston_window::*;
+use piston_window::texture::{CreateTexture, UpdateTexture, Format};
 
-pub fn draw_loop(window_title: &str, gameboy: &mut crate::hardware::gameboy::GameBoy)
+use rboy::{GameBoy, SCREEN_WIDTH, SCREEN_HEIGHT};
+use rboy::Button as JoypadButton;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::{info, warn, error};
+
+use rboy::audio::{AudioOutput, AudioRecorder};
+use rboy::capture;
+use rboy::movie::{MoviePlayer, MovieRecorder};
+use rboy::rewind::RewindBuffer;
+use rboy::video::{VideoFormat, VideoRecorder};
+use crate::display;
+use crate::frame_limiter::{FrameLimiter, SoundSkip, SpeedControl};
+use crate::gamepad::Gamepads;
+use crate::input::{KeyMapping, ControllerMapping, ControllerEvent, InputSource, InputState};
+use crate::input::{RECORD_AUDIO_KEY, RECORD_VIDEO_KEY, SCREENSHOT_KEY, FULLSCREEN_KEY, FAST_FORWARD_KEY, SLOW_MOTION_KEY, PAUSE_KEY, FRAME_ADVANCE_KEY};
+use crate::input::{SAVE_STATE_KEY, LOAD_STATE_KEY, PREVIOUS_SLOT_KEY, NEXT_SLOT_KEY, REWIND_KEY};
+use crate::save_slots::SaveSlots;
+
+fn framebuffer_to_rgba(framebuffer: &[u8], rgba: &mut Vec<u8>)
+{
+    rgba.clear();
+    for pixel in framebuffer.chunks(3)
+    {
+        rgba.extend_from_slice(pixel);
+        rgba.push(0xFF);
+    }
+}
+
+const UPDATES_PER_SECOND: u64 = 240;
+
+/// The state of the frontend features that sit around the emulation
+pub struct Frontend
+{
+    pub speed_control: SpeedControl,
+    pub save_slots: SaveSlots,
+    pub rewind: RewindBuffer,
+    pub movie_recorder: Option<MovieRecorder>,
+    pub movie_player: Option<MoviePlayer>,
+    pub screenshot_scale: u32,
+    /// Video to record from the start
+    pub record_video: Option<PathBuf>,
+    /// Format of the videos started with the hotkey
+    pub video_format: VideoFormat,
+    /// Size of the window as a multiple of the screen
+    pub window_scale: u32,
+    pub fullscreen: bool,
+    /// Controllers, none if they can not be read
+    pub gamepads: Option<Gamepads>,
+    /// The other side of a link cable runs in lockstep and waits for this one
+    pub link_cable: bool,
+}
+
+impl Frontend
+{
+    /// Rewinding and loading states would break the movie
+    fn movie_running(&self) -> bool
+    {
+        self.movie_recorder.is_some() || self.movie_player.is_some()
+    }
+
+    /// Why rewinding and loading states are off, they would break a movie or
+    /// the link cable. Pausing would stall the other side of the cable until
+    /// it gives up waiting, so it is off as well with a cable.
+    fn time_travel_blocked(&self) -> Option<&'static str>
+    {
+        if self.movie_running()
+        {
+            Some("a movie runs")
+        }
+        else if self.link_cable
+        {
+            Some("the link cable is connected")
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+/// Everything the emulated sound goes to
+pub struct Sound
+{
+    output: Option<AudioOutput>,
+    recorder: Option<AudioRecorder>,
+    record_channels: bool,
+    sound_skip: SoundSkip,
+}
+
+impl Sound
+{
+    pub fn new(output: Option<AudioOutput>, recorder: Option<AudioRecorder>, record_channels: bool) -> Sound
+    {
+        Sound { output, recorder, record_channels, sound_skip: SoundSkip::default() }
+    }
+
+    fn update_apu_output(&self, gameboy: &mut GameBoy)
+    {
+        gameboy.apu.output_enabled = self.output.is_some();
+        gameboy.apu.channel_output_enabled = false;
+        if let Some(recorder) = &self.recorder
+        {
+            recorder.enable_output(&mut gameboy.apu);
+        }
+    }
+
+    fn stop_recording(&mut self)
+    {
+        if let Some(recorder) = self.recorder.take()
+        {
+            let path = recorder.path().display().to_string();
+            match recorder.finish() {
+                Ok(()) => info!("Saved recording to {path}", path=path),
+                Err(message) => error!("Could not save recording to {path}: {message}", path=path, message=message)
+            }
+        }
+    }
+
+    fn recording(&self) -> bool
+    {
+        self.recorder.is_some()
+    }
+
+    fn start_recording(&mut self, path: &Path, gameboy: &mut GameBoy)
+    {
+        match AudioRecorder::create(path, self.record_channels) {
+            Ok(recorder) => {
+                info!("Recording sound to {path}", path=path.display());
+                self.recorder = Some(recorder);
+            },
+            Err(message) => error!("Could not record to {path}: {message}", path=path.display(), message=message)
+        }
+        self.update_apu_output(gameboy);
+    }
+
+    fn toggle_recording(&mut self, rom_name: &str, gameboy: &mut GameBoy)
+    {
+        if self.recording()
+        {
+            self.stop_recording();
+            self.update_apu_output(gameboy);
+        }
+        else
+        {
+            self.start_recording(Path::new(&capture::file_name(rom_name, "wav")), gameboy);
+        }
+    }
+
+    /// Hands the sound of the last frame to the output and the recorder. Fast
+    /// forward skips frames of sound to keep the pitch, at 2.5 times the speed
+    /// two out of five frames are played. Uncapped it is muted. Recordings
+    /// always get everything.
+    fn play(&mut self, gameboy: &mut GameBoy, speed: Option<f64>)
+    {
+        if !gameboy.apu.output_enabled
+        {
+            return;
+        }
+        let samples = gameboy.apu.take_samples();
+        let channel_samples = gameboy.apu.take_channel_samples();
+        if let (Some(output), Some(speed)) = (&mut self.output, speed)
+        {
+            if self.sound_skip.play(speed)
+            {
+                output.queue_samples(&samples);
+            }
+        }
+        if let Some(recorder) = &mut self.recorder
+        {
+            if let Err(message) = recorder.record(&samples, &channel_samples)
+            {
+                error!("Recording failed: {message}", message=message);
+                self.stop_recording();
+                self.update_apu_output(gameboy);
+            }
+        }
+    }
+}
+
+/// Returns true if the speed changed
+fn handle_speed_keys(event: &Event, speed_control: &mut SpeedControl, can_pause: bool) -> bool
+{
+    let speed = speed_control.speed();
+    let paused = speed_control.paused();
+    let pressed = |key| event.press_args() == Some(Button::Keyboard(key));
+    if pressed(FAST_FORWARD_KEY)
+    {
+        speed_control.set_fast_forward(true);
+    }
+    if event.release_args() == Some(Button::Keyboard(FAST_FORWARD_KEY))
+    {
+        speed_control.set_fast_forward(false);
+    }
+    if pressed(SLOW_MOTION_KEY)
+    {
+        speed_control.cycle_slow_motion();
+    }
+    if pressed(PAUSE_KEY) && !can_pause
+    {
+        warn!("Pausing is off while the link cable is connected");
+    }
+    else if pressed(PAUSE_KEY)
+    {
+        speed_control.toggle_pause();
+    }
+    if pressed(FRAME_ADVANCE_KEY)
+    {
+        speed_control.advance_frame();
+    }
+    if speed_control.paused() != paused
+    {
+        info!("{state}", state=if speed_control.paused() { "Paused" } else { "Resumed" });
+    }
+    if speed_control.speed() != speed
+    {
+        match speed_control.speed() {
+            Some(speed) => info!("Speed {speed}x", speed=speed),
+            None => info!("Speed uncapped")
+        }
+    }
+    speed_control.speed() != speed || speed_control.paused() != paused
+}
+
+fn handle_save_state_keys(event: &Event, save_slots: &mut SaveSlots, gameboy: &mut GameBoy, load_blocked: Option<&str>)
+{
+    let pressed = |key| event.press_args() == Some(Button::Keyboard(key));
+    if pressed(PREVIOUS_SLOT_KEY) || pressed(NEXT_SLOT_KEY)
+    {
+        if pressed(NEXT_SLOT_KEY) { save_slots.select_next() } else { save_slots.select_previous() }
+        info!("Save state slot {slot}", slot=save_slots.selected());
+    }
+    let path = save_slots.path().display().to_string();
+    if pressed(SAVE_STATE_KEY)
+    {
+        match save_slots.save(gameboy) {
+            Ok(()) => info!("Saved state to {path}", path=path),
+            Err(message) => error!("Could not save state to {path}: {message}", path=path, message=message)
+        }
+    }
+    if pressed(LOAD_STATE_KEY)
+    {
+        if let Some(reason) = load_blocked
+        {
+            warn!("States can not be loaded while {reason}", reason=reason);
+            return;
+        }
+        match save_slots.load(gameboy) {
+            Ok(()) => info!("Loaded state from {path}", path=path),
+            Err(message) => error!("Could not load state from {path}: {message}", path=path, message=message)
+        }
+    }
+}
+
+/// A video being recorded, with whether the sound recording was started for it
+struct Video
+{
+    recorder: VideoRecorder,
+    with_sound: bool,
+}
+
+fn start_video(path: &Path, sound: &mut Sound, gameboy: &mut GameBoy) -> Option<Video>
+{
+    // A sound recording that already runs keeps going on its own
+    let sound_path = path.with_extension("wav");
+    let with_sound = !sound.recording();
+    match VideoRecorder::create(path, Some(sound_path.as_path()).filter(|_| with_sound)) {
+        Ok(recorder) => {
+            info!("Recording video to {path}", path=path.display());
+            if with_sound
+            {
+                sound.start_recording(&sound_path, gameboy);
+            }
+            Some(Video { recorder, with_sound })
+        },
+        Err(message) => {
+            error!("Could not record video to {path}: {message}", path=path.display(), message=message);
+            None
+        }
+    }
+}
+
+fn stop_video(video: Video, sound: &mut Sound, gameboy: &mut GameBoy)
+{
+    let path = video.recorder.path().display().to_string();
+    let frames = video.recorder.frames();
+    match video.recorder.finish() {
+        Ok(()) => info!("Saved {frames} frames of video to {path}", frames=frames, path=path),
+        Err(message) => error!("Could not save video to {path}: {message}", path=path, message=message)
+    }
+    if video.with_sound
+    {
+        sound.stop_recording();
+        sound.update_apu_output(gameboy);
+    }
+}
+
+fn take_screenshot(rom_name: &str, gameboy: &GameBoy, scale: u32)
+{
+    let path = capture::file_name(rom_name, "png");
+    match capture::save_screenshot(gameboy, Path::new(&path), scale) {
+        Ok(()) => info!("Saved screenshot to {path}", path=path),
+        Err(message) => error!("Could not save screenshot to {path}: {message}", path=path, message=message)
+    }
+}
+
+fn handle_input(event: &Event, input_state: &mut InputState, key_mapping: &KeyMapping)
+{
+    if let Some(false) = event.focus_args()
+    {
+        input_state.release_all();
+    }
+    if let Some(ButtonArgs { button: Button::Keyboard(key), state, .. }) = event.button_args()
+    {
+        if let Some(button) = key_mapping.button_for(key)
+        {
+            input_state.set((InputSource::Keyboard, button, state == ButtonState::Press));
+        }
+    }
+}
+
+fn handle_controller_event(controller_event: ControllerEvent, input_state: &mut InputState, controller_mapping: &ControllerMapping)
+{
+    let changes = match controller_event {
+        ControllerEvent::Connected(id, name) => {
+            if input_state.connect(id)
+            {
+                info!("Controller {id} connected: {name}", id=id, name=name);
+            }
+            vec![]
+        },
+        ControllerEvent::Disconnected(id) => {
+            if input_state.disconnect(id)
+            {
+                info!("Controller {id} disconnected", id=id);
+            }
+            vec![]
+        },
+        ControllerEvent::Button(id, number, pressed) => controller_mapping.button_changes(id, number, pressed),
+        ControllerEvent::Axis(id, axis, position) => controller_mapping.axis_changes(id, axis, position)
+    };
+    for change in changes
+    {
+        input_state.set(change);
+    }
+}
+
+/// Presses the buttons for the next frame. The input only changes between
+/// frames, so that a movie of the input per frame replays exactly.
+fn press_buttons(gameboy: &mut GameBoy, input_state: &InputState, movie_player: &mut Option<MoviePlayer>)
+{
+    if let Some(player) = movie_player
+    {
+        if player.play_frame(gameboy)
+        {
+            return;
+        }
+        if player.in_sync(gameboy)
+        {
+            info!("Movie ended in sync after {frames} frames", frames=player.frames());
+        }
+        else
+        {
+            warn!("Movie ended out of sync after {frames} frames", frames=player.frames());
+        }
+        *movie_player = None;
+    }
+    for &button in JoypadButton::ALL.iter()
+    {
+        gameboy.set_button(button, input_state.is_held(button));
+    }
+}
+
+pub fn draw_loop(window_title: &str, gameboy: &mut GameBoy, key_mapping: &KeyMapping,
+                 controller_mapping: &ControllerMapping, sound: &mut Sound, frontend: &mut Frontend)
 {
     let mut window: PistonWindow =
-        WindowSettings::new(window_title, [160, 144])
-        .exit_on_esc(true).build().unwrap();
+        WindowSettings::new(window_title, [SCREEN_WIDTH as u32 * frontend.window_scale, SCREEN_HEIGHT as u32 * frontend.window_scale])
+        .fullscreen(frontend.fullscreen).exit_on_esc(true).build().unwrap();
+
+    let mut texture_context = window.create_texture_context();
+    let texture_settings = TextureSettings::new().filter(Filter::Nearest);
+    let mut rgba: Vec<u8> = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+    framebuffer_to_rgba(gameboy.framebuffer(), &mut rgba);
+    let mut texture = G2dTexture::create(&mut texture_context, Format::Rgba8, &rgba,
+                                         [SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32],
+                                         &texture_settings).unwrap();
+    let mut input_state = InputState::default();
+    sound.update_apu_output(gameboy);
+    // Updates only poll the frame limiter, they have to come often enough to hit every frame
+    window.set_ups(UPDATES_PER_SECOND);
+    let mut limiter = FrameLimiter::new(Instant::now());
+    let mut rewinding = false;
+    let mut video = None;
+    if let Some(path) = &frontend.record_video
+    {
+        video = start_video(path, sound, gameboy);
+    }
+
     while let Some(event) = window.next() {
-        crate::hardware::cpu::step(gameboy);
-        window.draw_2d(&event, |context, graphics, _device| {
-            clear([1.0; 4], graphics);
-            rectangle([1.0, 0.0, 0.0, 1.0], // red
-                      [0.0, 0.0, 100.0, 100.0],
-                      context.transform,
-                      graphics);
+        if event.update_args().is_some()
+        {
+            let speed_control = &mut frontend.speed_control;
+            let frames = if speed_control.paused() { speed_control.take_frame_advance() as u32 } else { limiter.frames_due(Instant::now()) };
+            for _ in 0..frames
+            {
+                if rewinding
+                {
+                    frontend.rewind.rewind_frame(gameboy);
+                    continue;
+                }
+                press_buttons(gameboy, &input_state, &mut frontend.movie_player);
+                if let Some(recorder) = &mut frontend.movie_recorder
+                {
+                    recorder.record_frame(gameboy);
+                }
+                gameboy.run_frame();
+                frontend.rewind.record_frame(gameboy);
+                sound.play(gameboy, frontend.speed_control.speed());
+                if let Some(Err(message)) = video.as_mut().map(|video: &mut Video| video.recorder.record_frame(gameboy.framebuffer()))
+                {
+                    error!("Video recording failed: {message}", message=message);
+                    stop_video(video.take().unwrap(), sound, gameboy);
+                }
+            }
+        }
+        if handle_speed_keys(&event, &mut frontend.speed_control, !frontend.link_cable)
+        {
+            limiter.set_speed(frontend.speed_control.speed(), Instant::now());
+        }
+        if event.press_args() == Some(Button::Keyboard(REWIND_KEY))
+        {
+            match frontend.time_travel_blocked() {
+                Some(reason) => warn!("Rewinding is off while {reason}", reason=reason),
+                None => rewinding = true
+            }
+        }
+        if event.release_args() == Some(Button::Keyboard(REWIND_KEY))
+        {
+            rewinding = false;
+        }
+        if event.press_args() == Some(Button::Keyboard(RECORD_AUDIO_KEY))
+        {
+            sound.toggle_recording(window_title, gameboy);
+        }
+        if event.press_args() == Some(Button::Keyboard(RECORD_VIDEO_KEY))
+        {
+            match video.take() {
+                Some(video) => stop_video(video, sound, gameboy),
+                None => {
+                    let path = capture::file_name(window_title, frontend.video_format.extension());
+                    video = start_video(Path::new(&path), sound, gameboy);
+                }
+            }
+        }
+        if event.press_args() == Some(Button::Keyboard(SCREENSHOT_KEY))
+        {
+            take_screenshot(window_title, gameboy, frontend.screenshot_scale);
+        }
+        let load_blocked = frontend.time_travel_blocked();
+        handle_save_state_keys(&event, &mut frontend.save_slots, gameboy, load_blocked);
+        handle_input(&event, &mut input_state, key_mapping);
+        for controller_event in frontend.gamepads.as_mut().map(Gamepads::poll).unwrap_or_default()
+        {
+            handle_controller_event(controller_event, &mut input_state, controller_mapping);
+        }
+        if event.render_args().is_some()
+        {
+            framebuffer_to_rgba(gameboy.framebuffer(), &mut rgba);
+            UpdateTexture::update(&mut texture, &mut texture_context, Format::Rgba8, &rgba,
+                                  [0, 0], [SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32]).unwrap();
+        }
+        if event.press_args() == Some(Button::Keyboard(FULLSCREEN_KEY))
+        {
+            frontend.fullscreen = !frontend.fullscreen;
+            let gl_window = &window.window.window;
+            gl_window.set_fullscreen(if frontend.fullscreen { Some(gl_window.get_current_monitor()) } else { None });
+        }
+        window.draw_2d(&event, |context, graphics, device| {
+            texture_context.encoder.flush(device);
+            clear([0.0, 0.0, 0.0, 1.0], graphics);
+            let placement = display::place_screen(context.get_view_size());
+            image(&texture, context.transform.trans(placement.x, placement.y).scale(placement.scale, placement.scale), graphics);
         });
     }
-}
\ No newline at end of file
+    if let Some(video) = video
+    {
+        stop_video(video, sound, gameboy);
+    }
+    sound.stop_recording();
+}
@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::hardware::serial::SerialDevice;
+
+/*
+
+Serial console for test ROMs like Blargg's cpu_instrs, which send their
+results over the link port. Every byte the Game Boy sends is collected as
+text, echoed to stdout and optionally written to a log file. Bytes that are
+not printable ASCII show up as '?' on stdout, the log gets them unchanged.
+
+*/
+
+pub struct SerialConsole
+{
+    text: Rc<RefCell<String>>,
+    echo: bool,
+    log: Option<Box<dyn Write>>,
+}
+
+impl SerialConsole
+{
+    pub fn new(echo: bool, log: Option<Box<dyn Write>>) -> SerialConsole
+    {
+        SerialConsole { text: Rc::new(RefCell::new(String::new())), echo, log }
+    }
+
+    /// Everything received so far, stays readable after the console got plugged in
+    pub fn text(&self) -> Rc<RefCell<String>>
+    {
+        self.text.clone()
+    }
+}
+
+fn printable(byte: u8) -> char
+{
+    match byte {
+        b'\n' | b'\t' | 0x20..=0x7E => byte as char,
+        _ => '?'
+    }
+}
+
+impl SerialDevice for SerialConsole
+{
+    fn exchange(&mut self, byte: u8) -> u8
+    {
+        let character = printable(byte);
+        self.text.borrow_mut().push(character);
+        if self.echo
+        {
+            print!("{}", character);
+            let _ = std::io::stdout().flush();
+        }
+        if let Some(log) = &mut self.log
+        {
+            if let Err(message) = log.write_all(&[byte]).and_then(|_| log.flush())
+            {
+                log::error!("Could not write serial log: {message}", message=message);
+                self.log = None;
+            }
+        }
+        // Nobody answers
+        0xFF
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog
+    {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize>
+        {
+            self.0.borrow_mut().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn collects_text()
+    {
+        let mut console = SerialConsole::new(false, None);
+        let text = console.text();
+
+        for byte in b"Passed\n"
+        {
+            assert_eq!(0xFF, console.exchange(*byte));
+        }
+
+        assert_eq!("Passed\n", *text.borrow());
+    }
+
+    #[test]
+    fn unprintable_bytes_are_replaced_in_text_but_not_in_log()
+    {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut console = SerialConsole::new(false, Some(Box::new(SharedLog(log.clone()))));
+
+        console.exchange(b'A');
+        console.exchange(0x00);
+
+        assert_eq!("A?", *console.text().borrow());
+        assert_eq!(vec![b'A', 0x00], *log.borrow());
+    }
+
+    #[test]
+    fn console_receives_bytes_from_gameboy()
+    {
+        let mut gameboy = crate::hardware::gameboy::GameBoy::default();
+        let console = SerialConsole::new(false, None);
+        let text = console.text();
+        gameboy.connect_serial(Box::new(console));
+
+        gameboy.write_byte(0xFF01, b'o');
+        gameboy.write_byte(0xFF02, 0x81);
+        gameboy.write_byte(0xFF01, b'k');
+        gameboy.write_byte(0xFF02, 0x81);
+
+        assert_eq!("ok", *text.borrow());
+    }
+}
@@ -0,0 +1,493 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::hardware::serial::SerialDevice;
+
+/*
+
+Link cable between two emulator instances over TCP on localhost. One side
+hosts and waits for the other one to join before the emulation starts.
+
+Both sides count the CPU cycles they ran since then and run in lockstep, so
+every transfer happens at the same emulated time no matter how the two
+processes get scheduled:
+  * A transfer started by the side with the internal clock at time T reaches
+    the other side at exactly T + LATENCY, the time a byte takes with the
+    normal clock. The starting side waits until the other side got there and
+    answers with its SB if it waits for an external clock, or 0xFF if it
+    does not take part in the transfer
+  * Every time is a promise that the side will not start a transfer before
+    it. Each side only runs up to LATENCY cycles past the last time it heard
+    from the other one, so it always knows every transfer that reaches it
+    before its own time
+  * Transfers of both sides that start less than LATENCY apart collide, both
+    sides drive the clock and receive 0xFF
+
+Messages are a type byte followed by little endian values:
+  * 0 Sync:     u64 time
+  * 1 Transfer: u64 time, u8 data
+  * 2 Reply:    u8 data
+
+Waiting for the other side gives up after a timeout, so a closed or hanging
+emulator on the other end can not freeze this one. Once the connection
+breaks or times out the cable behaves like it got unplugged. The frontend
+keeps pausing, rewinding and loading states off while a cable is connected,
+they would stop the time of one side or move it backwards.
+
+*/
+
+const SYNC: u8 = 0;
+const TRANSFER: u8 = 1;
+const REPLY: u8 = 2;
+
+// CPU cycles from the start of a transfer until it reaches the other side
+const LATENCY: u64 = 4096;
+const SYNC_INTERVAL: u64 = LATENCY / 4;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq)]
+enum Message
+{
+    Sync(u64),
+    Transfer(u64, u8),
+    Reply(u8),
+}
+
+impl Message
+{
+    fn encode(&self) -> Vec<u8>
+    {
+        let mut bytes = vec![];
+        match self {
+            Message::Sync(time) => {
+                bytes.push(SYNC);
+                bytes.extend_from_slice(&time.to_le_bytes());
+            },
+            Message::Transfer(time, data) => {
+                bytes.push(TRANSFER);
+                bytes.extend_from_slice(&time.to_le_bytes());
+                bytes.push(*data);
+            },
+            Message::Reply(data) => {
+                bytes.push(REPLY);
+                bytes.push(*data);
+            }
+        }
+        bytes
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Message>
+    {
+        let mut kind = [0; 1];
+        reader.read_exact(&mut kind)?;
+        let mut time = [0; 8];
+        let mut data = [0; 1];
+        match kind[0] {
+            SYNC => {
+                reader.read_exact(&mut time)?;
+                Ok(Message::Sync(u64::from_le_bytes(time)))
+            },
+            TRANSFER => {
+                reader.read_exact(&mut time)?;
+                reader.read_exact(&mut data)?;
+                Ok(Message::Transfer(u64::from_le_bytes(time), data[0]))
+            },
+            REPLY => {
+                reader.read_exact(&mut data)?;
+                Ok(Message::Reply(data[0]))
+            },
+            kind => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown link cable message {}", kind)))
+        }
+    }
+}
+
+pub struct LinkCable
+{
+    // None once the connection broke
+    stream: Option<TcpStream>,
+    messages: Receiver<Message>,
+    timeout: Duration,
+    time: u64,
+    last_sync: u64,
+    // The other side will not start a transfer before this time
+    peer_time: u64,
+    // Transfer of the other side that has not reached this side yet
+    pending_transfer: Option<(u64, u8)>,
+    // Start of the last transfer of this side
+    last_transfer: Option<u64>,
+}
+
+impl LinkCable
+{
+    /// Waits for the other instance to join on the given port
+    pub fn host(port: u16) -> io::Result<LinkCable>
+    {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for the other Game Boy on port {port}", port=port);
+        let (stream, _) = listener.accept()?;
+        LinkCable::connect(stream)
+    }
+
+    pub fn join(port: u16) -> io::Result<LinkCable>
+    {
+        LinkCable::connect(TcpStream::connect(("127.0.0.1", port))?)
+    }
+
+    fn connect(stream: TcpStream) -> io::Result<LinkCable>
+    {
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (sender, messages) = mpsc::channel();
+        // Reading happens on its own thread, so the emulation can poll without blocking
+        thread::spawn(move || {
+            while let Ok(message) = Message::read(&mut reader)
+            {
+                if sender.send(message).is_err()
+                {
+                    break;
+                }
+            }
+        });
+        info!("Link cable connected");
+        Ok(LinkCable {
+            stream: Some(stream),
+            messages,
+            timeout: DEFAULT_TIMEOUT,
+            time: 0,
+            last_sync: 0,
+            peer_time: 0,
+            pending_transfer: None,
+            last_transfer: None,
+        })
+    }
+
+    fn connected(&self) -> bool
+    {
+        self.stream.is_some()
+    }
+
+    fn disconnect(&mut self, reason: &str)
+    {
+        if self.stream.take().is_some()
+        {
+            warn!("Link cable disconnected: {reason}", reason=reason);
+        }
+    }
+
+    fn send(&mut self, message: Message)
+    {
+        if let Some(stream) = &mut self.stream
+        {
+            if let Err(error) = stream.write_all(&message.encode())
+            {
+                self.disconnect(&error.to_string());
+            }
+        }
+    }
+
+    fn sync(&mut self)
+    {
+        self.last_sync = self.time;
+        self.send(Message::Sync(self.time));
+    }
+
+    /// The next message, waiting for at most the timeout if blocking
+    fn receive(&mut self, blocking: bool) -> Option<Message>
+    {
+        if !self.connected()
+        {
+            return None;
+        }
+        let result = if blocking
+        {
+            self.messages.recv_timeout(self.timeout).map_err(|error| match error {
+                RecvTimeoutError::Timeout => "the other side stopped responding",
+                RecvTimeoutError::Disconnected => "the other side closed the connection"
+            })
+        }
+        else
+        {
+            match self.messages.try_recv() {
+                Ok(message) => Ok(message),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => Err("the other side closed the connection")
+            }
+        };
+        match result {
+            Ok(message) => Some(message),
+            Err(reason) => {
+                self.disconnect(reason);
+                None
+            }
+        }
+    }
+
+    fn handle(&mut self, message: Message)
+    {
+        match message {
+            Message::Sync(time) => self.peer_time = self.peer_time.max(time),
+            Message::Transfer(time, data) => {
+                self.peer_time = self.peer_time.max(time);
+                if self.last_transfer.is_some_and(|own| time < own + LATENCY)
+                {
+                    self.send(Message::Reply(0xFF));
+                }
+                else
+                {
+                    self.pending_transfer = Some((time, data));
+                }
+            },
+            // Answers only arrive during our own transfers
+            Message::Reply(_) => ()
+        }
+    }
+
+    /// Whether every transfer that reaches this side up to its current time is known
+    fn caught_up(&self) -> bool
+    {
+        self.peer_time + LATENCY > self.time
+    }
+}
+
+impl Drop for LinkCable
+{
+    fn drop(&mut self)
+    {
+        // The reader thread holds a clone of the stream, which would keep the connection open
+        if let Some(stream) = &self.stream
+        {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl SerialDevice for LinkCable
+{
+    fn exchange(&mut self, byte: u8) -> u8
+    {
+        if !self.connected()
+        {
+            return 0xFF;
+        }
+        // A transfer of the other side that is still on its way collides with this one,
+        // just like one that comes in less than LATENCY after it
+        if self.pending_transfer.take().is_some()
+        {
+            self.send(Message::Reply(0xFF));
+        }
+        self.last_transfer = Some(self.time);
+        self.send(Message::Transfer(self.time, byte));
+        self.last_sync = self.time;
+        while let Some(message) = self.receive(true)
+        {
+            match message {
+                Message::Reply(data) => return data,
+                message => self.handle(message)
+            }
+        }
+        0xFF
+    }
+
+    fn tick(&mut self, cycles: u32, waiting: Option<u8>) -> Option<u8>
+    {
+        self.time += cycles as u64;
+        if !self.connected()
+        {
+            return None;
+        }
+        if self.time - self.last_sync >= SYNC_INTERVAL
+        {
+            self.sync();
+        }
+        let mut waiting = waiting;
+        let mut received = None;
+        loop
+        {
+            while let Some(message) = self.receive(false)
+            {
+                self.handle(message);
+            }
+            if let Some((time, data)) = self.pending_transfer
+            {
+                if time + LATENCY <= self.time
+                {
+                    self.pending_transfer = None;
+                    self.send(Message::Reply(waiting.unwrap_or(0xFF)));
+                    if waiting.take().is_some()
+                    {
+                        received = Some(data);
+                    }
+                    continue;
+                }
+            }
+            if self.caught_up() || !self.connected()
+            {
+                return received;
+            }
+            if self.last_sync != self.time
+            {
+                self.sync();
+            }
+            match self.receive(true) {
+                Some(message) => self.handle(message),
+                None => return received
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn connected_pair() -> (LinkCable, LinkCable)
+    {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let joining = thread::spawn(move || LinkCable::join(port).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        (LinkCable::connect(stream).unwrap(), joining.join().unwrap())
+    }
+
+    #[test]
+    fn messages_survive_encoding()
+    {
+        for message in [Message::Sync(0x1234_5678_9ABC), Message::Transfer(42, 0x7F), Message::Reply(0xAB)]
+        {
+            let bytes = message.encode();
+
+            assert_eq!(message, Message::read(&mut &bytes[..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn unknown_message_is_an_error()
+    {
+        assert!(Message::read(&mut &[7u8][..]).is_err());
+    }
+
+    /// Ticks until the given side is done, so it never waits for this one
+    fn tick_until_finished<T>(cable: &mut LinkCable, other_side: &thread::JoinHandle<T>)
+    {
+        while !other_side.is_finished()
+        {
+            cable.tick(4, None);
+        }
+    }
+
+    #[test]
+    fn transfer_to_waiting_side()
+    {
+        let (mut host, mut guest) = connected_pair();
+        let guest_side = thread::spawn(move || {
+            loop
+            {
+                if let Some(data) = guest.tick(4, Some(0x55))
+                {
+                    return data;
+                }
+            }
+        });
+
+        assert_eq!(0x55, host.exchange(0x12));
+        tick_until_finished(&mut host, &guest_side);
+        assert_eq!(0x12, guest_side.join().unwrap());
+    }
+
+    #[test]
+    fn transfer_arrives_after_the_latency()
+    {
+        let (mut host, mut guest) = connected_pair();
+        let guest_side = thread::spawn(move || {
+            loop
+            {
+                if guest.tick(100, Some(0x55)).is_some()
+                {
+                    return guest.time;
+                }
+            }
+        });
+
+        host.tick(1000, None);
+        // Give the guest the chance to run ahead as far as it may
+        thread::sleep(Duration::from_millis(20));
+        host.exchange(0x12);
+        tick_until_finished(&mut host, &guest_side);
+
+        // The first tick at or after 1000 + LATENCY
+        assert_eq!(5100, guest_side.join().unwrap());
+    }
+
+    #[test]
+    fn side_that_is_not_waiting_answers_ff()
+    {
+        let (mut host, mut guest) = connected_pair();
+        let host_side = thread::spawn(move || host.exchange(0x12));
+
+        while !host_side.is_finished()
+        {
+            assert_eq!(None, guest.tick(4, None));
+        }
+
+        assert_eq!(0xFF, host_side.join().unwrap());
+    }
+
+    #[test]
+    fn transfers_of_both_sides_collide()
+    {
+        let (mut host, mut guest) = connected_pair();
+        let guest_side = thread::spawn(move || {
+            guest.tick(100, None);
+            guest.exchange(0x34)
+        });
+
+        let host_data = host.exchange(0x12);
+        tick_until_finished(&mut host, &guest_side);
+
+        assert_eq!(0xFF, host_data);
+        assert_eq!(0xFF, guest_side.join().unwrap());
+    }
+
+    #[test]
+    fn side_that_runs_ahead_waits()
+    {
+        let (mut host, mut guest) = connected_pair();
+        let guest_side = thread::spawn(move || {
+            guest.tick(2 * LATENCY as u32, None);
+            guest.peer_time
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!guest_side.is_finished());
+        host.tick(LATENCY as u32 + 4, None);
+
+        assert_eq!(LATENCY + 4, guest_side.join().unwrap());
+    }
+
+    #[test]
+    fn silent_other_side_times_out()
+    {
+        let (mut host, _guest) = connected_pair();
+        host.timeout = Duration::from_millis(10);
+
+        assert_eq!(None, host.tick(2 * LATENCY as u32, Some(0x00)));
+        assert!(!host.connected());
+        assert_eq!(0xFF, host.exchange(0x12));
+    }
+
+    #[test]
+    fn broken_connection_acts_like_no_cable()
+    {
+        let (mut host, guest) = connected_pair();
+        drop(guest);
+
+        assert_eq!(0xFF, host.exchange(0x12));
+        assert_eq!(None, host.tick(LATENCY as u32 * 4, Some(0x00)));
+    }
+}
@@ -0,0 +1,9 @@
+/*
+
+Devices that can be plugged into the link port of the emulated Game Boy.
+
+*/
+
+pub mod console;
+pub mod link_cable;
+pub mod printer;
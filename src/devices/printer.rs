@@ -0,0 +1,472 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::hardware::serial::SerialDevice;
+
+/*
+
+Game Boy Printer. The Game Boy sends it packets over the link port:
+  * Magic bytes 0x88 0x33
+  * Command, compression flag and the data length (little endian)
+  * Data, run length encoded if the compression flag is set
+  * Checksum (little endian), the sum of all bytes from the command on
+  * Two bytes for the printer to answer, it sends 0x81 and then its status
+
+Commands:
+  * INIT (0x01):   Clears the buffer
+  * PRINT (0x02):  Prints the buffer, the data holds the number of sheets,
+                   the margins before and after in the upper and lower nibble,
+                   the palette like BGP and the exposure (ignored)
+  * DATA (0x04):   Appends tile data to the buffer, two rows of 20 tiles per
+                   packet at most. Games end the image with an empty packet.
+  * STATUS (0x0F): Only asks for the status
+
+Compressed data consists of runs: a control byte with bit 7 set repeats the
+following byte (control & 0x7F) + 2 times, otherwise the next control + 1
+bytes are copied as they are.
+
+Everything printed ends up on the same strip of paper until a margin feeds
+it out, then the strip is saved as a grayscale PNG. Games like Pokémon print
+one picture in several parts without margins in between.
+
+*/
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0x01;
+const PRINTING: u8 = 0x02;
+const IMAGE_DATA_FULL: u8 = 0x04;
+const UNPROCESSED_DATA: u8 = 0x08;
+const PACKET_ERROR: u8 = 0x10;
+
+// The printer has 8 KiB of RAM for tile data
+const BUFFER_SIZE: usize = 0x2000;
+const TILES_PER_ROW: usize = 20;
+const TILE_BYTES: usize = 16;
+const PAPER_WIDTH: usize = TILES_PER_ROW * 8;
+
+// How long the printer reports to be busy after printing, in CPU cycles
+const PRINT_CYCLES: u32 = 4194304 / 2;
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State
+{
+    Magic,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct GameBoyPrinter
+{
+    directory: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    buffer: Vec<u8>,
+    // Grayscale pixels of the strip that was printed so far
+    paper: Vec<u8>,
+    status: u8,
+    busy_cycles: u32,
+    printed_sheets: u32,
+}
+
+impl GameBoyPrinter
+{
+    /// Saves the printed pictures into the given directory
+    pub fn new(directory: PathBuf) -> GameBoyPrinter
+    {
+        GameBoyPrinter {
+            directory,
+            state: State::Magic,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            received_checksum: 0,
+            buffer: vec![],
+            paper: vec![],
+            status: 0,
+            busy_cycles: 0,
+            printed_sheets: 0,
+        }
+    }
+
+    fn status(&self) -> u8
+    {
+        if self.busy_cycles > 0 { self.status | PRINTING } else { self.status }
+    }
+
+    fn run_command(&mut self)
+    {
+        self.status &= !PACKET_ERROR;
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            },
+            DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(space)]);
+                if !self.buffer.is_empty()
+                {
+                    self.status |= UNPROCESSED_DATA;
+                }
+                if self.buffer.len() == BUFFER_SIZE
+                {
+                    self.status |= IMAGE_DATA_FULL;
+                }
+            },
+            PRINT if self.data.len() == 4 => self.print(self.data[1], self.data[2]),
+            STATUS => (),
+            _ => self.status |= PACKET_ERROR
+        }
+    }
+
+    fn print(&mut self, margins: u8, palette: u8)
+    {
+        if margins >> 4 > 0
+        {
+            self.feed_paper();
+        }
+        let row_bytes = TILES_PER_ROW * TILE_BYTES;
+        let partial = self.buffer.len() % row_bytes;
+        if partial > 0
+        {
+            warn!("Printing an incomplete row of {tiles} tiles, the rest of the row gets color 0", tiles=partial / TILE_BYTES);
+            self.buffer.resize(self.buffer.len() + row_bytes - partial, 0);
+        }
+        self.paper.extend(render(&self.buffer, palette));
+        if margins & 0x0F > 0
+        {
+            self.feed_paper();
+        }
+        self.buffer.clear();
+        self.status &= !(UNPROCESSED_DATA | IMAGE_DATA_FULL);
+        self.busy_cycles = PRINT_CYCLES;
+    }
+
+    /// Saves what was printed so far as a picture
+    fn feed_paper(&mut self)
+    {
+        if self.paper.is_empty()
+        {
+            return;
+        }
+        let paper = std::mem::take(&mut self.paper);
+        self.printed_sheets += 1;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        let path = self.directory.join(format!("print-{}-{}.png", timestamp, self.printed_sheets));
+        let height = (paper.len() / PAPER_WIDTH) as u32;
+        let result = image::GrayImage::from_raw(PAPER_WIDTH as u32, height, paper)
+            .map(|picture| picture.save(&path));
+        match result {
+            Some(Ok(())) => info!("Printed {path}", path=path.display()),
+            Some(Err(message)) => warn!("Could not save {path}: {message}", path=path.display(), message=message),
+            None => warn!("Printed picture has an invalid size")
+        }
+    }
+}
+
+impl Drop for GameBoyPrinter
+{
+    fn drop(&mut self)
+    {
+        // Whatever is still in the printer when the emulator exits
+        self.feed_paper();
+    }
+}
+
+fn decompress(data: &[u8]) -> Vec<u8>
+{
+    let mut output = vec![];
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next()
+    {
+        if control & 0x80 > 0
+        {
+            if let Some(&byte) = bytes.next()
+            {
+                output.extend(std::iter::repeat(byte).take((control & 0x7F) as usize + 2));
+            }
+        }
+        else
+        {
+            output.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    output
+}
+
+/// Turns rows of 20 tiles into grayscale pixels, 160 per line
+fn render(tiles: &[u8], palette: u8) -> Vec<u8>
+{
+    // Some games leave the palette at 0, which the printer treats like the usual one
+    let palette = if palette == 0 { 0xE4 } else { palette };
+    let tile_rows = tiles.len() / (TILES_PER_ROW * TILE_BYTES);
+    let mut pixels = Vec::with_capacity(tile_rows * 8 * PAPER_WIDTH);
+    for y in 0..tile_rows * 8
+    {
+        for x in 0..PAPER_WIDTH
+        {
+            let offset = ((y / 8) * TILES_PER_ROW + x / 8) * TILE_BYTES + (y % 8) * 2;
+            let bit = 7 - x % 8;
+            let color = (((tiles[offset + 1] >> bit) & 1) << 1) | ((tiles[offset] >> bit) & 1);
+            pixels.push(SHADES[((palette >> (color * 2)) & 0x03) as usize]);
+        }
+    }
+    pixels
+}
+
+impl SerialDevice for GameBoyPrinter
+{
+    fn exchange(&mut self, byte: u8) -> u8
+    {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic if byte == MAGIC[0] => State::Magic2,
+            State::Magic => State::Magic,
+            State::Magic2 if byte == MAGIC[1] => State::Command,
+            State::Magic2 if byte == MAGIC[0] => State::Magic2,
+            State::Magic2 => State::Magic,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            },
+            State::Compression => {
+                self.compressed = byte & 0x01 > 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            },
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            },
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length > 0 { State::Data } else { State::ChecksumLow }
+            },
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize { State::ChecksumLow } else { State::Data }
+            },
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            },
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                if self.received_checksum == self.checksum
+                {
+                    self.status &= !CHECKSUM_ERROR;
+                    self.run_command();
+                }
+                else
+                {
+                    self.status |= CHECKSUM_ERROR;
+                }
+                State::Alive
+            },
+            State::Alive => {
+                reply = ALIVE;
+                State::Status
+            },
+            State::Status => {
+                reply = self.status();
+                State::Magic
+            }
+        };
+        reply
+    }
+
+    fn tick(&mut self, cycles: u32, _waiting: Option<u8>) -> Option<u8>
+    {
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8>
+    {
+        let mut bytes = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        let mut packet = MAGIC.to_vec();
+        packet.extend(bytes);
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet
+    }
+
+    /// Returns the two bytes the printer answers with
+    fn send(printer: &mut GameBoyPrinter, packet: &[u8]) -> (u8, u8)
+    {
+        let replies: Vec<u8> = packet.iter().map(|&byte| printer.exchange(byte)).collect();
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    fn temporary_directory(name: &str) -> PathBuf
+    {
+        let directory = std::env::temp_dir().join(format!("rboy-printer-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn answers_status_packet()
+    {
+        let mut printer = GameBoyPrinter::new(PathBuf::new());
+
+        assert_eq!((ALIVE, 0x00), send(&mut printer, &packet(STATUS, false, &[])));
+    }
+
+    #[test]
+    fn ignores_bytes_before_magic()
+    {
+        let mut printer = GameBoyPrinter::new(PathBuf::new());
+        printer.exchange(0x00);
+        printer.exchange(0x88);
+
+        assert_eq!((ALIVE, 0x00), send(&mut printer, &packet(INIT, false, &[])));
+    }
+
+    #[test]
+    fn reports_checksum_errors()
+    {
+        let mut printer = GameBoyPrinter::new(PathBuf::new());
+        let mut broken = packet(DATA, false, &[1, 2, 3]);
+        broken[7] ^= 0xFF;
+
+        assert_eq!((ALIVE, CHECKSUM_ERROR), send(&mut printer, &broken));
+        assert!(printer.buffer.is_empty());
+    }
+
+    #[test]
+    fn data_is_buffered_until_printed()
+    {
+        let mut printer = GameBoyPrinter::new(PathBuf::new());
+
+        assert_eq!((ALIVE, UNPROCESSED_DATA), send(&mut printer, &packet(DATA, false, &[0xAA; 640])));
+        assert_eq!(640, printer.buffer.len());
+        assert_eq!((ALIVE, 0x00), send(&mut printer, &packet(INIT, false, &[])));
+        assert!(printer.buffer.is_empty());
+    }
+
+    #[test]
+    fn compressed_data_is_expanded()
+    {
+        assert_eq!(vec![7, 7, 7, 7, 1, 2, 3], decompress(&[0x82, 7, 0x02, 1, 2, 3]));
+    }
+
+    #[test]
+    fn unknown_command_is_a_packet_error()
+    {
+        let mut printer = GameBoyPrinter::new(PathBuf::new());
+
+        assert_eq!((ALIVE, PACKET_ERROR), send(&mut printer, &packet(0x05, false, &[])));
+    }
+
+    #[test]
+    fn tiles_are_rendered_with_palette()
+    {
+        let mut tiles = vec![0x00; TILES_PER_ROW * TILE_BYTES];
+        // First line of the first tile: colors 3, 2, 1, 0, 0, 0, 0, 0
+        tiles[0] = 0b1010_0000;
+        tiles[1] = 0b1100_0000;
+
+        let pixels = render(&tiles, 0xE4);
+        let inverted = render(&tiles, 0x1B);
+
+        assert_eq!(8 * PAPER_WIDTH, pixels.len());
+        assert_eq!(&[0x00, 0x55, 0xAA, 0xFF], &pixels[0..4]);
+        assert_eq!(&[0xFF, 0xAA, 0x55, 0x00], &inverted[0..4]);
+        assert_eq!(0xFF, pixels[PAPER_WIDTH]);
+    }
+
+    #[test]
+    fn incomplete_rows_are_filled_up()
+    {
+        let directory = temporary_directory("incomplete");
+        let mut printer = GameBoyPrinter::new(directory.clone());
+        send(&mut printer, &packet(DATA, false, &[0xFF; 3 * TILE_BYTES]));
+
+        send(&mut printer, &packet(PRINT, false, &[1, 0x00, 0xE4, 0x40]));
+        let paper = std::mem::take(&mut printer.paper);
+        drop(printer);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(8 * PAPER_WIDTH, paper.len());
+        assert_eq!(0x00, paper[0]);
+        assert_eq!(0xFF, paper[3 * 8]);
+    }
+
+    #[test]
+    fn printing_reports_busy_for_a_while()
+    {
+        let mut printer = GameBoyPrinter::new(PathBuf::new());
+        send(&mut printer, &packet(PRINT, false, &[1, 0x00, 0xE4, 0x40]));
+
+        assert_eq!((ALIVE, PRINTING), send(&mut printer, &packet(STATUS, false, &[])));
+        printer.tick(PRINT_CYCLES, None);
+        assert_eq!((ALIVE, 0x00), send(&mut printer, &packet(STATUS, false, &[])));
+    }
+
+    #[test]
+    fn prints_without_margin_end_up_on_one_picture()
+    {
+        let directory = temporary_directory("strip");
+        let mut printer = GameBoyPrinter::new(directory.clone());
+
+        for _ in 0..2
+        {
+            send(&mut printer, &packet(DATA, false, &[0x00; 640]));
+            send(&mut printer, &packet(DATA, false, &[]));
+            send(&mut printer, &packet(PRINT, false, &[1, 0x00, 0xE4, 0x40]));
+        }
+        assert_eq!(0, std::fs::read_dir(&directory).unwrap().count());
+        send(&mut printer, &packet(DATA, false, &[0xFF; 640]));
+        send(&mut printer, &packet(PRINT, false, &[1, 0x03, 0xE4, 0x40]));
+
+        let files: Vec<PathBuf> = std::fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        let picture = image::open(&files[0]).unwrap().to_luma();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!((160, 48), picture.dimensions());
+        assert_eq!(0xFF, picture.get_pixel(0, 0)[0]);
+        assert_eq!(0x00, picture.get_pixel(0, 47)[0]);
+    }
+}
@@ -0,0 +1,63 @@
+/*
+
+Where the Game Boy screen goes in the window. The picture gets the largest
+whole scale factor that fits, so every Game Boy pixel is a square of the
+same size, and it is centered with black bars around it. A window smaller
+than the screen shrinks the picture instead, still keeping its aspect ratio.
+
+*/
+
+use rboy::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+#[derive(Debug, PartialEq)]
+pub struct Placement
+{
+    pub x: f64,
+    pub y: f64,
+    pub scale: f64,
+}
+
+pub fn place_screen(window_size: [f64; 2]) -> Placement
+{
+    let fit = (window_size[0] / SCREEN_WIDTH as f64).min(window_size[1] / SCREEN_HEIGHT as f64);
+    let scale = if fit >= 1.0 { fit.floor() } else { fit.max(0.0) };
+    Placement {
+        x: ((window_size[0] - SCREEN_WIDTH as f64 * scale) / 2.0).floor(),
+        y: ((window_size[1] - SCREEN_HEIGHT as f64 * scale) / 2.0).floor(),
+        scale,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn exact_fit()
+    {
+        assert_eq!(Placement { x: 0.0, y: 0.0, scale: 3.0 }, place_screen([480.0, 432.0]));
+    }
+
+    #[test]
+    fn wide_window_gets_bars_on_the_sides()
+    {
+        assert_eq!(Placement { x: 480.0, y: 0.0, scale: 7.0 }, place_screen([2080.0, 1008.0]));
+    }
+
+    #[test]
+    fn only_whole_scale_factors()
+    {
+        assert_eq!(Placement { x: 79.0, y: 71.0, scale: 2.0 }, place_screen([479.0, 431.0]));
+    }
+
+    #[test]
+    fn small_window_shrinks_the_screen()
+    {
+        let placement = place_screen([80.0, 144.0]);
+
+        assert_eq!(0.5, placement.scale);
+        assert_eq!(0.0, placement.x);
+        assert_eq!(36.0, placement.y);
+    }
+}
@@ -0,0 +1,270 @@
+use std::time::{Duration, Instant};
+
+use rboy::hardware::ppu::FRAME_DOTS;
+
+/*
+
+Keeps the emulation at the speed of a real Game Boy, one frame of 70224 dots
+at 4.194304 MHz, which is about 59.7275 frames per second. The window can
+refresh at whatever rate it likes, the limiter tells how many emulated
+frames are due whenever it gets asked.
+
+If the emulation falls behind, it catches up by running a few frames in a
+row. Anything beyond that is dropped, so a stall does not turn into a long
+stretch of fast forward afterwards.
+
+The speed can be changed from the frontend:
+  * Holding fast forward runs at a multiple of the speed or uncapped
+  * Slow motion runs at half or a quarter of the speed
+  * Pausing stops the emulation, single frames can still be advanced
+
+*/
+
+const DOTS_PER_SECOND: u64 = 4_194_304;
+const MAX_CATCH_UP_FRAMES: u32 = 4;
+// Frames per poll without a speed limit
+const UNCAPPED_FRAMES: u32 = 8;
+const SLOW_MOTION_SPEEDS: [f64; 3] = [1.0, 0.5, 0.25];
+
+pub fn frame_duration() -> Duration
+{
+    Duration::from_nanos(FRAME_DOTS as u64 * 1_000_000_000 / DOTS_PER_SECOND)
+}
+
+pub struct FrameLimiter
+{
+    // None runs as fast as possible
+    frame_duration: Option<Duration>,
+    next_frame: Instant,
+}
+
+impl FrameLimiter
+{
+    pub fn new(now: Instant) -> FrameLimiter
+    {
+        FrameLimiter { frame_duration: Some(frame_duration()), next_frame: now }
+    }
+
+    /// Changes the speed as a multiple of the real one, None for uncapped.
+    /// The next frame is due right away.
+    pub fn set_speed(&mut self, speed: Option<f64>, now: Instant)
+    {
+        self.frame_duration = speed.map(|speed| frame_duration().div_f64(speed));
+        self.next_frame = now;
+    }
+
+    /// The amount of frames to run to keep up with the clock
+    pub fn frames_due(&mut self, now: Instant) -> u32
+    {
+        let frame_duration = match self.frame_duration {
+            Some(duration) => duration,
+            None => return UNCAPPED_FRAMES
+        };
+        let mut frames = 0;
+        while now >= self.next_frame && frames < MAX_CATCH_UP_FRAMES
+        {
+            frames += 1;
+            self.next_frame += frame_duration;
+        }
+        if now >= self.next_frame
+        {
+            self.next_frame = now + frame_duration;
+        }
+        frames
+    }
+}
+
+/// Picks the frames of sound to play while fast forwarding, skipping the rest
+/// keeps the pitch
+#[derive(Default)]
+pub struct SoundSkip
+{
+    owed: f64,
+}
+
+impl SoundSkip
+{
+    /// Whether the sound of the next frame should be played at this speed
+    pub fn play(&mut self, speed: f64) -> bool
+    {
+        self.owed += 1.0 / speed.max(1.0);
+        if self.owed >= 1.0
+        {
+            self.owed -= 1.0;
+            return true;
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct SpeedControl
+{
+    /// Speed while fast forward is held, None for uncapped
+    fast_forward_speed: Option<f64>,
+    fast_forward: bool,
+    slow_motion: usize,
+    paused: bool,
+    frame_advance: bool,
+}
+
+impl SpeedControl
+{
+    /// Fast forward runs at the given speed, None for uncapped
+    pub fn new(fast_forward_speed: Option<f64>) -> SpeedControl
+    {
+        SpeedControl { fast_forward_speed, ..SpeedControl::default() }
+    }
+
+    /// The speed the frame limiter should run at, None for uncapped
+    pub fn speed(&self) -> Option<f64>
+    {
+        if self.fast_forward { self.fast_forward_speed } else { Some(SLOW_MOTION_SPEEDS[self.slow_motion]) }
+    }
+
+    pub fn paused(&self) -> bool
+    {
+        self.paused
+    }
+
+    pub fn set_fast_forward(&mut self, held: bool)
+    {
+        self.fast_forward = held;
+    }
+
+    pub fn toggle_pause(&mut self)
+    {
+        self.paused = !self.paused;
+        self.frame_advance = false;
+    }
+
+    /// Switches to the next slow motion speed, back to normal after the slowest
+    pub fn cycle_slow_motion(&mut self)
+    {
+        self.slow_motion = (self.slow_motion + 1) % SLOW_MOTION_SPEEDS.len();
+    }
+
+    /// Runs a single frame, only while paused
+    pub fn advance_frame(&mut self)
+    {
+        self.frame_advance = self.paused;
+    }
+
+    /// Whether a single frame should run now
+    pub fn take_frame_advance(&mut self) -> bool
+    {
+        std::mem::take(&mut self.frame_advance)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn frame_rate_of_a_game_boy()
+    {
+        let rate = 1.0 / frame_duration().as_secs_f64();
+
+        assert!((rate - 59.7275).abs() < 0.0001, "{}", rate);
+    }
+
+    #[test]
+    fn first_frame_is_due_right_away()
+    {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(start);
+
+        assert_eq!(1, limiter.frames_due(start));
+        assert_eq!(0, limiter.frames_due(start + frame_duration() / 2));
+        assert_eq!(1, limiter.frames_due(start + frame_duration()));
+    }
+
+    #[test]
+    fn one_second_runs_60_frames()
+    {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(start);
+
+        let frames: u32 = (0..1000).map(|millisecond| limiter.frames_due(start + Duration::from_millis(millisecond))).sum();
+
+        assert_eq!(60, frames);
+    }
+
+    #[test]
+    fn half_speed_runs_half_the_frames()
+    {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(start);
+        limiter.set_speed(Some(0.5), start);
+
+        let frames: u32 = (0..1000).map(|millisecond| limiter.frames_due(start + Duration::from_millis(millisecond))).sum();
+
+        assert_eq!(30, frames);
+    }
+
+    #[test]
+    fn uncapped_runs_frames_on_every_poll()
+    {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(start);
+        limiter.set_speed(None, start);
+
+        assert_eq!(UNCAPPED_FRAMES, limiter.frames_due(start));
+        assert_eq!(UNCAPPED_FRAMES, limiter.frames_due(start));
+    }
+
+    #[test]
+    fn fractional_speeds_skip_the_right_share_of_sound()
+    {
+        let mut skip = SoundSkip::default();
+
+        let played = (0..10).filter(|_| skip.play(2.5)).count();
+        let played_slowly = (0..10).filter(|_| skip.play(0.5)).count();
+
+        assert_eq!(4, played);
+        assert_eq!(10, played_slowly);
+    }
+
+    #[test]
+    fn fast_forward_overrides_slow_motion()
+    {
+        let mut control = SpeedControl::new(Some(4.0));
+        control.cycle_slow_motion();
+        assert_eq!(Some(0.5), control.speed());
+
+        control.set_fast_forward(true);
+        assert_eq!(Some(4.0), control.speed());
+        control.set_fast_forward(false);
+        control.cycle_slow_motion();
+        control.cycle_slow_motion();
+
+        assert_eq!(Some(1.0), control.speed());
+    }
+
+    #[test]
+    fn frames_only_advance_while_paused()
+    {
+        let mut control = SpeedControl::default();
+        control.advance_frame();
+        assert!(!control.take_frame_advance());
+
+        control.toggle_pause();
+        control.advance_frame();
+
+        assert!(control.paused());
+        assert!(control.take_frame_advance());
+        assert!(!control.take_frame_advance());
+    }
+
+    #[test]
+    fn catching_up_is_limited()
+    {
+        let start = Instant::now();
+        let mut limiter = FrameLimiter::new(start);
+
+        assert_eq!(MAX_CATCH_UP_FRAMES, limiter.frames_due(start + Duration::from_secs(1)));
+        assert_eq!(0, limiter.frames_due(start + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,169 @@
+#[cfg(feature = "gamepad")]
+extern crate gilrs;
+
+use crate::input::ControllerEvent;
+
+/*
+
+Controllers, read with gilrs next to the window events. gilrs knows the
+layout of most controllers and names their buttons, which get numbered like
+the SDL game controller layout for the bindings:
+  0 south, 1 east, 2 west, 3 north, 4 back, 5 guide, 6 start,
+  7 left stick, 8 right stick, 9 left shoulder, 10 right shoulder,
+  11 up, 12 down, 13 left, 14 right
+
+Controllers that are plugged in at the start count as connected right away,
+later ones whenever gilrs reports them.
+
+Reading controllers is only built with the "gamepad" feature, because it
+needs the udev development files on Linux. Without it only the keyboard
+works.
+
+*/
+
+pub struct Gamepads
+{
+    #[cfg(feature = "gamepad")]
+    gilrs: gilrs::Gilrs,
+    #[cfg(feature = "gamepad")]
+    connected_at_start: Vec<ControllerEvent>,
+}
+
+impl Gamepads
+{
+    #[cfg(feature = "gamepad")]
+    pub fn open() -> Result<Gamepads, String>
+    {
+        let gilrs = gilrs::Gilrs::new().map_err(|error| error.to_string())?;
+        let connected_at_start = gilrs.gamepads()
+            .map(|(id, gamepad)| ControllerEvent::Connected(id.into(), gamepad.name().to_string()))
+            .collect();
+        Ok(Gamepads { gilrs, connected_at_start })
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn open() -> Result<Gamepads, String>
+    {
+        Err("Built without the gamepad feature, controllers are ignored".to_string())
+    }
+
+    /// Everything that happened on the controllers since the last call
+    #[cfg(feature = "gamepad")]
+    pub fn poll(&mut self) -> Vec<ControllerEvent>
+    {
+        let mut events: Vec<ControllerEvent> = self.connected_at_start.drain(..).collect();
+        while let Some(gilrs::Event { id, event, .. }) = self.gilrs.next_event()
+        {
+            let name = self.gilrs.gamepad(id).name().to_string();
+            events.extend(translate(id.into(), event, name));
+        }
+        events
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn poll(&mut self) -> Vec<ControllerEvent>
+    {
+        vec![]
+    }
+}
+
+#[cfg(feature = "gamepad")]
+fn translate(id: usize, event: gilrs::EventType, name: String) -> Option<ControllerEvent>
+{
+    use gilrs::EventType;
+
+    match event {
+        EventType::Connected => Some(ControllerEvent::Connected(id, name)),
+        EventType::Disconnected => Some(ControllerEvent::Disconnected(id)),
+        EventType::ButtonPressed(button, _) => button_number(button).map(|number| ControllerEvent::Button(id, number, true)),
+        EventType::ButtonReleased(button, _) => button_number(button).map(|number| ControllerEvent::Button(id, number, false)),
+        EventType::AxisChanged(axis, position, _) => stick_axis(axis, position).map(|(number, position)| ControllerEvent::Axis(id, number, position)),
+        _ => None
+    }
+}
+
+/// The number of a button in the SDL layout
+#[cfg(feature = "gamepad")]
+fn button_number(button: gilrs::Button) -> Option<u8>
+{
+    use gilrs::Button;
+
+    let number = match button {
+        Button::South => 0,
+        Button::East => 1,
+        Button::West => 2,
+        Button::North => 3,
+        Button::Select => 4,
+        Button::Mode => 5,
+        Button::Start => 6,
+        Button::LeftThumb => 7,
+        Button::RightThumb => 8,
+        Button::LeftTrigger => 9,
+        Button::RightTrigger => 10,
+        Button::DPadUp => 11,
+        Button::DPadDown => 12,
+        Button::DPadLeft => 13,
+        Button::DPadRight => 14,
+        _ => return None
+    };
+    Some(number)
+}
+
+/// The number of a left stick axis in the SDL layout and its position, gilrs counts up as positive
+#[cfg(feature = "gamepad")]
+fn stick_axis(axis: gilrs::Axis, position: f32) -> Option<(u8, f32)>
+{
+    match axis {
+        gilrs::Axis::LeftStickX => Some((0, position)),
+        gilrs::Axis::LeftStickY => Some((1, -position)),
+        _ => None
+    }
+}
+
+#[cfg(all(test, feature = "gamepad"))]
+mod tests
+{
+    use super::*;
+    use crate::input::{ControllerMapping, InputState};
+    use rboy::Button;
+
+    #[test]
+    fn buttons_use_the_sdl_numbers()
+    {
+        let mapping = ControllerMapping::default();
+
+        assert_eq!(Some(Button::A), button_number(gilrs::Button::East).and_then(|number| mapping.button_for(number)));
+        assert_eq!(Some(Button::B), button_number(gilrs::Button::South).and_then(|number| mapping.button_for(number)));
+        assert_eq!(Some(Button::Select), button_number(gilrs::Button::Select).and_then(|number| mapping.button_for(number)));
+        assert_eq!(Some(Button::Right), button_number(gilrs::Button::DPadRight).and_then(|number| mapping.button_for(number)));
+        assert_eq!(None, button_number(gilrs::Button::LeftTrigger2));
+    }
+
+    #[test]
+    fn pushing_the_stick_up_presses_up()
+    {
+        let mapping = ControllerMapping::default();
+        let mut state = InputState::default();
+        let (axis, position) = stick_axis(gilrs::Axis::LeftStickY, 0.8).unwrap();
+
+        for change in mapping.axis_changes(0, axis, position)
+        {
+            state.set(change);
+        }
+
+        assert!(state.is_held(Button::Up));
+        assert!(!state.is_held(Button::Down));
+        assert_eq!(None, stick_axis(gilrs::Axis::RightStickX, 1.0));
+    }
+
+    #[test]
+    fn plugging_in_and_out()
+    {
+        let connected = translate(2, gilrs::EventType::Connected, "Pad".to_string());
+        let disconnected = translate(2, gilrs::EventType::Disconnected, String::new());
+
+        assert_eq!(Some(ControllerEvent::Connected(2, "Pad".to_string())), connected);
+        assert_eq!(Some(ControllerEvent::Disconnected(2)), disconnected);
+        assert_eq!(None, translate(2, gilrs::EventType::Dropped, String::new()));
+    }
+}
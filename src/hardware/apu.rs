@@ -0,0 +1,1352 @@
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+Audio processing unit with four channels, registers 0xFF10 - 0xFF3F:
+  * 0xFF10 - 0xFF14: Channel 1, pulse with frequency sweep
+  * 0xFF16 - 0xFF19: Channel 2, pulse
+  * 0xFF1A - 0xFF1E: Channel 3, wave output from wave RAM (0xFF30 - 0xFF3F)
+  * 0xFF20 - 0xFF23: Channel 4, noise from a linear feedback shift register
+  * 0xFF24:          NR50, master volume for the left (bits 4-6) and right (bits 0-2) output
+  * 0xFF25:          NR51, panning, bits 4-7 route channel 1-4 to the left, bits 0-3 to the right
+  * 0xFF26:          NR52, bit 7 powers the APU, bits 0-3 show which channels are active
+
+The frame sequencer runs at 512 Hz and clocks the length counters (256 Hz),
+the sweep (128 Hz) and the volume envelopes (64 Hz):
+  Step:      0  1  2  3  4  5  6  7
+  Length:    x     x     x     x
+  Sweep:           x           x
+  Envelope:                       x
+
+Every channel has a DAC which is enabled by the upper 5 bits of NRx2
+(NR30 bit 7 for the wave channel). Turning the DAC off also turns the
+channel off, triggering a channel with its DAC off does nothing.
+
+Some quirks that games and test ROMs rely on:
+  * Enabling the length counter while the next frame sequencer step does not
+    clock it clocks it once more, a trigger in that situation loads 63 / 255
+  * Clearing the sweep negate bit after a negated calculation turns channel 1 off
+  * While the wave channel plays, wave RAM accesses go to the byte it is
+    currently reading. On DMG hardware this only works in the cycle the
+    channel reads it, otherwise reads return 0xFF and writes are lost
+  * Powering off clears all registers and ignores writes to them, except for
+    the length counters on DMG hardware. Wave RAM keeps its contents
+
+The APU produces one stereo sample every 4 dots (1048576 Hz). Samples are
+only collected while output_enabled is set, so nobody has to drain them. The
+high-pass filter of the mix runs either way, it is part of the save state
+and has to end up the same with and without output.
+With channel_output_enabled every channel is collected separately as well,
+panned, scaled by the master volume and through a high-pass filter of its
+own. The filter is linear, so the channels add up to the mixed output once
+the filters settled after enabling it.
+
+*/
+
+// Samples are produced at normal speed, even in CGB double speed mode
+pub const SAMPLE_RATE: u32 = 4_194_304 / DOTS_PER_SAMPLE;
+const DOTS_PER_SAMPLE: u32 = 4;
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+// How far the wave samples get shifted right for each NR32 volume code
+const WAVE_VOLUME_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+// Unused and write only bits read back as 1, indexed from 0xFF10
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+];
+
+// Charge factor of the output capacitor for one sample, it removes the DC offset of the DACs
+const HIGH_PASS_CHARGE_FACTOR: f32 = 0.999_832;
+
+pub type StereoSample = [f32; 2];
+
+#[derive(Default)]
+struct LengthCounter
+{
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter
+{
+    fn new(max: u16) -> LengthCounter
+    {
+        LengthCounter { max, counter: 0, enabled: false }
+    }
+
+    fn load(&mut self, length: u8)
+    {
+        self.counter = self.max - length as u16;
+    }
+
+    /// Returns true if the counter ran out and the channel has to be turned off
+    fn clock(&mut self) -> bool
+    {
+        if !self.enabled || self.counter == 0
+        {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    /// Handles the length enable bit of NRx4. Returns true if the channel has to be turned off.
+    fn write_enable(&mut self, enabled: bool, next_step_skips_length: bool) -> bool
+    {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        !was_enabled && enabled && next_step_skips_length && self.clock()
+    }
+
+    fn trigger(&mut self, next_step_skips_length: bool)
+    {
+        if self.counter == 0
+        {
+            self.counter = self.max;
+            if self.enabled && next_step_skips_length
+            {
+                self.counter -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Envelope
+{
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope
+{
+    fn write(&mut self, value: u8)
+    {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 > 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self)
+    {
+        self.volume = self.initial_volume;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn clock(&mut self)
+    {
+        if self.period == 0
+        {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0
+        {
+            self.timer = self.period;
+            if self.increase && self.volume < 15
+            {
+                self.volume += 1;
+            }
+            else if !self.increase && self.volume > 0
+            {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sweep
+{
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    negate_used: bool,
+}
+
+impl Sweep
+{
+    /// Handles a write to NR10. Returns true if channel 1 has to be turned off.
+    fn write(&mut self, value: u8) -> bool
+    {
+        let was_negate = self.negate;
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & 0x08 > 0;
+        self.shift = value & 0x07;
+        was_negate && !self.negate && self.negate_used
+    }
+
+    fn reload_timer(&mut self)
+    {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn calculate(&mut self) -> u16
+    {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate
+        {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        }
+        else
+        {
+            self.shadow_frequency + delta
+        }
+    }
+
+    /// Returns true if the overflow check turned channel 1 off
+    fn trigger(&mut self, frequency: u16) -> bool
+    {
+        self.shadow_frequency = frequency;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negate_used = false;
+        self.shift != 0 && self.calculate() > 0x7FF
+    }
+}
+
+#[derive(Default)]
+struct Pulse
+{
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Pulse
+{
+    fn new() -> Pulse
+    {
+        Pulse { length: LengthCounter::new(64), ..Pulse::default() }
+    }
+
+    fn period(&self) -> u32
+    {
+        (0x800 - self.frequency as u32) * 4
+    }
+
+    fn write_length(&mut self, value: u8)
+    {
+        self.duty = value >> 6;
+        self.length.load(value & 0x3F);
+    }
+
+    fn write_envelope(&mut self, value: u8)
+    {
+        self.envelope.write(value);
+        self.dac_enabled = value & 0xF8 > 0;
+        self.enabled &= self.dac_enabled;
+    }
+
+    fn write_control(&mut self, value: u8, next_step_skips_length: bool)
+    {
+        self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+        if self.length.write_enable(value & 0x40 > 0, next_step_skips_length)
+        {
+            self.enabled = false;
+        }
+        if value & 0x80 > 0
+        {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(next_step_skips_length);
+            self.timer = self.period();
+            self.envelope.trigger();
+        }
+    }
+
+    fn tick(&mut self)
+    {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0
+        {
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> u8
+    {
+        let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_position)) & 0x01 > 0;
+        if self.enabled && high { self.envelope.volume } else { 0 }
+    }
+}
+
+#[derive(Default)]
+struct Wave
+{
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample_buffer: u8,
+    just_read: bool,
+    ram: [u8; 0x10],
+    length: LengthCounter,
+}
+
+impl Wave
+{
+    fn new() -> Wave
+    {
+        Wave { length: LengthCounter::new(256), ..Wave::default() }
+    }
+
+    fn period(&self) -> u32
+    {
+        (0x800 - self.frequency as u32) * 2
+    }
+
+    fn write_control(&mut self, value: u8, next_step_skips_length: bool)
+    {
+        self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+        if self.length.write_enable(value & 0x40 > 0, next_step_skips_length)
+        {
+            self.enabled = false;
+        }
+        if value & 0x80 > 0
+        {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(next_step_skips_length);
+            // The first sample gets read a little later than the following ones
+            self.timer = self.period() + 6;
+            self.position = 0;
+        }
+    }
+
+    fn tick(&mut self)
+    {
+        self.just_read = false;
+        if !self.enabled
+        {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0
+        {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+            self.sample_buffer = self.ram[self.position as usize / 2];
+            self.just_read = true;
+        }
+    }
+
+    fn output(&self) -> u8
+    {
+        if !self.enabled
+        {
+            return 0;
+        }
+        let sample = if self.position & 0x01 == 0 { self.sample_buffer >> 4 } else { self.sample_buffer & 0x0F };
+        sample >> WAVE_VOLUME_SHIFTS[self.volume_code as usize]
+    }
+
+    /// Returns the index of the wave RAM byte an access goes to, None if it gets lost
+    fn ram_index(&self, index: usize, cgb_hardware: bool) -> Option<usize>
+    {
+        if !self.enabled
+        {
+            Some(index)
+        }
+        else if cgb_hardware || self.just_read
+        {
+            Some(self.position as usize / 2)
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct Noise
+{
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    narrow: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Noise
+{
+    fn new() -> Noise
+    {
+        Noise { length: LengthCounter::new(64), ..Noise::default() }
+    }
+
+    fn period(&self) -> u32
+    {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn write_envelope(&mut self, value: u8)
+    {
+        self.envelope.write(value);
+        self.dac_enabled = value & 0xF8 > 0;
+        self.enabled &= self.dac_enabled;
+    }
+
+    fn write_polynomial(&mut self, value: u8)
+    {
+        self.clock_shift = value >> 4;
+        self.narrow = value & 0x08 > 0;
+        self.divisor_code = value & 0x07;
+    }
+
+    fn write_control(&mut self, value: u8, next_step_skips_length: bool)
+    {
+        if self.length.write_enable(value & 0x40 > 0, next_step_skips_length)
+        {
+            self.enabled = false;
+        }
+        if value & 0x80 > 0
+        {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(next_step_skips_length);
+            self.timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+        }
+    }
+
+    fn tick(&mut self)
+    {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0
+        {
+            self.timer = self.period();
+            // The LFSR does not get clocked with shifts of 14 and 15
+            if self.clock_shift < 14
+            {
+                self.clock_lfsr();
+            }
+        }
+    }
+
+    fn clock_lfsr(&mut self)
+    {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.narrow
+        {
+            self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+        }
+    }
+
+    fn output(&self) -> u8
+    {
+        if self.enabled && self.lfsr & 0x01 == 0 { self.envelope.volume } else { 0 }
+    }
+}
+
+pub struct Apu
+{
+    pub cgb_hardware: bool,
+    pub output_enabled: bool,
+    pub channel_output_enabled: bool,
+    powered: bool,
+    registers: [u8; 0x17],
+    pulse1: Pulse,
+    sweep: Sweep,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    frame_step: u8,
+    frame_timer: u32,
+    sample_timer: u32,
+    capacitors: [f32; 2],
+    // Only for the separate channel output, not part of the machine state
+    channel_capacitors: [StereoSample; 4],
+    samples: Vec<StereoSample>,
+    channel_samples: Vec<[StereoSample; 4]>,
+}
+
+impl Default for Apu {
+    fn default() -> Apu
+    {
+        let mut apu = Apu {
+            cgb_hardware: true,
+            output_enabled: false,
+            channel_output_enabled: false,
+            powered: true,
+            registers: [0; 0x17],
+            pulse1: Pulse::new(),
+            sweep: Sweep::default(),
+            pulse2: Pulse::new(),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_step: 0,
+            frame_timer: FRAME_SEQUENCER_PERIOD,
+            sample_timer: DOTS_PER_SAMPLE,
+            capacitors: [0.0; 2],
+            channel_capacitors: [[0.0; 2]; 4],
+            samples: vec![],
+            channel_samples: vec![],
+        };
+        // State left behind by the boot ROM
+        apu.write_register(0xFF11, 0x80);
+        apu.write_register(0xFF12, 0xF3);
+        apu.write_register(0xFF24, 0x77);
+        apu.write_register(0xFF25, 0xF3);
+        apu
+    }
+}
+
+impl Apu
+{
+    fn next_step_skips_length(&self) -> bool
+    {
+        self.frame_step & 0x01 > 0
+    }
+
+    fn channel_status(&self) -> u8
+    {
+        [self.pulse1.enabled, self.pulse2.enabled, self.wave.enabled, self.noise.enabled].iter()
+            .enumerate()
+            .fold(0, |status, (channel, enabled)| if *enabled { status | (1 << channel) } else { status })
+    }
+
+    pub fn read_register(&self, address: u16) -> u8
+    {
+        match address {
+            0xFF26 => {
+                let power = if self.powered { 0x80 } else { 0x00 };
+                0x70 | power | self.channel_status()
+            },
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            },
+            0xFF30..=0xFF3F => {
+                match self.wave.ram_index((address - 0xFF30) as usize, self.cgb_hardware) {
+                    Some(index) => self.wave.ram[index],
+                    None => 0xFF
+                }
+            },
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8)
+    {
+        match address {
+            0xFF26 => self.write_power(value & 0x80 > 0),
+            0xFF30..=0xFF3F => {
+                if let Some(index) = self.wave.ram_index((address - 0xFF30) as usize, self.cgb_hardware)
+                {
+                    self.wave.ram[index] = value;
+                }
+            },
+            0xFF10..=0xFF25 if self.powered => {
+                self.registers[(address - 0xFF10) as usize] = value;
+                self.write_channel_register(address, value);
+            },
+            // Only the length counters can be written while powered off, and only on DMG hardware
+            0xFF11 | 0xFF16 | 0xFF20 if !self.cgb_hardware => self.write_channel_register(address, value & 0x3F),
+            0xFF1B if !self.cgb_hardware => self.write_channel_register(address, value),
+            _ => ()
+        }
+    }
+
+    fn write_channel_register(&mut self, address: u16, value: u8)
+    {
+        let next_step_skips_length = self.next_step_skips_length();
+        match address {
+            0xFF10 => self.pulse1.enabled &= !self.sweep.write(value),
+            0xFF11 => self.pulse1.write_length(value),
+            0xFF12 => self.pulse1.write_envelope(value),
+            0xFF13 => self.pulse1.frequency = (self.pulse1.frequency & 0x700) | value as u16,
+            0xFF14 => {
+                self.pulse1.write_control(value, next_step_skips_length);
+                if value & 0x80 > 0 && self.sweep.trigger(self.pulse1.frequency)
+                {
+                    self.pulse1.enabled = false;
+                }
+            },
+            0xFF16 => self.pulse2.write_length(value),
+            0xFF17 => self.pulse2.write_envelope(value),
+            0xFF18 => self.pulse2.frequency = (self.pulse2.frequency & 0x700) | value as u16,
+            0xFF19 => self.pulse2.write_control(value, next_step_skips_length),
+            0xFF1A => {
+                self.wave.dac_enabled = value & 0x80 > 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            },
+            0xFF1B => self.wave.length.load(value),
+            0xFF1C => self.wave.volume_code = (value >> 5) & 0x03,
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0xFF1E => self.wave.write_control(value, next_step_skips_length),
+            0xFF20 => self.noise.length.load(value & 0x3F),
+            0xFF21 => self.noise.write_envelope(value),
+            0xFF22 => self.noise.write_polynomial(value),
+            0xFF23 => self.noise.write_control(value, next_step_skips_length),
+            _ => ()
+        }
+    }
+
+    fn write_power(&mut self, powered: bool)
+    {
+        if self.powered && !powered
+        {
+            let lengths = [self.pulse1.length.counter, self.pulse2.length.counter,
+                           self.wave.length.counter, self.noise.length.counter];
+            self.registers = [0; 0x17];
+            self.pulse1 = Pulse::new();
+            self.sweep = Sweep::default();
+            self.pulse2 = Pulse::new();
+            self.wave = Wave { ram: self.wave.ram, ..Wave::new() };
+            self.noise = Noise::new();
+            if !self.cgb_hardware
+            {
+                self.pulse1.length.counter = lengths[0];
+                self.pulse2.length.counter = lengths[1];
+                self.wave.length.counter = lengths[2];
+                self.noise.length.counter = lengths[3];
+            }
+        }
+        else if !self.powered && powered
+        {
+            self.frame_step = 0;
+            self.frame_timer = FRAME_SEQUENCER_PERIOD;
+        }
+        self.powered = powered;
+    }
+
+    fn clock_lengths(&mut self)
+    {
+        if self.pulse1.length.clock()
+        {
+            self.pulse1.enabled = false;
+        }
+        if self.pulse2.length.clock()
+        {
+            self.pulse2.enabled = false;
+        }
+        if self.wave.length.clock()
+        {
+            self.wave.enabled = false;
+        }
+        if self.noise.length.clock()
+        {
+            self.noise.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self)
+    {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0
+        {
+            return;
+        }
+        self.sweep.reload_timer();
+        if !self.sweep.enabled || self.sweep.period == 0
+        {
+            return;
+        }
+        let frequency = self.sweep.calculate();
+        if frequency > 0x7FF
+        {
+            self.pulse1.enabled = false;
+        }
+        else if self.sweep.shift != 0
+        {
+            self.sweep.shadow_frequency = frequency;
+            self.pulse1.frequency = frequency;
+            // The new frequency gets checked for an overflow right away
+            if self.sweep.calculate() > 0x7FF
+            {
+                self.pulse1.enabled = false;
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self)
+    {
+        match self.frame_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.clock_sweep();
+            },
+            7 => {
+                self.pulse1.envelope.clock();
+                self.pulse2.envelope.clock();
+                self.noise.envelope.clock();
+            },
+            _ => ()
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    fn dac_output(digital: u8, dac_enabled: bool) -> f32
+    {
+        if dac_enabled { digital as f32 / 7.5 - 1.0 } else { 0.0 }
+    }
+
+    /// Returns every channel panned and scaled by the master volume
+    fn channel_outputs(&self) -> [StereoSample; 4]
+    {
+        let outputs = [
+            Apu::dac_output(self.pulse1.output(), self.pulse1.dac_enabled),
+            Apu::dac_output(self.pulse2.output(), self.pulse2.dac_enabled),
+            Apu::dac_output(self.wave.output(), self.wave.dac_enabled),
+            Apu::dac_output(self.noise.output(), self.noise.dac_enabled),
+        ];
+        let panning = self.registers[0x15];
+        let volumes = self.registers[0x14];
+        let mut channels = [[0.0; 2]; 4];
+        for (side, shift) in [4, 0].iter().enumerate()
+        {
+            let volume = ((volumes >> shift) & 0x07) as f32 + 1.0;
+            for (channel, output) in outputs.iter().enumerate()
+            {
+                if panning & (1 << (channel + shift)) > 0
+                {
+                    channels[channel][side] = output / 4.0 * volume / 8.0;
+                }
+            }
+        }
+        channels
+    }
+
+    /// Passes a sample through the output capacitor, which charges towards the DC offset
+    fn high_pass(input: f32, capacitor: &mut f32) -> f32
+    {
+        let output = input - *capacitor;
+        *capacitor = input - output * HIGH_PASS_CHARGE_FACTOR;
+        output
+    }
+
+    fn mix(&mut self, channels: &[StereoSample; 4]) -> StereoSample
+    {
+        let mut sample = [0.0; 2];
+        for side in 0..2
+        {
+            let input: f32 = channels.iter().map(|channel| channel[side]).sum();
+            sample[side] = Apu::high_pass(input, &mut self.capacitors[side]);
+        }
+        sample
+    }
+
+    fn filter_channels(&mut self, channels: &[StereoSample; 4]) -> [StereoSample; 4]
+    {
+        let mut filtered = [[0.0; 2]; 4];
+        for (channel, capacitors) in self.channel_capacitors.iter_mut().enumerate()
+        {
+            for side in 0..2
+            {
+                filtered[channel][side] = Apu::high_pass(channels[channel][side], &mut capacitors[side]);
+            }
+        }
+        filtered
+    }
+
+    /// Advances the APU by the given amount of dots
+    pub fn tick(&mut self, dots: u32)
+    {
+        for _ in 0..dots
+        {
+            if self.powered
+            {
+                self.frame_timer -= 1;
+                if self.frame_timer == 0
+                {
+                    self.frame_timer = FRAME_SEQUENCER_PERIOD;
+                    self.clock_frame_sequencer();
+                }
+                self.pulse1.tick();
+                self.pulse2.tick();
+                self.wave.tick();
+                self.noise.tick();
+            }
+
+            self.sample_timer -= 1;
+            if self.sample_timer == 0
+            {
+                self.sample_timer = DOTS_PER_SAMPLE;
+                // The output filter always runs, its state must not depend on the output
+                let channels = self.channel_outputs();
+                let sample = self.mix(&channels);
+                if self.output_enabled
+                {
+                    self.samples.push(sample);
+                    if self.channel_output_enabled
+                    {
+                        let filtered = self.filter_channels(&channels);
+                        self.channel_samples.push(filtered);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands out the samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<StereoSample>
+    {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Hands out the separate channel outputs produced since the last call
+    pub fn take_channel_samples(&mut self) -> Vec<[StereoSample; 4]>
+    {
+        std::mem::take(&mut self.channel_samples)
+    }
+}
+
+
+impl Snapshot for LengthCounter
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.u16(self.max);
+        state.u16(self.counter);
+        state.bool(self.enabled);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        // The length of a channel never changes
+        if state.u16()? != self.max
+        {
+            return Err(StateError::Corrupt);
+        }
+        self.counter = state.u16()?.min(self.max);
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Envelope
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.u8(self.initial_volume);
+        state.bool(self.increase);
+        state.u8(self.period);
+        state.u8(self.volume);
+        state.u8(self.timer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.initial_volume = state.u8()? & 0x0F;
+        self.increase = state.bool()?;
+        self.period = state.u8()? & 0x07;
+        self.volume = state.u8()? & 0x0F;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Sweep
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.u8(self.period);
+        state.bool(self.negate);
+        state.u8(self.shift);
+        state.u8(self.timer);
+        state.bool(self.enabled);
+        state.u16(self.shadow_frequency);
+        state.bool(self.negate_used);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.period = state.u8()? & 0x07;
+        self.negate = state.bool()?;
+        self.shift = state.u8()? & 0x07;
+        self.timer = state.u8()?;
+        self.enabled = state.bool()?;
+        self.shadow_frequency = state.u16()? & 0x7FF;
+        self.negate_used = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Pulse
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.duty);
+        state.u8(self.duty_position);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        self.length.save(state);
+        self.envelope.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.duty = state.u8()? & 0x03;
+        self.duty_position = state.u8()? & 0x07;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.u32()?;
+        Snapshot::load(&mut self.length, state)?;
+        self.envelope.load(state)
+    }
+}
+
+impl Snapshot for Wave
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.volume_code);
+        state.u16(self.frequency);
+        state.u32(self.timer);
+        state.u8(self.position);
+        state.u8(self.sample_buffer);
+        state.bool(self.just_read);
+        state.bytes(&self.ram);
+        self.length.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.volume_code = state.u8()? & 0x03;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.u32()?;
+        self.position = state.u8()? & 0x1F;
+        self.sample_buffer = state.u8()?;
+        self.just_read = state.bool()?;
+        state.bytes(&mut self.ram)?;
+        Snapshot::load(&mut self.length, state)
+    }
+}
+
+impl Snapshot for Noise
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.u8(self.clock_shift);
+        state.bool(self.narrow);
+        state.u8(self.divisor_code);
+        state.u16(self.lfsr);
+        state.u32(self.timer);
+        self.length.save(state);
+        self.envelope.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.enabled = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.clock_shift = state.u8()? & 0x0F;
+        self.narrow = state.bool()?;
+        self.divisor_code = state.u8()? & 0x07;
+        self.lfsr = state.u16()?;
+        self.timer = state.u32()?;
+        Snapshot::load(&mut self.length, state)?;
+        self.envelope.load(state)
+    }
+}
+
+// Samples that were not taken yet are not part of the state
+impl Snapshot for Apu
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.cgb_hardware);
+        state.bool(self.powered);
+        state.bytes(&self.registers);
+        self.pulse1.save(state);
+        self.sweep.save(state);
+        self.pulse2.save(state);
+        self.wave.save(state);
+        self.noise.save(state);
+        state.u8(self.frame_step);
+        state.u32(self.frame_timer);
+        state.u32(self.sample_timer);
+        state.f32(self.capacitors[0]);
+        state.f32(self.capacitors[1]);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.cgb_hardware = state.bool()?;
+        self.powered = state.bool()?;
+        state.bytes(&mut self.registers)?;
+        self.pulse1.load(state)?;
+        self.sweep.load(state)?;
+        self.pulse2.load(state)?;
+        self.wave.load(state)?;
+        self.noise.load(state)?;
+        self.frame_step = state.u8()? & 0x07;
+        self.frame_timer = state.u32_in(1..=FRAME_SEQUENCER_PERIOD)?;
+        self.sample_timer = state.u32_in(1..=DOTS_PER_SAMPLE)?;
+        self.capacitors = [state.f32()?, state.f32()?];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn status(apu: &Apu) -> u8
+    {
+        apu.read_register(0xFF26) & 0x0F
+    }
+
+    #[test]
+    fn registers_read_back_with_unused_bits_set()
+    {
+        let mut apu = Apu::default();
+
+        apu.write_register(0xFF10, 0x00);
+        apu.write_register(0xFF13, 0x12);
+        apu.write_register(0xFF1C, 0x20);
+
+        assert_eq!(0x80, apu.read_register(0xFF10));
+        assert_eq!(0xFF, apu.read_register(0xFF13));
+        assert_eq!(0xBF, apu.read_register(0xFF1C));
+        assert_eq!(0xFF, apu.read_register(0xFF27));
+    }
+
+    #[test]
+    fn trigger_enables_channel()
+    {
+        let mut apu = Apu::default();
+
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x80);
+
+        assert_eq!(0x02, status(&apu));
+    }
+
+    #[test]
+    fn trigger_with_dac_off_does_nothing()
+    {
+        let mut apu = Apu::default();
+
+        apu.write_register(0xFF17, 0x07);
+        apu.write_register(0xFF19, 0x80);
+
+        assert_eq!(0x00, status(&apu));
+    }
+
+    #[test]
+    fn turning_dac_off_disables_channel()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF1A, 0x80);
+        apu.write_register(0xFF1E, 0x80);
+        assert_eq!(0x04, status(&apu));
+
+        apu.write_register(0xFF1A, 0x00);
+
+        assert_eq!(0x00, status(&apu));
+    }
+
+    #[test]
+    fn length_counter_disables_channel()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF21, 0xF0);
+        apu.write_register(0xFF20, 0x3E);
+        apu.write_register(0xFF23, 0xC0);
+
+        apu.tick(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(0x08, status(&apu));
+
+        apu.tick(2 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(0x00, status(&apu));
+    }
+
+    #[test]
+    fn enabling_length_in_first_half_clocks_it_once_more()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF21, 0xF0);
+        apu.write_register(0xFF20, 0x3F);
+        apu.write_register(0xFF23, 0x80);
+        // The next step (1) does not clock the length counters
+        apu.tick(FRAME_SEQUENCER_PERIOD);
+
+        apu.write_register(0xFF23, 0x40);
+
+        assert_eq!(0x00, status(&apu));
+    }
+
+    #[test]
+    fn trigger_in_first_half_loads_63()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF21, 0xF0);
+        apu.tick(FRAME_SEQUENCER_PERIOD);
+
+        apu.write_register(0xFF23, 0xC0);
+
+        assert_eq!(63, apu.noise.length.counter);
+    }
+
+    #[test]
+    fn envelope_decreases_volume()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF17, 0xA1);
+        apu.write_register(0xFF19, 0x80);
+
+        apu.tick(8 * FRAME_SEQUENCER_PERIOD);
+
+        assert_eq!(0x09, apu.pulse2.envelope.volume);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_channel()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF10, 0x11);
+        apu.write_register(0xFF13, 0xFF);
+
+        apu.write_register(0xFF14, 0x87);
+
+        assert_eq!(0x00, status(&apu));
+    }
+
+    #[test]
+    fn sweep_changes_frequency()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF10, 0x11);
+        apu.write_register(0xFF13, 0x00);
+        apu.write_register(0xFF14, 0x81);
+
+        // The sweep is clocked in step 2
+        apu.tick(3 * FRAME_SEQUENCER_PERIOD);
+
+        assert_eq!(0x180, apu.pulse1.frequency);
+        assert_eq!(0x01, status(&apu));
+    }
+
+    #[test]
+    fn clearing_negate_after_calculation_disables_channel()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF10, 0x19);
+        apu.write_register(0xFF13, 0x00);
+        apu.write_register(0xFF14, 0x84);
+
+        apu.write_register(0xFF10, 0x11);
+
+        assert_eq!(0x00, status(&apu));
+    }
+
+    #[test]
+    fn power_off_clears_registers_and_keeps_wave_ram()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF30, 0x12);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x80);
+
+        apu.write_register(0xFF26, 0x00);
+        apu.write_register(0xFF17, 0xF0);
+
+        assert_eq!(0x70, apu.read_register(0xFF26));
+        assert_eq!(0x00, apu.read_register(0xFF17));
+        assert_eq!(0x00, apu.read_register(0xFF24));
+        assert_eq!(0x12, apu.read_register(0xFF30));
+    }
+
+    #[test]
+    fn length_is_writable_while_powered_off_on_dmg()
+    {
+        let mut apu = Apu { cgb_hardware: false, ..Apu::default() };
+        apu.write_register(0xFF26, 0x00);
+
+        apu.write_register(0xFF20, 0x3E);
+        apu.write_register(0xFF11, 0xFE);
+
+        assert_eq!(2, apu.noise.length.counter);
+        assert_eq!(2, apu.pulse1.length.counter);
+        assert_eq!(0x3F, apu.read_register(0xFF11));
+    }
+
+    #[test]
+    fn length_is_not_writable_while_powered_off_on_cgb()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF26, 0x00);
+
+        apu.write_register(0xFF20, 0x3E);
+
+        assert_eq!(0, apu.noise.length.counter);
+    }
+
+    #[test]
+    fn wave_ram_access_while_playing_on_cgb()
+    {
+        let mut apu = Apu::default();
+        apu.write_register(0xFF31, 0xAB);
+        apu.write_register(0xFF1D, 0xFF);
+        apu.write_register(0xFF1A, 0x80);
+        apu.write_register(0xFF1E, 0x87);
+
+        // Advance to sample 2, which is in the second byte
+        apu.tick(2 + 6);
+        assert_eq!(1, apu.wave.position);
+        apu.tick(2);
+
+        assert_eq!(0xAB, apu.read_register(0xFF3F));
+    }
+
+    #[test]
+    fn wave_ram_read_while_playing_on_dmg()
+    {
+        let mut apu = Apu { cgb_hardware: false, ..Apu::default() };
+        apu.write_register(0xFF31, 0xAB);
+        apu.write_register(0xFF1D, 0xFE);
+        apu.write_register(0xFF1A, 0x80);
+        apu.write_register(0xFF1E, 0x87);
+
+        apu.tick(4 + 6 + 4);
+        assert_eq!(0xAB, apu.read_register(0xFF30));
+
+        apu.tick(1);
+        assert_eq!(0xFF, apu.read_register(0xFF30));
+    }
+
+    #[test]
+    fn noise_lfsr_in_7_bit_mode()
+    {
+        let mut noise = Noise::new();
+        noise.write_polynomial(0x08);
+        noise.lfsr = 0x7FFF;
+
+        noise.clock_lfsr();
+
+        assert_eq!(0x3FBF, noise.lfsr);
+    }
+
+    #[test]
+    fn noise_lfsr_in_15_bit_mode()
+    {
+        let mut noise = Noise::new();
+        noise.lfsr = 0x0001;
+
+        noise.clock_lfsr();
+
+        assert_eq!(0x4000, noise.lfsr);
+    }
+
+    #[test]
+    fn samples_are_only_collected_when_enabled()
+    {
+        let mut apu = Apu::default();
+        apu.tick(400);
+        assert!(apu.take_samples().is_empty());
+
+        apu.output_enabled = true;
+        apu.tick(400);
+
+        assert_eq!(100, apu.take_samples().len());
+    }
+
+    #[test]
+    fn panning_routes_channels()
+    {
+        let mut apu = Apu { output_enabled: true, ..Apu::default() };
+        apu.write_register(0xFF25, 0x20);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x87);
+
+        apu.tick(64);
+
+        let samples = apu.take_samples();
+        assert!(samples.iter().all(|sample| sample[1] == 0.0));
+        assert!(samples.iter().any(|sample| sample[0] != 0.0));
+    }
+
+    #[test]
+    fn channels_are_collected_separately()
+    {
+        let mut apu = Apu { output_enabled: true, channel_output_enabled: true, ..Apu::default() };
+        apu.write_register(0xFF25, 0x22);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x87);
+
+        apu.tick(8);
+
+        let channels = apu.take_channel_samples();
+        assert_eq!(2, channels.len());
+        assert_eq!([0.0; 2], channels[0][0]);
+        assert_eq!([-0.25; 2], channels[0][1]);
+    }
+
+    #[test]
+    fn filtered_channels_add_up_to_the_mix()
+    {
+        let mut apu = Apu { output_enabled: true, channel_output_enabled: true, ..Apu::default() };
+        apu.write_register(0xFF25, 0xFF);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x87);
+        apu.write_register(0xFF12, 0xA0);
+        apu.write_register(0xFF14, 0x87);
+        apu.write_register(0xFF1A, 0x80);
+
+        apu.tick(200_000);
+
+        let samples = apu.take_samples();
+        let channels = apu.take_channel_samples();
+        for (sample, channels) in samples.iter().zip(&channels)
+        {
+            let sum: f32 = channels.iter().map(|channel| channel[0]).sum();
+            assert!((sum - sample[0]).abs() < 0.0001, "{} {}", sum, sample[0]);
+        }
+        // The wave channel has its DAC on but never got triggered, its DC offset is gone
+        assert!(channels.last().unwrap()[2][0].abs() < 0.001, "{}", channels.last().unwrap()[2][0]);
+    }
+
+    #[test]
+    fn stopped_frame_sequencer_in_state_is_corrupt()
+    {
+        let mut apu = Apu::default();
+        let mut state = StateWriter::default();
+        apu.save(&mut state);
+        let mut data = state.into_bytes();
+        // The frame timer comes before the sample timer and the two capacitors
+        let frame_timer = data.len() - 16;
+        data[frame_timer..frame_timer + 4].copy_from_slice(&0u32.to_le_bytes());
+
+        assert_eq!(Err(StateError::Corrupt), apu.load(&mut StateReader::new(&data)));
+    }
+}
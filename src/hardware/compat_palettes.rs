@@ -0,0 +1,317 @@
+/*
+
+When a DMG game runs on a CGB, the CGB boot ROM colorizes it. For games
+licensed by Nintendo the palette is looked up by the checksum of the 16
+title bytes (0x0134 - 0x0143). Some checksums are shared between games,
+those are told apart by the fourth letter of the title. Everything else
+gets the default palette (the one selected by Right + A).
+
+The player can also pick one of twelve palettes by holding a direction
+and optionally A or B while the boot logo is shown.
+
+A palette ID consists of:
+  * Bit 0-4: Index into PALETTE_COMBINATIONS
+  * Bit 5:   OBJ0 uses its own palette instead of the BG one
+  * Bit 6:   OBJ1 uses the palette of OBJ0
+  * Bit 7:   OBJ1 uses its own palette instead of the BG one
+
+All tables are taken from the CGB boot ROM.
+
+*/
+
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+// Checksums from this index onwards are shared by multiple games
+const FIRST_DUPLICATE_CHECKSUM: usize = 65;
+const DUPLICATE_CHECKSUMS: usize = TITLE_CHECKSUMS.len() - FIRST_DUPLICATE_CHECKSUM;
+
+// Rows of DUPLICATE_CHECKSUMS letters, one column per duplicate checksum
+const TITLE_FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// One entry per unique checksum, followed by one entry per fourth letter
+const PALETTE_IDS: [u8; 94] = [
+    0x7C, 0x08, 0x12, 0xA3, 0xA2, 0x07, 0x87, 0x4B, 0x20, 0x12, 0x65, 0xA8, 0x16, 0xA9, 0x86, 0xB1,
+    0x68, 0xA0, 0x87, 0x66, 0x12, 0xA1, 0x30, 0x3C, 0x12, 0x85, 0x12, 0x64, 0x1B, 0x07, 0x06, 0x6F,
+    0x6E, 0x6E, 0xAE, 0xAF, 0x6F, 0xB2, 0xAF, 0xB2, 0xA8, 0xAB, 0x6F, 0xAF, 0x86, 0xAE, 0xA2, 0xA2,
+    0x12, 0xAF, 0x13, 0x12, 0xA1, 0x6E, 0xAF, 0xAF, 0xAD, 0x06, 0x4C, 0x6E, 0xAF, 0xAF, 0x12, 0x7C,
+    0xAC, 0xA8, 0x6A, 0x6E, 0x13, 0xA0, 0x2D, 0xA8, 0x2B, 0xAC, 0x64, 0xAC, 0x6D, 0x87, 0xBC, 0x60,
+    0xB4, 0x13, 0x72, 0x7C, 0xB5, 0xAE, 0xAE, 0x7C, 0x7C, 0x65, 0xA2, 0x6C, 0x64, 0x85,
+];
+
+// OBJ0, OBJ1 and BG palette as byte offsets into PALETTE_COLORS
+const PALETTE_COMBINATIONS: [[u8; 3]; 29] = [
+    [0x80, 0xB0, 0x40],
+    [0x88, 0x20, 0x68],
+    [0xDE, 0x00, 0x70],
+    [0xDE, 0x20, 0x78],
+    [0x20, 0x20, 0x38],
+    [0x20, 0xB0, 0x90],
+    [0x20, 0xB0, 0xA0],
+    [0xE0, 0xB0, 0xC0],
+    [0x98, 0xB6, 0x48],
+    [0x80, 0xE0, 0x50],
+    [0x1E, 0x1E, 0x58],
+    [0x20, 0xB8, 0xE0],
+    [0x88, 0xB0, 0x10],
+    [0x20, 0x00, 0x10],
+    [0x20, 0xE0, 0x18],
+    [0xE0, 0x18, 0x00],
+    [0x18, 0xE0, 0x20],
+    [0xA8, 0xE0, 0x20],
+    [0x18, 0xE0, 0x00],
+    [0x20, 0x18, 0xD8],
+    [0xC8, 0x18, 0xE0],
+    [0x00, 0xE0, 0x40],
+    [0x28, 0x28, 0x28],
+    [0x18, 0xE0, 0x60],
+    [0x20, 0x18, 0xE0],
+    [0x00, 0x00, 0x08],
+    [0xE0, 0x18, 0x30],
+    [0xD0, 0xD0, 0xD0],
+    [0x20, 0xE0, 0xE8],
+];
+
+// RGB555 colours, four per palette
+const PALETTE_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Button combinations held during the boot logo and the palette they select
+const BUTTON_COMBINATIONS: [(&str, u8); 12] = [
+    ("up", 0x12),
+    ("up+a", 0xB0),
+    ("up+b", 0x79),
+    ("left", 0xB8),
+    ("left+a", 0xAD),
+    ("left+b", 0x16),
+    ("down", 0x17),
+    ("down+a", 0x07),
+    ("down+b", 0xBA),
+    ("right", 0x05),
+    ("right+a", 0x7C),
+    ("right+b", 0x13),
+];
+
+const DEFAULT_PALETTE_ID: u8 = PALETTE_IDS[0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompatPalette
+{
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+fn palette_at(byte_offset: u8) -> [u16; 4]
+{
+    let start = byte_offset as usize / 2;
+    let mut colors = [0; 4];
+    colors.copy_from_slice(&PALETTE_COLORS[start..start + 4]);
+    colors
+}
+
+fn palette_from_id(id: u8) -> CompatPalette
+{
+    let [obj0, obj1, bg] = PALETTE_COMBINATIONS[(id & 0x1F) as usize];
+    let bg = palette_at(bg);
+    let obj0 = if id & 0x20 > 0 { palette_at(obj0) } else { bg };
+    let obj1 = if id & 0x40 > 0
+    {
+        obj0
+    }
+    else if id & 0x80 > 0
+    {
+        palette_at(obj1)
+    }
+    else
+    {
+        bg
+    };
+    CompatPalette { bg, obj0, obj1 }
+}
+
+fn is_licensed_by_nintendo(rom: &[u8]) -> bool
+{
+    match rom[0x014B] {
+        0x01 => true,
+        // The new licensee code is used instead
+        0x33 => &rom[0x0144..0x0146] == b"01",
+        _ => false
+    }
+}
+
+fn palette_id_for_rom(rom: &[u8]) -> u8
+{
+    if !is_licensed_by_nintendo(rom)
+    {
+        return DEFAULT_PALETTE_ID;
+    }
+    let checksum = rom[0x0134..0x0144].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let index = match TITLE_CHECKSUMS.iter().position(|&entry| entry == checksum) {
+        Some(index) => index,
+        None => return DEFAULT_PALETTE_ID
+    };
+    if index < FIRST_DUPLICATE_CHECKSUM
+    {
+        return PALETTE_IDS[index];
+    }
+
+    let fourth_letter = rom[0x0137];
+    let letter_index = (index - FIRST_DUPLICATE_CHECKSUM..TITLE_FOURTH_LETTERS.len())
+        .step_by(DUPLICATE_CHECKSUMS)
+        .find(|&letter_index| TITLE_FOURTH_LETTERS[letter_index] == fourth_letter);
+    match letter_index {
+        Some(letter_index) => PALETTE_IDS[FIRST_DUPLICATE_CHECKSUM + letter_index],
+        None => DEFAULT_PALETTE_ID
+    }
+}
+
+/// Returns the palette the CGB boot ROM would pick for a DMG game
+pub fn palette_for_rom(rom: &[u8]) -> CompatPalette
+{
+    palette_from_id(palette_id_for_rom(rom))
+}
+
+/// Returns the palette for a button combination like "up+a"
+pub fn palette_for_buttons(buttons: &str) -> Option<CompatPalette>
+{
+    let buttons = buttons.to_lowercase();
+    BUTTON_COMBINATIONS.iter()
+        .find(|(name, _)| *name == buttons)
+        .map(|(_, id)| palette_from_id(*id))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn rom_with_title(title: &str, licensee: u8) -> Vec<u8>
+    {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x014B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn unlicensed_games_get_default_palette()
+    {
+        let rom = rom_with_title("TETRIS", 0x00);
+
+        assert_eq!(palette_for_buttons("right+a").unwrap(), palette_for_rom(&rom));
+    }
+
+    #[test]
+    fn unknown_titles_get_default_palette()
+    {
+        let rom = rom_with_title("RUSTYBOY", 0x01);
+
+        assert_eq!(palette_for_buttons("right+a").unwrap(), palette_for_rom(&rom));
+    }
+
+    #[test]
+    fn new_licensee_code_is_checked()
+    {
+        let mut rom = rom_with_title("TETRIS", 0x33);
+        rom[0x0144] = b'0';
+        rom[0x0145] = b'1';
+
+        assert_eq!(palette_for_buttons("down+a").unwrap(), palette_for_rom(&rom));
+    }
+
+    #[test]
+    fn tetris_is_found_by_checksum()
+    {
+        let rom = rom_with_title("TETRIS", 0x01);
+
+        let palette = palette_for_rom(&rom);
+
+        assert_eq!([0x7FFF, 0x03FF, 0x001F, 0x0000], palette.bg);
+        assert_eq!(palette.bg, palette.obj0);
+        assert_eq!(palette.bg, palette.obj1);
+    }
+
+    #[test]
+    fn shared_checksum_uses_fourth_letter()
+    {
+        // Both titles share the checksum 0x46
+        let mario = rom_with_title("SUPER MARIOLAND", 0x01);
+        let metroid = rom_with_title("METROID2", 0x01);
+
+        assert_eq!(0x6A, palette_id_for_rom(&mario));
+        assert_eq!(palette_from_id(PALETTE_IDS[80]), palette_for_rom(&metroid));
+    }
+
+    #[test]
+    fn shared_checksum_with_unknown_fourth_letter_gets_default()
+    {
+        let mut rom = rom_with_title("SUPER MARIOLAND", 0x01);
+        // Keep the checksum, change the fourth letter
+        rom[0x0137] = b'F';
+        rom[0x0138] = b'Q';
+
+        assert_eq!(DEFAULT_PALETTE_ID, palette_id_for_rom(&rom));
+    }
+
+    #[test]
+    fn up_a_uses_three_different_palettes()
+    {
+        let palette = palette_for_buttons("UP+A").unwrap();
+
+        assert_eq!([0x7FFF, 0x421F, 0x1CF2, 0x0000], palette.bg);
+        assert_eq!([0x7FFF, 0x1BEF, 0x0200, 0x0000], palette.obj0);
+        assert_eq!([0x7FFF, 0x7E8C, 0x7C00, 0x0000], palette.obj1);
+    }
+
+    #[test]
+    fn right_a_shares_obj0_palette()
+    {
+        let palette = palette_for_buttons("right+a").unwrap();
+
+        assert_eq!([0x7FFF, 0x1BEF, 0x6180, 0x0000], palette.bg);
+        assert_eq!([0x7FFF, 0x421F, 0x1CF2, 0x0000], palette.obj0);
+        assert_eq!(palette.obj0, palette.obj1);
+    }
+
+    #[test]
+    fn unknown_button_combination()
+    {
+        assert_eq!(None, palette_for_buttons("start"));
+    }
+}
@@ -23,14 +23,14 @@ fn increment_pc_by(hardware: &mut GameBoy, amount: u16)
 
 fn get_16_bit_value(hardware: &mut GameBoy, start_index: usize) -> u16
 {
-    let l = hardware.memory_map[start_index] as u16;
-    let h = (hardware.memory_map[start_index + 1] as u16) << 8;
+    let l = hardware.read_byte(start_index as u16) as u16;
+    let h = (hardware.read_byte((start_index + 1) as u16) as u16) << 8;
     h + l
 }
 
 fn get_8_bit_value(hardware: &mut GameBoy, start_index: usize) -> u8
 {
-    hardware.memory_map[start_index]
+    hardware.read_byte(start_index as u16)
 }
 
 fn jump_absolute_16_bit(hardware: &mut GameBoy)
@@ -45,6 +45,18 @@ fn disable_interrupts(hardware: &mut GameBoy)
     increment_pc(hardware);
 }
 
+fn stop(hardware: &mut GameBoy)
+{
+    // STOP always resets the divider
+    hardware.write_byte(0xFF04, 0);
+    if hardware.cgb_mode && hardware.speed.stop()
+    {
+        info!("Switched to {speed} speed", speed=if hardware.speed.is_double_speed() { "double" } else { "normal" });
+    }
+    // TODO implement low power mode until a button is pressed
+    increment_pc_by(hardware, 2);
+}
+
 fn load_to_sp(hardware: &mut GameBoy)
 {
     hardware.registers.sp = get_16_bit_value(hardware, (hardware.registers.pc + 1) as usize);
@@ -55,7 +67,7 @@ fn load_to_sp(hardware: &mut GameBoy)
 fn save_a_to_address(hardware: &mut GameBoy)
 {
     let location = get_16_bit_value(hardware, (hardware.registers.pc + 1) as usize);
-    hardware.memory_map[location as usize] = hardware.registers.a;
+    hardware.write_byte(location, hardware.registers.a);
     info!("Loading register A: {a:#X} into memory[{location:#X}]", a=hardware.registers.a, location=location);
     increment_pc_by(hardware, 3);
 }
@@ -71,7 +83,7 @@ fn load_8bit_intermediate_to_a(hardware: &mut GameBoy)
 fn save_a_to_ff00_plus_intermediate(hardware: &mut GameBoy)
 {
     let location = 0xFF00u16 + get_8_bit_value(hardware, (hardware.registers.pc + 1) as usize) as u16;
-    hardware.memory_map[location as usize] = hardware.registers.a;
+    hardware.write_byte(location, hardware.registers.a);
     info!("Saving register A: {a:#X} to memory[{location:#X}]", a=hardware.registers.a, location=location);
     increment_pc_by(hardware, 2);
 }
@@ -87,8 +99,8 @@ fn load_16bit_intermediate_to_hl(hardware: &mut GameBoy)
 fn call(hardware: &mut GameBoy)
 {
     let post_call_pc = hardware.registers.pc + 3;
-    hardware.memory_map[(hardware.registers.sp - 1) as usize] = (post_call_pc >> 8) as u8;
-    hardware.memory_map[(hardware.registers.sp - 2) as usize] = ((post_call_pc << 8) >> 8) as u8;
+    hardware.write_byte(hardware.registers.sp - 1, (post_call_pc >> 8) as u8);
+    hardware.write_byte(hardware.registers.sp - 2, ((post_call_pc << 8) >> 8) as u8);
     hardware.registers.pc = get_16_bit_value(hardware, (hardware.registers.pc + 1) as usize);
     hardware.registers.sp -= 2;
     info!("Calling {pc:#X}", pc=hardware.registers.pc);
@@ -96,8 +108,8 @@ fn call(hardware: &mut GameBoy)
 
 fn return_from_call(hardware: &mut GameBoy)
 {
-    let pc_lower = hardware.memory_map[hardware.registers.sp as usize];
-    let pc_higher = hardware.memory_map[(hardware.registers.sp + 1) as usize];
+    let pc_lower = hardware.read_byte(hardware.registers.sp);
+    let pc_higher = hardware.read_byte(hardware.registers.sp + 1);
     hardware.registers.pc = ((pc_higher as u16) << 8) + pc_lower as u16;
     hardware.registers.sp += 2;
     info!("Returning to {pc:#X}", pc=hardware.registers.pc);
@@ -122,14 +134,24 @@ fn jump_signed_immediate(hardware: &mut GameBoy)
     info!("Jumped by {jump_size} to {pc:#X}", jump_size=jump_size, pc=hardware.registers.pc);
 }
 
-pub fn step(hardware: &mut GameBoy)
+/// Executes a single instruction and returns the amount of cycles it took
+pub fn step(hardware: &mut GameBoy) -> u32
 {
-    let opcode = hardware.memory_map[hardware.registers.pc as usize];
+    let opcode = hardware.read_byte(hardware.registers.pc);
     info!("Parsed opcode: {opcode:#X}", opcode=opcode);
+    let cycles = match opcode {
+        0x00 | 0x10 | 0xF3 | 0x7D | 0x7C => 4,
+        0x3E => 8,
+        0x31 | 0xE0 | 0x21 | 0x18 => 12,
+        0xC3 | 0xEA | 0xC9 => 16,
+        0xCD => 24,
+        _ => 0
+    };
     match opcode {
         0x00 => increment_pc(hardware), // NOP
         0xC3 => jump_absolute_16_bit(hardware),
         0xF3 => disable_interrupts(hardware),
+        0x10 => stop(hardware),
         0x31 => load_to_sp(hardware),
         0xEA => save_a_to_address(hardware),
         0x3E => load_8bit_intermediate_to_a(hardware),
@@ -142,6 +164,7 @@ pub fn step(hardware: &mut GameBoy)
         0xC9 => return_from_call(hardware),
         x => error_unknown_opcode(x, &hardware.registers)
     };
+    hardware.tick(cycles)
 }
 
 #[cfg(test)]
@@ -242,16 +265,30 @@ mod tests
     }
 
     #[test]
-    fn save_a_to_ff00_plus_intermediate_5_to_ff04()
+    fn save_a_to_ff00_plus_intermediate_5_to_ff80()
     {
         let mut gameboy = GameBoy::default();
         gameboy.registers.pc = 0x1000;
+        gameboy.memory_map[0x1001] = 0x80;
+        gameboy.registers.a = 0x5;
+
+        save_a_to_ff00_plus_intermediate(&mut gameboy);
+
+        assert_eq!(0x5, gameboy.memory_map[0xff80]);
+    }
+
+    #[test]
+    fn save_a_to_ff00_plus_intermediate_to_ff04_resets_div()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.timer.tick(0x1234);
+        gameboy.registers.pc = 0x1000;
         gameboy.memory_map[0x1001] = 0x4;
         gameboy.registers.a = 0x5;
 
         save_a_to_ff00_plus_intermediate(&mut gameboy);
 
-        assert_eq!(0x5, gameboy.memory_map[0xff04]);
+        assert_eq!(0x0, gameboy.read_byte(0xff04));
     }
 
     #[test]
@@ -281,6 +318,20 @@ mod tests
         assert_eq!(0x5, gameboy.memory_map[0x1234]);
     }
 
+    #[test]
+    fn stop_switches_speed_when_armed()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.cgb_mode = true;
+        gameboy.registers.pc = 0x1000;
+        gameboy.speed.write_key1(0x01);
+
+        stop(&mut gameboy);
+
+        assert!(gameboy.speed.is_double_speed());
+        assert_eq!(0x1002, gameboy.registers.pc);
+    }
+
     #[test]
     fn increment_program_counter_by_5()
     {
@@ -17,11 +17,46 @@ FF00 - FF7F: I/O Registers
 FF80 - FFFE: High RAM
 FFFF - FFFF: Interruptes Enable Register
 
+Everything that is not owned by a subsystem (like VRAM and the LCD
+registers belonging to the PPU, or the banked work RAM) is kept in memory_map.
+
 */
 
+use super::interrupts;
+use super::ppu::{Ppu, Mode, FRAME_DOTS};
+use super::hdma::Hdma;
+use super::speed::SpeedSwitch;
+use super::wram::WorkRam;
+use super::compat_palettes;
+use super::timer::Timer;
+use super::joypad::{Joypad, Button};
+use super::apu::{Apu, StereoSample};
+use super::serial::{Serial, SerialDevice};
+use super::save_state::{Header, Snapshot, StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model
+{
+    Dmg,
+    Cgb,
+}
+
 pub struct GameBoy {
     pub registers: super::registers::Registers,
     pub memory_map: [u8; 0x10000],
+    pub ppu: Ppu,
+    pub hdma: Hdma,
+    pub speed: SpeedSwitch,
+    pub wram: WorkRam,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub serial: Serial,
+    pub model: Model,
+    pub cgb_mode: bool,
+    pub rom_checksum: u32,
+    // Dots that already belong to the next frame
+    frame_dots: u32,
 }
 
 impl Default for GameBoy {
@@ -30,18 +65,457 @@ impl Default for GameBoy {
         GameBoy {
             registers: super::registers::Registers::default(),
             memory_map: [0; 0x10000],
+            ppu: Ppu::default(),
+            hdma: Hdma::default(),
+            speed: SpeedSwitch::default(),
+            wram: WorkRam::default(),
+            timer: Timer::default(),
+            joypad: Joypad::default(),
+            apu: Apu::default(),
+            serial: Serial::default(),
+            model: Model::Cgb,
+            cgb_mode: false,
+            rom_checksum: 0,
+            frame_dots: 0,
         }
     }
 }
 
 impl GameBoy {
-    pub fn map_cartridge(&mut self, rom: &Vec<u8>)
+    /// Powers on the given model with the cartridge inserted
+    pub fn new(rom: &[u8], model: Model) -> GameBoy
+    {
+        let mut gameboy = GameBoy { model, ..GameBoy::default() };
+        gameboy.map_cartridge(rom);
+        gameboy
+    }
+
+    pub fn map_cartridge(&mut self, rom: &[u8])
     {
         // Map till 0x3FFF
-        for index in 0..0x3FFF+1
+        self.memory_map[..0x4000].copy_from_slice(&rom[..0x4000]);
+        self.rom_checksum = super::rom_loader::checksum(rom);
+        self.cgb_mode = self.model == Model::Cgb && super::rom_loader::is_cgb_rom(rom);
+        self.ppu.cgb_mode = self.cgb_mode;
+        self.wram.cgb_mode = self.cgb_mode;
+        self.serial.cgb_mode = self.cgb_mode;
+        self.apu.cgb_hardware = self.model == Model::Cgb;
+        if self.model == Model::Cgb && !self.cgb_mode
+        {
+            self.ppu.set_compat_palette(&compat_palettes::palette_for_rom(rom));
+        }
+    }
+
+    /// Overrides the palette chosen for a DMG game, like holding buttons during the CGB boot logo.
+    /// Only a CGB running a DMG game has these palettes, a DMG stays gray.
+    pub fn set_compat_palette(&mut self, palette: &compat_palettes::CompatPalette)
+    {
+        if self.model == Model::Cgb && !self.cgb_mode
+        {
+            self.ppu.set_compat_palette(palette);
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8
+    {
+        match address {
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xC000..=0xFDFF => self.wram.read(address),
+            0xFE00..=0xFE9F => self.ppu.read_oam(address),
+            0xFF00 => self.joypad.read(),
+            0xFF01..=0xFF02 => self.serial.read_register(address),
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            0xFF10..=0xFF3F => self.apu.read_register(address),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6B => self.ppu.read_register(address),
+            0xFF4D if self.cgb_mode => self.speed.read_key1(),
+            0xFF51..=0xFF55 if self.cgb_mode => self.hdma.read_register(address),
+            0xFF70 => self.wram.read_svbk(),
+            // CGB only registers
+            0xFF4D | 0xFF51..=0xFF55 => 0xFF,
+            _ => self.memory_map[address as usize]
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8)
+    {
+        match address {
+            0x8000..=0x9FFF => self.ppu.write_vram(address, value),
+            0xC000..=0xFDFF => self.wram.write(address, value),
+            0xFE00..=0xFE9F => self.ppu.write_oam(address, value),
+            0xFF00 => {
+                let interrupts = self.joypad.write(value);
+                self.request_interrupts(interrupts);
+            },
+            0xFF01..=0xFF02 => self.serial.write_register(address, value),
+            0xFF04..=0xFF07 => self.timer.write_register(address, value),
+            0xFF10..=0xFF3F => self.apu.write_register(address, value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6B => self.ppu.write_register(address, value),
+            0xFF4D if self.cgb_mode => self.speed.write_key1(value),
+            0xFF51..=0xFF54 if self.cgb_mode => self.hdma.write_register(address, value),
+            0xFF55 if self.cgb_mode => self.start_hdma(value),
+            0xFF70 => self.wram.write_svbk(value),
+            0xFF4D | 0xFF51..=0xFF55 => (),
+            _ => self.memory_map[address as usize] = value
+        }
+    }
+
+    pub fn request_interrupts(&mut self, flags: u8)
+    {
+        self.memory_map[interrupts::INTERRUPT_FLAG_ADDRESS] |= flags;
+    }
+
+    /// Plugs a device into the link port
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>)
+    {
+        self.serial.connect(device);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool)
+    {
+        let interrupts = self.joypad.set_button(button, pressed);
+        self.request_interrupts(interrupts);
+    }
+
+    /// The pressed buttons, with the masks of Button
+    pub fn pressed_buttons(&self) -> u8
+    {
+        self.joypad.pressed()
+    }
+
+    fn transfer_hdma_block(&mut self)
+    {
+        let (source, destination) = self.hdma.next_block();
+        for offset in 0..super::hdma::BLOCK_SIZE
         {
-            self.memory_map[index] = rom[index]
+            let value = self.read_byte(source.wrapping_add(offset));
+            self.ppu.dma_write_vram(destination + offset, value);
         }
     }
+
+    fn start_hdma(&mut self, value: u8)
+    {
+        if !self.hdma.start(value)
+        {
+            return;
+        }
+        while self.hdma.general_transfer_active()
+        {
+            self.transfer_hdma_block();
+        }
+        // A HBlank DMA started while already in HBlank (or with the LCD off)
+        // copies its first block right away
+        if self.hdma.hblank_transfer_active() && (!self.ppu.lcd_enabled() || self.ppu.mode() == Mode::HBlank)
+        {
+            self.transfer_hdma_block();
+        }
+    }
+
+    fn take_stall_cycles(&mut self) -> u32
+    {
+        // DMA stalls take the same time in both speed modes, so they count double in double speed
+        let dma_stall = self.speed.dots_to_cycles(self.hdma.take_stall_cycles());
+        dma_stall + self.speed.take_switch_delay()
+    }
+
+    /// Advances all subsystems by the given amount of CPU cycles.
+    /// Returns the amount of CPU cycles that actually passed, including
+    /// the time the CPU was halted by DMA transfers and speed switches.
+    pub fn tick(&mut self, cycles: u32) -> u32
+    {
+        let mut elapsed = 0;
+        let mut pending = cycles + self.take_stall_cycles();
+        while pending > 0
+        {
+            // The timer and serial port run at CPU speed, everything else at normal speed
+            let counter = self.timer.counter();
+            let mut interrupts = self.timer.tick(pending);
+            interrupts |= self.serial.tick(counter, pending);
+            let dots = self.speed.cycles_to_dots(pending);
+            interrupts |= self.ppu.tick(dots);
+            self.apu.tick(dots);
+            self.request_interrupts(interrupts);
+            elapsed += pending;
+
+            for _ in 0..self.ppu.take_hblanks_started()
+            {
+                if self.hdma.hblank_transfer_active()
+                {
+                    self.transfer_hdma_block();
+                }
+            }
+            pending = self.take_stall_cycles();
+        }
+        elapsed
+    }
+
+    /// Executes a single instruction, returns the CPU cycles it took
+    pub fn step(&mut self) -> u32
+    {
+        let cycles = super::cpu::step(self);
+        self.frame_dots += self.speed.cycles_to_dots(cycles);
+        cycles
+    }
+
+    /// Runs the CPU for one frame worth of dots (70224), which is one
+    /// frame of the PPU as long as the LCD is on.
+    pub fn run_frame(&mut self)
+    {
+        self.run_frame_until(|_| false);
+    }
+
+    /// Like run_frame, but checks the condition before every instruction.
+    /// Returns true if the condition stopped the frame early, the next call
+    /// then continues the same frame.
+    pub fn run_frame_until<F: FnMut(&GameBoy) -> bool>(&mut self, mut condition: F) -> bool
+    {
+        while self.frame_dots < FRAME_DOTS
+        {
+            if condition(self)
+            {
+                return true;
+            }
+            self.step();
+        }
+        self.frame_dots -= FRAME_DOTS;
+        false
+    }
+
+    /// The last picture of the LCD, 160x144 pixels of RGB
+    pub fn framebuffer(&self) -> &[u8]
+    {
+        &self.ppu.framebuffer
+    }
+
+    /// Starts or stops collecting sound samples for take_audio_samples
+    pub fn enable_audio(&mut self, enabled: bool)
+    {
+        self.apu.output_enabled = enabled;
+    }
+
+    /// Hands out the stereo samples produced since the last call, at apu::SAMPLE_RATE
+    pub fn take_audio_samples(&mut self) -> Vec<StereoSample>
+    {
+        self.apu.take_samples()
+    }
+
+    /// Snapshot of the whole machine, except for what is plugged into the link port
+    pub fn save_state(&self) -> Vec<u8>
+    {
+        let mut state = StateWriter::default();
+        Header::new(self.rom_checksum, self.model as u8).write(&mut state);
+        self.save_sections(&mut state);
+        state.into_bytes()
+    }
+
+    /// The sections of the save state without the header, which changes with the emulator version
+    pub(crate) fn save_sections(&self, state: &mut StateWriter)
+    {
+        state.section(b"GB  ", |state| {
+            state.bool(self.cgb_mode);
+            state.u32(self.frame_dots);
+            state.bytes(&self.memory_map);
+        });
+        state.section(b"CPU ", |state| self.registers.save(state));
+        state.section(b"PPU ", |state| self.ppu.save(state));
+        state.section(b"HDMA", |state| self.hdma.save(state));
+        state.section(b"SPD ", |state| self.speed.save(state));
+        state.section(b"WRAM", |state| self.wram.save(state));
+        state.section(b"TIMR", |state| self.timer.save(state));
+        state.section(b"JOYP", |state| self.joypad.save(state));
+        state.section(b"APU ", |state| self.apu.save(state));
+        state.section(b"SER ", |state| self.serial.save(state));
+    }
+
+    /// Restores a snapshot taken by save_state with the same ROM and model.
+    /// Nothing changes if the state can not be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError>
+    {
+        let mut state = StateReader::new(data);
+        let header = Header::read(&mut state)?;
+        if header.model != self.model as u8
+        {
+            return Err(StateError::WrongModel);
+        }
+        if header.rom_checksum != self.rom_checksum
+        {
+            return Err(StateError::WrongRom);
+        }
+        let backup = self.save_state();
+        if let Err(error) = self.load_sections(state)
+        {
+            let mut backup = StateReader::new(&backup);
+            Header::read(&mut backup).unwrap();
+            self.load_sections(backup).unwrap();
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn load_sections(&mut self, mut state: StateReader) -> Result<(), StateError>
+    {
+        while !state.is_empty()
+        {
+            let (tag, mut content) = state.section()?;
+            match &tag {
+                b"GB  " => {
+                    self.cgb_mode = content.bool()?;
+                    self.frame_dots = content.u32()?;
+                    content.bytes(&mut self.memory_map)?;
+                },
+                b"CPU " => self.registers.load(&mut content)?,
+                b"PPU " => self.ppu.load(&mut content)?,
+                b"HDMA" => self.hdma.load(&mut content)?,
+                b"SPD " => self.speed.load(&mut content)?,
+                b"WRAM" => self.wram.load(&mut content)?,
+                b"TIMR" => self.timer.load(&mut content)?,
+                b"JOYP" => self.joypad.load(&mut content)?,
+                b"APU " => self.apu.load(&mut content)?,
+                b"SER " => self.serial.load(&mut content)?,
+                // Written by a newer version
+                _ => ()
+            }
+        }
+        Ok(())
+    }
 }
 
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn new_maps_the_cartridge()
+    {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x3C;
+        rom[0x143] = 0x80;
+
+        let gameboy = GameBoy::new(&rom, Model::Cgb);
+
+        assert_eq!(0x3C, gameboy.read_byte(0x100));
+        assert!(gameboy.cgb_mode);
+    }
+
+    #[test]
+    fn run_frame_executes_one_frame_of_nops()
+    {
+        let mut gameboy = GameBoy::default();
+
+        gameboy.run_frame();
+
+        // 70224 dots of 4 cycle NOPs
+        assert_eq!(0x100 + 17556, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn steps_count_towards_the_frame()
+    {
+        let mut gameboy = GameBoy::default();
+
+        assert_eq!(4, gameboy.step());
+        gameboy.run_frame();
+
+        assert_eq!(0x100 + 17556, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn stopped_frame_continues_where_it_stopped()
+    {
+        let mut gameboy = GameBoy::default();
+
+        assert!(gameboy.run_frame_until(|gameboy| gameboy.registers.pc == 0x110));
+        assert_eq!(0x110, gameboy.registers.pc);
+        assert!(!gameboy.run_frame_until(|_| false));
+
+        assert_eq!(0x100 + 17556, gameboy.registers.pc);
+    }
+
+    fn test_rom() -> Vec<u8>
+    {
+        let mut rom = vec![0; 0x8000];
+        rom[0x7000] = 0x01;
+        rom
+    }
+
+    #[test]
+    fn state_round_trip()
+    {
+        let mut gameboy = GameBoy::new(&test_rom(), Model::Cgb);
+        gameboy.write_byte(0xC123, 0x42);
+        gameboy.write_byte(0xFF12, 0xF3);
+        gameboy.run_frame_until(|gameboy| gameboy.registers.pc == 0x150);
+        let state = gameboy.save_state();
+
+        gameboy.run_frame();
+        gameboy.write_byte(0xC123, 0x00);
+        gameboy.load_state(&state).unwrap();
+
+        assert_eq!(0x150, gameboy.registers.pc);
+        assert_eq!(0x42, gameboy.read_byte(0xC123));
+        assert_eq!(state, gameboy.save_state());
+    }
+
+    #[test]
+    fn only_cgb_colorizes_dmg_games()
+    {
+        let palette = compat_palettes::palette_for_buttons("up+a").unwrap();
+        let mut cgb = GameBoy::new(&test_rom(), Model::Cgb);
+        let mut dmg = GameBoy::new(&test_rom(), Model::Dmg);
+        let gray = dmg.save_state();
+
+        cgb.set_compat_palette(&palette);
+        dmg.set_compat_palette(&palette);
+
+        assert_ne!(GameBoy::new(&test_rom(), Model::Cgb).save_state(), cgb.save_state());
+        assert_eq!(gray, dmg.save_state());
+    }
+
+    #[test]
+    fn state_of_other_rom_or_model_is_rejected()
+    {
+        let state = GameBoy::new(&test_rom(), Model::Cgb).save_state();
+        let mut other_rom = GameBoy::new(&vec![0; 0x8000], Model::Cgb);
+        let mut other_model = GameBoy::new(&test_rom(), Model::Dmg);
+
+        assert_eq!(Err(StateError::WrongRom), other_rom.load_state(&state));
+        assert_eq!(Err(StateError::WrongModel), other_model.load_state(&state));
+    }
+
+    #[test]
+    fn broken_state_changes_nothing()
+    {
+        let mut gameboy = GameBoy::new(&test_rom(), Model::Cgb);
+        let state = gameboy.save_state();
+        gameboy.run_frame_until(|gameboy| gameboy.registers.pc == 0x150);
+
+        assert_eq!(Err(StateError::Truncated), gameboy.load_state(&state[..state.len() - 1]));
+
+        assert_eq!(0x150, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped()
+    {
+        let mut gameboy = GameBoy::new(&test_rom(), Model::Cgb);
+        let mut state = gameboy.save_state();
+        state.extend_from_slice(b"NEW ");
+        state.extend_from_slice(&2u32.to_le_bytes());
+        state.extend_from_slice(&[1, 2]);
+
+        assert_eq!(Ok(()), gameboy.load_state(&state));
+    }
+
+    #[test]
+    fn audio_samples_only_while_enabled()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.step();
+        assert!(gameboy.take_audio_samples().is_empty());
+
+        gameboy.enable_audio(true);
+        gameboy.step();
+
+        assert_eq!(1, gameboy.take_audio_samples().len());
+    }
+}
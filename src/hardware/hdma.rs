@@ -0,0 +1,262 @@
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+CGB VRAM DMA, controlled by the registers 0xFF51 - 0xFF55:
+  * HDMA1/HDMA2: Source address, lower 4 bits are ignored
+  * HDMA3/HDMA4: Destination address in VRAM, only bits 4-12 are used
+  * HDMA5:       Bit 7 selects the mode, bits 0-6 are the length / 0x10 - 1
+
+General purpose DMA (bit 7 = 0) copies everything at once and halts the CPU
+until it is done. HBlank DMA (bit 7 = 1) copies 0x10 bytes at the start of
+every HBlank. Writing bit 7 = 0 while a HBlank DMA is running cancels it.
+The copied bytes always reach VRAM, even while the PPU blocks the CPU.
+
+*/
+
+pub const BLOCK_SIZE: u16 = 0x10;
+// The CPU is halted for 8 M-cycles for every block that gets copied
+pub const BLOCK_STALL_CYCLES: u32 = 32;
+
+pub struct Hdma
+{
+    source: u16,
+    destination: u16,
+    remaining_blocks: u8,
+    hblank_mode: bool,
+    stall_cycles: u32,
+}
+
+impl Default for Hdma {
+    fn default() -> Hdma
+    {
+        Hdma {
+            source: 0,
+            destination: 0x8000,
+            remaining_blocks: 0,
+            hblank_mode: false,
+            stall_cycles: 0,
+        }
+    }
+}
+
+impl Hdma
+{
+    pub fn read_register(&self, address: u16) -> u8
+    {
+        match address {
+            // Bit 7 is 0 while a HBlank DMA is active, 1 once it is finished or cancelled
+            0xFF55 => {
+                let active = if self.hblank_transfer_active() { 0x00 } else { 0x80 };
+                active | (self.remaining_blocks.wrapping_sub(1) & 0x7F)
+            },
+            // HDMA1 - HDMA4 are write only
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8)
+    {
+        match address {
+            0xFF51 => self.source = ((value as u16) << 8) | (self.source & 0x00F0),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value as u16 & 0xF0),
+            0xFF53 => self.destination = 0x8000 | (((value as u16) & 0x1F) << 8) | (self.destination & 0x00F0),
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value as u16 & 0xF0),
+            _ => ()
+        }
+    }
+
+    /// Handles a write to HDMA5. Returns false if the write cancelled a running HBlank DMA.
+    pub fn start(&mut self, value: u8) -> bool
+    {
+        if self.hblank_transfer_active() && value & 0x80 == 0
+        {
+            self.hblank_mode = false;
+            return false;
+        }
+        self.remaining_blocks = (value & 0x7F) + 1;
+        self.hblank_mode = value & 0x80 > 0;
+        true
+    }
+
+    pub fn hblank_transfer_active(&self) -> bool
+    {
+        self.hblank_mode && self.remaining_blocks > 0
+    }
+
+    pub fn general_transfer_active(&self) -> bool
+    {
+        !self.hblank_mode && self.remaining_blocks > 0
+    }
+
+    /// Returns the source and destination of the next block and advances the transfer
+    pub fn next_block(&mut self) -> (u16, u16)
+    {
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.destination += BLOCK_SIZE;
+        self.remaining_blocks -= 1;
+        self.stall_cycles += BLOCK_STALL_CYCLES;
+        // The destination does not wrap around, the transfer just ends
+        if self.destination > 0x9FF0
+        {
+            self.destination = 0x8000;
+            self.remaining_blocks = 0;
+        }
+        block
+    }
+
+    pub fn take_stall_cycles(&mut self) -> u32
+    {
+        let cycles = self.stall_cycles;
+        self.stall_cycles = 0;
+        cycles
+    }
+}
+
+
+impl Snapshot for Hdma
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.remaining_blocks);
+        state.bool(self.hblank_mode);
+        state.u32(self.stall_cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.source = state.u16()? & 0xFFF0;
+        self.destination = 0x8000 | (state.u16()? & 0x1FF0);
+        self.remaining_blocks = state.u8_in(0..=0x80)?;
+        self.hblank_mode = state.bool()?;
+        self.stall_cycles = state.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use super::super::gameboy::GameBoy;
+
+    fn setup_transfer(gameboy: &mut GameBoy)
+    {
+        gameboy.cgb_mode = true;
+        gameboy.ppu.cgb_mode = true;
+        for index in 0..0x40u16
+        {
+            gameboy.write_byte(0xC100 + index, index as u8 + 1);
+        }
+        gameboy.write_byte(0xFF51, 0xC1);
+        gameboy.write_byte(0xFF52, 0x0F);
+        gameboy.write_byte(0xFF53, 0xE8);
+        gameboy.write_byte(0xFF54, 0x2A);
+    }
+
+    #[test]
+    fn source_and_destination_are_masked()
+    {
+        let mut hdma = Hdma::default();
+
+        hdma.write_register(0xFF51, 0xC1);
+        hdma.write_register(0xFF52, 0x2F);
+        hdma.write_register(0xFF53, 0xE8);
+        hdma.write_register(0xFF54, 0x2A);
+        hdma.start(0x00);
+
+        assert_eq!((0xC120, 0x8820), hdma.next_block());
+    }
+
+    #[test]
+    fn general_transfer_copies_everything_at_once()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.ppu.write_register(0xFF40, 0x00);
+        setup_transfer(&mut gameboy);
+
+        gameboy.write_byte(0xFF55, 0x03);
+
+        assert_eq!(0x01, gameboy.read_byte(0x8820));
+        assert_eq!(0x40, gameboy.read_byte(0x885F));
+        assert_eq!(0xFF, gameboy.read_byte(0xFF55));
+        assert_eq!(4 * BLOCK_STALL_CYCLES, gameboy.hdma.take_stall_cycles());
+    }
+
+    #[test]
+    fn hblank_transfer_copies_one_block_per_hblank()
+    {
+        let mut gameboy = GameBoy::default();
+        setup_transfer(&mut gameboy);
+
+        gameboy.write_byte(0xFF55, 0x81);
+        assert_eq!(0x01, gameboy.read_byte(0xFF55));
+
+        // Run into the first HBlank
+        gameboy.tick(80 + 172);
+        assert_eq!(0x00, gameboy.read_byte(0xFF55));
+        assert_eq!(0x10, gameboy.ppu.read_vram(0x882F));
+        assert_eq!(0x00, gameboy.ppu.read_vram(0x8830));
+
+        gameboy.tick(456);
+        assert_eq!(0xFF, gameboy.read_byte(0xFF55));
+        assert_eq!(0x20, gameboy.ppu.read_vram(0x883F));
+    }
+
+    #[test]
+    fn general_transfer_writes_during_pixel_transfer()
+    {
+        let mut gameboy = GameBoy::default();
+        setup_transfer(&mut gameboy);
+        gameboy.tick(80);
+
+        gameboy.write_byte(0xFF55, 0x00);
+        gameboy.tick(172);
+
+        assert_eq!(0x01, gameboy.ppu.read_vram(0x8820));
+        assert_eq!(0x10, gameboy.ppu.read_vram(0x882F));
+    }
+
+    #[test]
+    fn long_ticks_copy_a_block_for_every_hblank()
+    {
+        let mut gameboy = GameBoy::default();
+        setup_transfer(&mut gameboy);
+
+        gameboy.write_byte(0xFF55, 0x83);
+        gameboy.tick(80 + 172 + 456 * 2);
+
+        assert_eq!(0x00, gameboy.read_byte(0xFF55));
+        assert_eq!(0x30, gameboy.ppu.read_vram(0x884F));
+        assert_eq!(0x00, gameboy.ppu.read_vram(0x8850));
+    }
+
+    #[test]
+    fn hblank_transfer_can_be_cancelled()
+    {
+        let mut gameboy = GameBoy::default();
+        setup_transfer(&mut gameboy);
+
+        gameboy.write_byte(0xFF55, 0x83);
+        gameboy.tick(80 + 172);
+        gameboy.write_byte(0xFF55, 0x00);
+
+        assert_eq!(0x82, gameboy.read_byte(0xFF55));
+        gameboy.tick(456);
+        assert_eq!(0x00, gameboy.ppu.read_vram(0x8830));
+    }
+
+    #[test]
+    fn tick_includes_stall_cycles()
+    {
+        let mut gameboy = GameBoy::default();
+        setup_transfer(&mut gameboy);
+
+        gameboy.write_byte(0xFF55, 0x80);
+
+        assert_eq!(80 + 172 + BLOCK_STALL_CYCLES, gameboy.tick(80 + 172));
+    }
+}
@@ -0,0 +1,15 @@
+/*
+
+Interrupt flags as they appear in IF (0xFF0F) and IE (0xFFFF).
+Subsystems return these from their tick functions and the GameBoy
+ORs them into IF.
+
+*/
+
+pub const INTERRUPT_FLAG_ADDRESS: usize = 0xFF0F;
+
+pub const VBLANK: u8 = 0x01;
+pub const LCD_STAT: u8 = 0x02;
+pub const TIMER: u8 = 0x04;
+pub const SERIAL: u8 = 0x08;
+pub const JOYPAD: u8 = 0x10;
@@ -0,0 +1,244 @@
+use super::interrupts;
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+P1/JOYP (0xFF00). All bits are active low:
+  * Bit 5:   Select action buttons
+  * Bit 4:   Select direction buttons
+  * Bit 3:   Down  / Start
+  * Bit 2:   Up    / Select
+  * Bit 1:   Left  / B
+  * Bit 0:   Right / A
+
+The joypad interrupt is requested whenever one of the lower four bits
+goes from high to low, either because a button got pressed or because
+a line with a pressed button got selected.
+
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button
+{
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button
+{
+    pub const ALL: [Button; 8] = [Button::Right, Button::Left, Button::Up, Button::Down,
+                                  Button::A, Button::B, Button::Select, Button::Start];
+
+    // Directions use the lower nibble, actions the upper nibble
+    pub fn mask(self) -> u8
+    {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button>
+    {
+        match name.to_lowercase().as_str() {
+            "right" => Some(Button::Right),
+            "left" => Some(Button::Left),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "select" => Some(Button::Select),
+            "start" => Some(Button::Start),
+            _ => None
+        }
+    }
+}
+
+pub struct Joypad
+{
+    select: u8,
+    pressed: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad
+    {
+        Joypad {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+}
+
+impl Joypad
+{
+    fn input_lines(&self) -> u8
+    {
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0
+        {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if self.select & 0x20 == 0
+        {
+            lines &= !(self.pressed >> 4);
+        }
+        lines
+    }
+
+    fn interrupt_on_falling_edge(&self, old_lines: u8) -> u8
+    {
+        if old_lines & !self.input_lines() > 0 { interrupts::JOYPAD } else { 0 }
+    }
+
+    pub fn read(&self) -> u8
+    {
+        0xC0 | self.select | self.input_lines()
+    }
+
+    /// Selects the button lines. Returns the interrupts that were raised.
+    pub fn write(&mut self, value: u8) -> u8
+    {
+        let old_lines = self.input_lines();
+        self.select = value & 0x30;
+        self.interrupt_on_falling_edge(old_lines)
+    }
+
+    /// The pressed buttons, with the masks of Button
+    pub fn pressed(&self) -> u8
+    {
+        self.pressed
+    }
+
+    /// Updates the state of a button. Returns the interrupts that were raised.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> u8
+    {
+        let old_lines = self.input_lines();
+        if pressed
+        {
+            self.pressed |= button.mask();
+        }
+        else
+        {
+            self.pressed &= !button.mask();
+        }
+        self.interrupt_on_falling_edge(old_lines)
+    }
+}
+
+
+impl Snapshot for Joypad
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.u8(self.select);
+        state.u8(self.pressed);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.select = state.u8()?;
+        self.pressed = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn nothing_selected_reads_all_high()
+    {
+        let mut joypad = Joypad::default();
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Down, true);
+
+        assert_eq!(0xFF, joypad.read());
+    }
+
+    #[test]
+    fn directions_selected()
+    {
+        let mut joypad = Joypad::default();
+        joypad.write(0x20);
+
+        joypad.set_button(Button::Left, true);
+        joypad.set_button(Button::Start, true);
+
+        assert_eq!(0xED, joypad.read());
+    }
+
+    #[test]
+    fn actions_selected()
+    {
+        let mut joypad = Joypad::default();
+        joypad.write(0x10);
+
+        joypad.set_button(Button::Left, true);
+        joypad.set_button(Button::Start, true);
+
+        assert_eq!(0xD7, joypad.read());
+    }
+
+    #[test]
+    fn released_button_reads_high_again()
+    {
+        let mut joypad = Joypad::default();
+        joypad.write(0x10);
+        joypad.set_button(Button::A, true);
+
+        joypad.set_button(Button::A, false);
+
+        assert_eq!(0xDF, joypad.read());
+    }
+
+    #[test]
+    fn pressing_selected_button_requests_interrupt()
+    {
+        let mut joypad = Joypad::default();
+        joypad.write(0x10);
+
+        assert_eq!(interrupts::JOYPAD, joypad.set_button(Button::B, true));
+        assert_eq!(0, joypad.set_button(Button::B, false));
+    }
+
+    #[test]
+    fn pressing_unselected_button_does_not_request_interrupt()
+    {
+        let mut joypad = Joypad::default();
+        joypad.write(0x20);
+
+        assert_eq!(0, joypad.set_button(Button::B, true));
+    }
+
+    #[test]
+    fn selecting_line_with_pressed_button_requests_interrupt()
+    {
+        let mut joypad = Joypad::default();
+        joypad.set_button(Button::Up, true);
+
+        assert_eq!(interrupts::JOYPAD, joypad.write(0x20));
+        assert_eq!(0, joypad.write(0x20));
+    }
+
+    #[test]
+    fn button_names()
+    {
+        assert_eq!(Some(Button::Select), Button::from_name("Select"));
+        assert_eq!(None, Button::from_name("x"));
+    }
+}
@@ -1,4 +1,15 @@
 pub mod registers;
 pub mod rom_loader;
 pub mod cpu;
-pub mod gameboy;
\ No newline at end of file
+pub mod gameboy;
+pub mod interrupts;
+pub mod ppu;
+pub mod hdma;
+pub mod speed;
+pub mod wram;
+pub mod compat_palettes;
+pub mod timer;
+pub mod joypad;
+pub mod apu;
+pub mod serial;
+pub mod save_state;
@@ -0,0 +1,866 @@
+use super::interrupts;
+use super::compat_palettes::CompatPalette;
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+The PPU renders one scanline at a time. Every line takes 456 cycles:
+  * Mode 2 (OAM scan):        80 cycles
+  * Mode 3 (pixel transfer): 172 cycles
+  * Mode 0 (HBlank):         204 cycles
+Lines 144 - 153 are VBlank (mode 1).
+
+On the Game Boy Color VRAM has two banks. Bank 0 holds tile data and the
+tile maps, bank 1 holds more tile data and the BG map attributes:
+  * Bit 0-2: Background palette number
+  * Bit 3:   Tile VRAM bank number
+  * Bit 5:   Horizontal flip
+  * Bit 6:   Vertical flip
+  * Bit 7:   BG-to-OAM priority
+
+*/
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const OAM_SCAN_CYCLES: u32 = 80;
+const PIXEL_TRANSFER_CYCLES: u32 = 172;
+const HBLANK_CYCLES: u32 = 204;
+const SCANLINE_CYCLES: u32 = 456;
+const VBLANK_START_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+pub const FRAME_DOTS: u32 = SCANLINE_CYCLES * LINES_PER_FRAME as u32;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+const DMG_SHADES: [[u8; 3]; 4] = [
+    [0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x00, 0x00, 0x00],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode
+{
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    PixelTransfer = 3,
+}
+
+pub struct Ppu
+{
+    pub cgb_mode: bool,
+    vram: [[u8; 0x2000]; 2],
+    vram_bank: usize,
+    oam: [u8; 0xA0],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    mode_clock: u32,
+    window_line: u8,
+    // HBlank periods that started since the last take, more than one when ticked far
+    hblanks_started: u8,
+    bg_palette_index: u8,
+    bg_palette_ram: [u8; 0x40],
+    obj_palette_index: u8,
+    obj_palette_ram: [u8; 0x40],
+    dmg_bg_colors: [[u8; 3]; 4],
+    dmg_obj0_colors: [[u8; 3]; 4],
+    dmg_obj1_colors: [[u8; 3]; 4],
+    pub framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu
+    {
+        Ppu {
+            cgb_mode: false,
+            vram: [[0; 0x2000]; 2],
+            vram_bank: 0,
+            oam: [0; 0xA0],
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            mode_clock: 0,
+            window_line: 0,
+            hblanks_started: 0,
+            bg_palette_index: 0,
+            bg_palette_ram: [0xFF; 0x40],
+            obj_palette_index: 0,
+            obj_palette_ram: [0xFF; 0x40],
+            dmg_bg_colors: DMG_SHADES,
+            dmg_obj0_colors: DMG_SHADES,
+            dmg_obj1_colors: DMG_SHADES,
+            framebuffer: [0xFF; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+        }
+    }
+}
+
+fn rgb555_to_rgb888(value: u16) -> [u8; 3]
+{
+    let scale = |component: u16| -> u8 {
+        let component = (component & 0x1F) as u8;
+        (component << 3) | (component >> 2)
+    };
+    [scale(value), scale(value >> 5), scale(value >> 10)]
+}
+
+fn cgb_color(palette_ram: &[u8; 0x40], palette: u8, color: u8) -> [u8; 3]
+{
+    let index = (palette as usize) * 8 + (color as usize) * 2;
+    let value = ((palette_ram[index + 1] as u16) << 8) | palette_ram[index] as u16;
+    rgb555_to_rgb888(value)
+}
+
+fn dmg_shade(colors: &[[u8; 3]; 4], palette: u8, color: u8) -> [u8; 3]
+{
+    colors[((palette >> (color * 2)) & 0x03) as usize]
+}
+
+fn write_palette_data(palette_ram: &mut [u8; 0x40], index: &mut u8, value: u8, accessible: bool)
+{
+    if accessible
+    {
+        palette_ram[(*index & 0x3F) as usize] = value;
+    }
+    // Auto increment happens even if the write itself was blocked
+    if *index & 0x80 > 0
+    {
+        *index = 0x80 | ((*index + 1) & 0x3F);
+    }
+}
+
+impl Ppu
+{
+    fn vram_accessible(&self) -> bool
+    {
+        !self.lcd_enabled() || self.mode != Mode::PixelTransfer
+    }
+
+    fn oam_accessible(&self) -> bool
+    {
+        !self.lcd_enabled() || (self.mode != Mode::PixelTransfer && self.mode != Mode::OamScan)
+    }
+
+    /// Colorizes DMG games like the CGB boot ROM does
+    pub fn set_compat_palette(&mut self, palette: &CompatPalette)
+    {
+        for color in 0..4
+        {
+            self.dmg_bg_colors[color] = rgb555_to_rgb888(palette.bg[color]);
+            self.dmg_obj0_colors[color] = rgb555_to_rgb888(palette.obj0[color]);
+            self.dmg_obj1_colors[color] = rgb555_to_rgb888(palette.obj1[color]);
+        }
+    }
+
+    pub fn mode(&self) -> Mode
+    {
+        self.mode
+    }
+
+    pub fn lcd_enabled(&self) -> bool
+    {
+        self.lcdc & 0x80 > 0
+    }
+
+    /// Returns how many HBlank periods started since the last call
+    pub fn take_hblanks_started(&mut self) -> u8
+    {
+        let started = self.hblanks_started;
+        self.hblanks_started = 0;
+        started
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8
+    {
+        if !self.vram_accessible()
+        {
+            return 0xFF;
+        }
+        self.vram[self.vram_bank][(address - 0x8000) as usize]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8)
+    {
+        if self.vram_accessible()
+        {
+            self.vram[self.vram_bank][(address - 0x8000) as usize] = value;
+        }
+    }
+
+    /// Writes to VRAM for the VRAM DMA, which is not blocked during pixel transfer
+    pub fn dma_write_vram(&mut self, address: u16, value: u8)
+    {
+        self.vram[self.vram_bank][(address - 0x8000) as usize] = value;
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8
+    {
+        if !self.oam_accessible()
+        {
+            return 0xFF;
+        }
+        self.oam[(address - 0xFE00) as usize]
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8)
+    {
+        if self.oam_accessible()
+        {
+            self.oam[(address - 0xFE00) as usize] = value;
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8
+    {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = if self.ly == self.lyc { 0x04 } else { 0x00 };
+                let mode = if self.lcd_enabled() { self.mode as u8 } else { 0 };
+                0x80 | (self.stat & 0x78) | coincidence | mode
+            },
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb_mode => 0xFE | self.vram_bank as u8,
+            0xFF68 if self.cgb_mode => self.bg_palette_index | 0x40,
+            0xFF69 if self.cgb_mode && self.vram_accessible() => self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize],
+            0xFF6A if self.cgb_mode => self.obj_palette_index | 0x40,
+            0xFF6B if self.cgb_mode && self.vram_accessible() => self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize],
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8)
+    {
+        match address {
+            0xFF40 => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = value;
+                if was_enabled && !self.lcd_enabled()
+                {
+                    self.ly = 0;
+                    self.mode_clock = 0;
+                    self.window_line = 0;
+                    self.mode = Mode::HBlank;
+                }
+                else if !was_enabled && self.lcd_enabled()
+                {
+                    self.mode = Mode::OamScan;
+                }
+            },
+            0xFF41 => self.stat = value & 0x78,
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF44 => (), // LY is read only
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F if self.cgb_mode => self.vram_bank = (value & 0x01) as usize,
+            0xFF68 if self.cgb_mode => self.bg_palette_index = value & 0xBF,
+            0xFF69 if self.cgb_mode => {
+                let accessible = self.vram_accessible();
+                write_palette_data(&mut self.bg_palette_ram, &mut self.bg_palette_index, value, accessible);
+            },
+            0xFF6A if self.cgb_mode => self.obj_palette_index = value & 0xBF,
+            0xFF6B if self.cgb_mode => {
+                let accessible = self.vram_accessible();
+                write_palette_data(&mut self.obj_palette_ram, &mut self.obj_palette_index, value, accessible);
+            },
+            _ => ()
+        }
+    }
+
+    fn stat_interrupt_for(&self, mode: Mode) -> u8
+    {
+        let enabled = match mode {
+            Mode::HBlank => self.stat & 0x08 > 0,
+            Mode::VBlank => self.stat & 0x10 > 0,
+            Mode::OamScan => self.stat & 0x20 > 0,
+            Mode::PixelTransfer => false,
+        };
+        if enabled { interrupts::LCD_STAT } else { 0 }
+    }
+
+    fn set_mode(&mut self, mode: Mode) -> u8
+    {
+        self.mode = mode;
+        self.stat_interrupt_for(mode)
+    }
+
+    fn next_line(&mut self) -> u8
+    {
+        self.ly = (self.ly + 1) % LINES_PER_FRAME;
+        if self.ly == self.lyc && self.stat & 0x40 > 0
+        {
+            return interrupts::LCD_STAT;
+        }
+        0
+    }
+
+    /// Advances the PPU by the given amount of cycles.
+    /// Returns the interrupts that were raised in the meantime.
+    pub fn tick(&mut self, cycles: u32) -> u8
+    {
+        if !self.lcd_enabled()
+        {
+            return 0;
+        }
+        let mut interrupts = 0;
+        self.mode_clock += cycles;
+        loop {
+            match self.mode {
+                Mode::OamScan if self.mode_clock >= OAM_SCAN_CYCLES => {
+                    self.mode_clock -= OAM_SCAN_CYCLES;
+                    interrupts |= self.set_mode(Mode::PixelTransfer);
+                },
+                Mode::PixelTransfer if self.mode_clock >= PIXEL_TRANSFER_CYCLES => {
+                    self.mode_clock -= PIXEL_TRANSFER_CYCLES;
+                    self.render_scanline();
+                    self.hblanks_started = self.hblanks_started.saturating_add(1);
+                    interrupts |= self.set_mode(Mode::HBlank);
+                },
+                Mode::HBlank if self.mode_clock >= HBLANK_CYCLES => {
+                    self.mode_clock -= HBLANK_CYCLES;
+                    interrupts |= self.next_line();
+                    if self.ly == VBLANK_START_LINE
+                    {
+                        interrupts |= interrupts::VBLANK;
+                        interrupts |= self.set_mode(Mode::VBlank);
+                    }
+                    else
+                    {
+                        interrupts |= self.set_mode(Mode::OamScan);
+                    }
+                },
+                Mode::VBlank if self.mode_clock >= SCANLINE_CYCLES => {
+                    self.mode_clock -= SCANLINE_CYCLES;
+                    interrupts |= self.next_line();
+                    if self.ly == 0
+                    {
+                        self.window_line = 0;
+                        interrupts |= self.set_mode(Mode::OamScan);
+                    }
+                },
+                _ => break
+            }
+        }
+        interrupts
+    }
+
+    fn tile_data_offset(&self, tile_index: u8) -> usize
+    {
+        if self.lcdc & 0x10 > 0
+        {
+            tile_index as usize * 16
+        }
+        else
+        {
+            (0x1000 + (tile_index as i8 as i32) * 16) as usize
+        }
+    }
+
+    fn tile_pixel(&self, bank: usize, tile_offset: usize, x: u8, y: u8) -> u8
+    {
+        let low = self.vram[bank][tile_offset + (y as usize) * 2];
+        let high = self.vram[bank][tile_offset + (y as usize) * 2 + 1];
+        let bit = 7 - x;
+        (((high >> bit) & 0x01) << 1) | ((low >> bit) & 0x01)
+    }
+
+    fn put_pixel(&mut self, x: usize, color: [u8; 3])
+    {
+        let index = ((self.ly as usize) * SCREEN_WIDTH + x) * 3;
+        self.framebuffer[index..index + 3].copy_from_slice(&color);
+    }
+
+    fn render_scanline(&mut self)
+    {
+        // Colour numbers (0-3) of the background and whether the BG attribute
+        // requested priority over sprites, needed for sprite priority later on
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_priority = [false; SCREEN_WIDTH];
+
+        // On DMG LCDC bit 0 disables background and window,
+        // on CGB it is the BG master priority instead
+        if self.cgb_mode || self.lcdc & 0x01 > 0
+        {
+            self.render_background(&mut bg_colors, &mut bg_priority);
+        }
+        else
+        {
+            for x in 0..SCREEN_WIDTH
+            {
+                self.put_pixel(x, self.dmg_bg_colors[0]);
+            }
+        }
+
+        if self.lcdc & 0x02 > 0
+        {
+            self.render_sprites(&bg_colors, &bg_priority);
+        }
+    }
+
+    fn render_background(&mut self, bg_colors: &mut [u8; SCREEN_WIDTH], bg_priority: &mut [bool; SCREEN_WIDTH])
+    {
+        let window_visible = self.lcdc & 0x20 > 0 && self.wy <= self.ly && self.wx < 167;
+        let mut window_drawn = false;
+
+        for x in 0..SCREEN_WIDTH
+        {
+            let in_window = window_visible && x + 7 >= self.wx as usize;
+            let (map_offset, map_x, map_y) = if in_window
+            {
+                window_drawn = true;
+                let map = if self.lcdc & 0x40 > 0 { 0x1C00 } else { 0x1800 };
+                (map, (x + 7 - self.wx as usize) as u8, self.window_line)
+            }
+            else
+            {
+                let map = if self.lcdc & 0x08 > 0 { 0x1C00 } else { 0x1800 };
+                (map, self.scx.wrapping_add(x as u8), self.scy.wrapping_add(self.ly))
+            };
+
+            let tile_map_index = map_offset + (map_y as usize / 8) * 32 + (map_x as usize / 8);
+            let tile_index = self.vram[0][tile_map_index];
+            let attributes = if self.cgb_mode { self.vram[1][tile_map_index] } else { 0 };
+
+            let bank = ((attributes >> 3) & 0x01) as usize;
+            let tile_x = if attributes & 0x20 > 0 { 7 - map_x % 8 } else { map_x % 8 };
+            let tile_y = if attributes & 0x40 > 0 { 7 - map_y % 8 } else { map_y % 8 };
+            let color = self.tile_pixel(bank, self.tile_data_offset(tile_index), tile_x, tile_y);
+
+            bg_colors[x] = color;
+            bg_priority[x] = attributes & 0x80 > 0;
+            let rgb = if self.cgb_mode
+            {
+                cgb_color(&self.bg_palette_ram, attributes & 0x07, color)
+            }
+            else
+            {
+                dmg_shade(&self.dmg_bg_colors, self.bgp, color)
+            };
+            self.put_pixel(x, rgb);
+        }
+
+        if window_drawn
+        {
+            self.window_line += 1;
+        }
+    }
+
+    fn render_sprites(&mut self, bg_colors: &[u8; SCREEN_WIDTH], bg_priority: &[bool; SCREEN_WIDTH])
+    {
+        let height: u8 = if self.lcdc & 0x04 > 0 { 16 } else { 8 };
+        let line = self.ly as i16 + 16;
+
+        let mut sprites: Vec<usize> = (0..40)
+            .map(|index| index * 4)
+            .filter(|&offset| {
+                let y = self.oam[offset] as i16;
+                line >= y && line < y + height as i16
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+
+        // DMG prioritises by X coordinate, CGB only by OAM position.
+        // The sort is stable, so equal X coordinates keep OAM order.
+        if !self.cgb_mode
+        {
+            sprites.sort_by_key(|&offset| self.oam[offset + 1]);
+        }
+
+        let mut pixel_taken = [false; SCREEN_WIDTH];
+        for offset in sprites
+        {
+            let y = self.oam[offset] as i16;
+            let x = self.oam[offset + 1] as i16 - 8;
+            let mut tile = self.oam[offset + 2];
+            let attributes = self.oam[offset + 3];
+            if height == 16
+            {
+                tile &= 0xFE;
+            }
+
+            let mut row = (line - y) as u8;
+            if attributes & 0x40 > 0
+            {
+                row = height - 1 - row;
+            }
+            let bank = if self.cgb_mode { ((attributes >> 3) & 0x01) as usize } else { 0 };
+
+            for pixel in 0..8u8
+            {
+                let screen_x = x + pixel as i16;
+                if screen_x < 0 || screen_x >= SCREEN_WIDTH as i16 || pixel_taken[screen_x as usize]
+                {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                let tile_x = if attributes & 0x20 > 0 { 7 - pixel } else { pixel };
+                let color = self.tile_pixel(bank, tile as usize * 16, tile_x, row);
+                if color == 0
+                {
+                    continue;
+                }
+                // Even a sprite hidden behind the background hides sprites with lower priority
+                pixel_taken[screen_x] = true;
+
+                let background_wins = if self.cgb_mode
+                {
+                    self.lcdc & 0x01 > 0 && bg_colors[screen_x] != 0 && (bg_priority[screen_x] || attributes & 0x80 > 0)
+                }
+                else
+                {
+                    attributes & 0x80 > 0 && bg_colors[screen_x] != 0
+                };
+                if background_wins
+                {
+                    continue;
+                }
+
+                let rgb = if self.cgb_mode
+                {
+                    cgb_color(&self.obj_palette_ram, attributes & 0x07, color)
+                }
+                else
+                {
+                    if attributes & 0x10 > 0
+                    {
+                        dmg_shade(&self.dmg_obj1_colors, self.obp1, color)
+                    }
+                    else
+                    {
+                        dmg_shade(&self.dmg_obj0_colors, self.obp0, color)
+                    }
+                };
+                self.put_pixel(screen_x, rgb);
+            }
+        }
+    }
+}
+
+
+fn save_colors(state: &mut StateWriter, colors: &[[u8; 3]; 4])
+{
+    for color in colors
+    {
+        state.bytes(color);
+    }
+}
+
+fn load_colors(state: &mut StateReader, colors: &mut [[u8; 3]; 4]) -> Result<(), StateError>
+{
+    for color in colors.iter_mut()
+    {
+        state.bytes(color)?;
+    }
+    Ok(())
+}
+
+impl Snapshot for Ppu
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.cgb_mode);
+        state.bytes(&self.vram[0]);
+        state.bytes(&self.vram[1]);
+        state.u8(self.vram_bank as u8);
+        state.bytes(&self.oam);
+        state.bytes(&[self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc,
+                      self.bgp, self.obp0, self.obp1, self.wy, self.wx]);
+        state.u8(self.mode as u8);
+        state.u32(self.mode_clock);
+        state.u8(self.window_line);
+        state.u8(self.hblanks_started);
+        state.u8(self.bg_palette_index);
+        state.bytes(&self.bg_palette_ram);
+        state.u8(self.obj_palette_index);
+        state.bytes(&self.obj_palette_ram);
+        save_colors(state, &self.dmg_bg_colors);
+        save_colors(state, &self.dmg_obj0_colors);
+        save_colors(state, &self.dmg_obj1_colors);
+        state.bytes(&self.framebuffer);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.cgb_mode = state.bool()?;
+        state.bytes(&mut self.vram[0])?;
+        state.bytes(&mut self.vram[1])?;
+        self.vram_bank = (state.u8()? & 0x01) as usize;
+        state.bytes(&mut self.oam)?;
+        let mut registers = [0; 11];
+        state.bytes(&mut registers)?;
+        let [lcdc, stat, scy, scx, ly, lyc, bgp, obp0, obp1, wy, wx] = registers;
+        self.lcdc = lcdc;
+        self.stat = stat;
+        self.scy = scy;
+        self.scx = scx;
+        self.ly = ly;
+        self.lyc = lyc;
+        self.bgp = bgp;
+        self.obp0 = obp0;
+        self.obp1 = obp1;
+        self.wy = wy;
+        self.wx = wx;
+        self.mode = match state.u8()? & 0x03 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            _ => Mode::PixelTransfer
+        };
+        // Only VBlank happens below the screen
+        let last_line = if self.mode == Mode::VBlank { LINES_PER_FRAME - 1 } else { VBLANK_START_LINE - 1 };
+        if self.ly > last_line
+        {
+            return Err(StateError::Corrupt);
+        }
+        self.mode_clock = state.u32_in(0..=SCANLINE_CYCLES - 1)?;
+        self.window_line = state.u8_in(0..=VBLANK_START_LINE)?;
+        self.hblanks_started = state.u8()?;
+        self.bg_palette_index = state.u8()? & 0xBF;
+        state.bytes(&mut self.bg_palette_ram)?;
+        self.obj_palette_index = state.u8()? & 0xBF;
+        state.bytes(&mut self.obj_palette_ram)?;
+        load_colors(state, &mut self.dmg_bg_colors)?;
+        load_colors(state, &mut self.dmg_obj0_colors)?;
+        load_colors(state, &mut self.dmg_obj1_colors)?;
+        state.bytes(&mut self.framebuffer)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> [u8; 3]
+    {
+        let index = (y * SCREEN_WIDTH + x) * 3;
+        [ppu.framebuffer[index], ppu.framebuffer[index + 1], ppu.framebuffer[index + 2]]
+    }
+
+    fn set_bg_color(ppu: &mut Ppu, palette: u8, color: u8, value: u16)
+    {
+        ppu.write_register(0xFF68, palette * 8 + color * 2);
+        ppu.write_register(0xFF69, value as u8);
+        ppu.write_register(0xFF68, palette * 8 + color * 2 + 1);
+        ppu.write_register(0xFF69, (value >> 8) as u8);
+    }
+
+    #[test]
+    fn vbk_selects_vram_bank()
+    {
+        let mut ppu = Ppu { cgb_mode: true, ..Ppu::default() };
+        ppu.write_register(0xFF40, 0x00);
+
+        ppu.write_vram(0x8000, 0x11);
+        ppu.write_register(0xFF4F, 0x01);
+        ppu.write_vram(0x8000, 0x22);
+
+        assert_eq!(0xFF, ppu.read_register(0xFF4F));
+        assert_eq!(0x22, ppu.read_vram(0x8000));
+        ppu.write_register(0xFF4F, 0x00);
+        assert_eq!(0xFE, ppu.read_register(0xFF4F));
+        assert_eq!(0x11, ppu.read_vram(0x8000));
+    }
+
+    #[test]
+    fn vbk_is_ignored_on_dmg()
+    {
+        let mut ppu = Ppu::default();
+        ppu.write_register(0xFF40, 0x00);
+
+        ppu.write_register(0xFF4F, 0x01);
+        ppu.write_vram(0x8000, 0x22);
+
+        assert_eq!(0xFF, ppu.read_register(0xFF4F));
+        assert_eq!(0x22, ppu.vram[0][0]);
+    }
+
+    #[test]
+    fn palette_index_auto_increments()
+    {
+        let mut ppu = Ppu { cgb_mode: true, ..Ppu::default() };
+        ppu.write_register(0xFF40, 0x00);
+
+        ppu.write_register(0xFF68, 0x80 | 0x3E);
+        ppu.write_register(0xFF69, 0x12);
+        ppu.write_register(0xFF69, 0x34);
+        ppu.write_register(0xFF69, 0x56);
+
+        assert_eq!(0x12, ppu.bg_palette_ram[0x3E]);
+        assert_eq!(0x34, ppu.bg_palette_ram[0x3F]);
+        assert_eq!(0x56, ppu.bg_palette_ram[0x00]);
+        assert_eq!(0xC1, ppu.read_register(0xFF68));
+    }
+
+    #[test]
+    fn palette_index_without_auto_increment_stays()
+    {
+        let mut ppu = Ppu { cgb_mode: true, ..Ppu::default() };
+        ppu.write_register(0xFF40, 0x00);
+
+        ppu.write_register(0xFF6A, 0x05);
+        ppu.write_register(0xFF6B, 0x12);
+        ppu.write_register(0xFF6B, 0x34);
+
+        assert_eq!(0x34, ppu.obj_palette_ram[0x05]);
+        assert_eq!(0x34, ppu.read_register(0xFF6B));
+        assert_eq!(0x45, ppu.read_register(0xFF6A));
+    }
+
+    #[test]
+    fn cgb_color_expands_rgb555()
+    {
+        let mut palette_ram = [0u8; 0x40];
+        // Red = 0x1F, Green = 0x00, Blue = 0x10
+        palette_ram[10] = 0x1F;
+        palette_ram[11] = 0x40;
+
+        assert_eq!([0xFF, 0x00, 0x84], cgb_color(&palette_ram, 1, 1));
+    }
+
+    #[test]
+    fn bg_attributes_select_palette_bank_and_flip()
+    {
+        let mut ppu = Ppu { cgb_mode: true, ..Ppu::default() };
+        ppu.write_register(0xFF40, 0x00);
+
+        // Tile 1 in bank 1: top left pixel has colour 3, everything else colour 0
+        ppu.vram[1][16] = 0x80;
+        ppu.vram[1][17] = 0x80;
+        ppu.vram[0][0x1800] = 0x01;
+        // Palette 2, bank 1, horizontal + vertical flip
+        ppu.vram[1][0x1800] = 0x02 | 0x08 | 0x20 | 0x40;
+        set_bg_color(&mut ppu, 2, 3, 0x001F);
+
+        ppu.lcdc = 0x91;
+        ppu.ly = 7;
+        ppu.render_scanline();
+
+        assert_eq!([0xFF, 0x00, 0x00], pixel(&ppu, 7, 7));
+        assert_eq!([0xFF, 0xFF, 0xFF], pixel(&ppu, 0, 7));
+    }
+
+    fn setup_sprite_over_background(ppu: &mut Ppu, bg_attributes: u8, sprite_attributes: u8)
+    {
+        ppu.cgb_mode = true;
+        ppu.write_register(0xFF40, 0x00);
+        // Tile 0 is fully colour 1, used by background and sprite
+        for row in 0..8
+        {
+            ppu.vram[0][row * 2] = 0xFF;
+        }
+        ppu.vram[1][0x1800] = bg_attributes;
+        set_bg_color(ppu, 0, 1, 0x001F);
+        ppu.write_register(0xFF6A, 0x80 | 0x02);
+        ppu.write_register(0xFF6B, 0xE0);
+        ppu.write_register(0xFF6B, 0x03);
+        ppu.oam[0] = 16;
+        ppu.oam[1] = 8;
+        ppu.oam[3] = sprite_attributes;
+        ppu.ly = 0;
+    }
+
+    #[test]
+    fn sprite_drawn_over_background()
+    {
+        let mut ppu = Ppu::default();
+        setup_sprite_over_background(&mut ppu, 0x00, 0x00);
+        ppu.lcdc = 0x93;
+
+        ppu.render_scanline();
+
+        assert_eq!([0x00, 0xFF, 0x00], pixel(&ppu, 0, 0));
+    }
+
+    #[test]
+    fn bg_attribute_priority_hides_sprite()
+    {
+        let mut ppu = Ppu::default();
+        setup_sprite_over_background(&mut ppu, 0x80, 0x00);
+        ppu.lcdc = 0x93;
+
+        ppu.render_scanline();
+
+        assert_eq!([0xFF, 0x00, 0x00], pixel(&ppu, 0, 0));
+    }
+
+    #[test]
+    fn master_priority_off_always_shows_sprite()
+    {
+        let mut ppu = Ppu::default();
+        setup_sprite_over_background(&mut ppu, 0x80, 0x80);
+        ppu.lcdc = 0x92;
+
+        ppu.render_scanline();
+
+        assert_eq!([0x00, 0xFF, 0x00], pixel(&ppu, 0, 0));
+    }
+
+    #[test]
+    fn compat_palette_colors_dmg_background()
+    {
+        let mut ppu = Ppu::default();
+        ppu.write_register(0xFF40, 0x00);
+        ppu.set_compat_palette(&CompatPalette {
+            bg: [0x7FFF, 0x001F, 0x03E0, 0x7C00],
+            obj0: [0x0000; 4],
+            obj1: [0x0000; 4],
+        });
+        // First pixel of tile 0 has colour 1, which BGP maps to shade 2
+        ppu.vram[0][0] = 0x80;
+        ppu.bgp = 0xE8;
+
+        ppu.lcdc = 0x91;
+        ppu.render_scanline();
+
+        assert_eq!([0x00, 0xFF, 0x00], pixel(&ppu, 0, 0));
+        assert_eq!([0xFF, 0xFF, 0xFF], pixel(&ppu, 1, 0));
+    }
+
+    #[test]
+    fn vblank_interrupt_after_144_lines()
+    {
+        let mut ppu = Ppu::default();
+
+        let interrupts = ppu.tick(SCANLINE_CYCLES * 143);
+        assert_eq!(0, interrupts & interrupts::VBLANK);
+
+        let interrupts = ppu.tick(SCANLINE_CYCLES);
+        assert_eq!(interrupts::VBLANK, interrupts & interrupts::VBLANK);
+        assert_eq!(Mode::VBlank, ppu.mode());
+        assert_eq!(144, ppu.read_register(0xFF44));
+    }
+}
@@ -1,4 +1,4 @@
-use std::convert::TryInto;
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -39,60 +39,60 @@ impl Registers
     #[allow(dead_code)]
     pub fn set_af(&mut self, af: u16)
     {
-        self.a = ((af >> 8)).try_into().unwrap();
-        self.f = ((((af << 8)) >> 8)).try_into().unwrap();
+        self.a = (af >> 8) as u8;
+        self.f = af as u8;
     }
 
     #[allow(dead_code)]
     pub fn get_af(&self) -> u16
     {
         let mut af: u16 = self.a as u16;
-        af = ((af << 8) + (self.f as u16)).into();
+        af = (af << 8) + (self.f as u16);
         af
     }
 
     #[allow(dead_code)]
     pub fn set_bc(&mut self, bc: u16)
     {
-        self.b = ((bc >> 8)).try_into().unwrap();
-        self.c = ((((bc << 8)) >> 8)).try_into().unwrap();
+        self.b = (bc >> 8) as u8;
+        self.c = bc as u8;
     }
 
     #[allow(dead_code)]
     pub fn get_bc(&self) -> u16
     {
         let mut bc: u16 = self.b as u16;
-        bc = ((bc << 8) + (self.c as u16)).into();
+        bc = (bc << 8) + (self.c as u16);
         bc
     }
 
     #[allow(dead_code)]
     pub fn set_de(&mut self, de: u16)
     {
-        self.d = ((de >> 8)).try_into().unwrap();
-        self.e = ((((de << 8)) >> 8)).try_into().unwrap();
+        self.d = (de >> 8) as u8;
+        self.e = de as u8;
     }
 
     #[allow(dead_code)]
     pub fn get_de(&self) -> u16
     {
         let mut de: u16 = self.d as u16;
-        de = ((de << 8) + (self.e as u16)).into();
+        de = (de << 8) + (self.e as u16);
         de
     }
     
     #[allow(dead_code)]
     pub fn set_hl(&mut self, hl: u16)
     {
-        self.h = ((hl >> 8)).try_into().unwrap();
-        self.l = ((((hl << 8)) >> 8)).try_into().unwrap();
+        self.h = (hl >> 8) as u8;
+        self.l = hl as u8;
     }
 
     #[allow(dead_code)]
     pub fn get_hl(&self) -> u16
     {
         let mut hl: u16 = self.h as u16;
-        hl = ((hl << 8) + (self.l as u16)).into();
+        hl = (hl << 8) + (self.l as u16);
         hl
     }
 
@@ -169,6 +169,26 @@ impl Registers
     }
 }
 
+
+impl Snapshot for Registers
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bytes(&[self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.f]);
+        state.u16(self.sp);
+        state.u16(self.pc);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        let mut values = [0; 8];
+        state.bytes(&mut values)?;
+        let [a, b, c, d, e, h, l, f] = values;
+        *self = Registers { a, b, c, d, e, h, l, f, sp: state.u16()?, pc: state.u16()? };
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,8 +204,7 @@ mod tests {
     #[test]
     fn set_a()
     {
-        let mut registers: Registers = Registers::default();
-        registers.a = 5;
+        let registers = Registers { a: 5, ..Registers::default() };
         
         assert_eq!(5, registers.a);
     }
@@ -204,10 +223,7 @@ mod tests {
     #[test]
     fn get_af()
     {
-        let mut registers: Registers = Registers::default();
-     
-        registers.a = 0xFA;
-        registers.f = 0xDA;
+        let registers = Registers { a: 0xFA, f: 0xDA, ..Registers::default() };
 
         assert_eq!(0xFADA, registers.get_af());
     }
@@ -236,10 +252,7 @@ mod tests {
     #[test]
     fn get_bc()
     {
-        let mut registers: Registers = Registers::default();
-     
-        registers.b = 0xFA;
-        registers.c = 0xDA;
+        let registers = Registers { b: 0xFA, c: 0xDA, ..Registers::default() };
 
         assert_eq!(0xFADA, registers.get_bc());
     }
@@ -269,10 +282,7 @@ mod tests {
     #[test]
     fn get_de()
     {
-        let mut registers: Registers = Registers::default();
-     
-        registers.d = 0xFA;
-        registers.e = 0xDA;
+        let registers = Registers { d: 0xFA, e: 0xDA, ..Registers::default() };
 
         assert_eq!(0xFADA, registers.get_de());
     }
@@ -302,10 +312,7 @@ mod tests {
     #[test]
     fn get_hl()
     {
-        let mut registers: Registers = Registers::default();
-     
-        registers.h = 0xFA;
-        registers.l = 0xDA;
+        let registers = Registers { h: 0xFA, l: 0xDA, ..Registers::default() };
 
         assert_eq!(0xFADA, registers.get_hl());
     }
@@ -327,18 +334,16 @@ mod tests {
 
         registers.set_zero_flag();
 
-        assert_eq!(true, registers.is_zero_flag_set());
+        assert!(registers.is_zero_flag_set());
     }
 
     #[test]
     fn unset_zero_flag()
     {
-        let mut registers: Registers = Registers::default();
-
-        registers.f = 0xFF;
+        let mut registers = Registers { f: 0xFF, ..Registers::default() };
         registers.unset_zero_flag();
 
-        assert_eq!(false, registers.is_zero_flag_set());
+        assert!(!registers.is_zero_flag_set());
     }
 
     #[test]
@@ -348,18 +353,16 @@ mod tests {
         
         registers.set_subtraction_flag();
 
-        assert_eq!(true, registers.is_subtraction_flag_set());
+        assert!(registers.is_subtraction_flag_set());
     }
 
     #[test]
     fn unset_subtraction_flag()
     {
-        let mut registers:Registers = Registers::default();
-
-        registers.f = 0xFF;
+        let mut registers = Registers { f: 0xFF, ..Registers::default() };
         registers.unset_subtraction_flag();
 
-        assert_eq!(false, registers.is_subtraction_flag_set());
+        assert!(!registers.is_subtraction_flag_set());
     }
 
     #[test]
@@ -369,18 +372,16 @@ mod tests {
 
         registers.set_halfcarry_flag();
 
-        assert_eq!(true, registers.is_halfcarry_flag_set());
+        assert!(registers.is_halfcarry_flag_set());
     }
 
     #[test]
     fn unset_halfcarry_flag()
     {
-        let mut registers: Registers = Registers::default();
-
-        registers.f = 0xFF;
+        let mut registers = Registers { f: 0xFF, ..Registers::default() };
         registers.unset_halfcarry_flag();
 
-        assert_eq!(false, registers.is_halfcarry_flag_set());
+        assert!(!registers.is_halfcarry_flag_set());
     }
 
     #[test]
@@ -390,17 +391,15 @@ mod tests {
 
         registers.set_carry_flag();
 
-        assert_eq!(true, registers.is_carry_flag_set());
+        assert!(registers.is_carry_flag_set());
     }
 
     #[test]
     fn unset_carry_flag()
     {
-        let mut registers: Registers = Registers::default();
-
-        registers.f = 0xFF;
+        let mut registers = Registers { f: 0xFF, ..Registers::default() };
         registers.unset_carry_flag();
 
-        assert_eq!(false, registers.is_carry_flag_set());
+        assert!(!registers.is_carry_flag_set());
     }
 }
\ No newline at end of file
@@ -24,14 +24,14 @@ fn check_nintendo_logo(logo: &[u8]) -> bool
     true
 }
 
-pub fn check_valid(rom: &Vec<u8>) -> bool
+pub fn check_valid(rom: &[u8]) -> bool
 {
     // TODO: Also check checksums here
     let slice = &rom[0x0104..0x0134];
     check_nintendo_logo(slice)
 }
 
-pub fn get_rom_name(rom: &Vec<u8>) -> String
+pub fn get_rom_name(rom: &[u8]) -> String
 {
     let chars_raw = &rom[0x0134..0x0144];
     let mut chars: Vec<char> = vec![];
@@ -41,3 +41,15 @@ pub fn get_rom_name(rom: &Vec<u8>) -> String
     }
     chars.into_iter().collect()
 }
+
+pub fn is_cgb_rom(rom: &[u8]) -> bool
+{
+    // 0x80: CGB enhanced, still runs on DMG. 0xC0: CGB only
+    rom[0x0143] & 0x80 > 0
+}
+
+/// FNV-1a hash of the whole ROM, tells ROMs apart in save states
+pub fn checksum(rom: &[u8]) -> u32
+{
+    rom.iter().fold(0x811C9DC5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
@@ -0,0 +1,381 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/*
+
+Save states, a snapshot of the whole machine. They start with a header:
+  * Magic bytes "RBOYSTAT"
+  * Format version (u16)
+  * Emulator version that wrote the state, prefixed with its length (u8)
+  * Checksum of the ROM (u32) and the model (u8)
+
+Every subsystem follows in its own section: a four character tag, the
+length of the content (u32) and the content. Everything is little endian.
+
+Loading skips sections it does not know and whatever is left at the end of a
+section it knows, so new fields only ever get appended to a section and
+states written by newer versions keep loading. The format version only goes
+up for changes that older versions can not read anymore.
+
+Loaded values that index tables, shift or count down get masked like the
+register writes would mask them, or rejected as corrupt if no mask fits.
+
+*/
+
+const MAGIC: &[u8; 8] = b"RBOYSTAT";
+pub const FORMAT_VERSION: u16 = 1;
+pub const EMULATOR_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, PartialEq)]
+pub enum StateError
+{
+    NotAState,
+    UnsupportedVersion(u16),
+    WrongRom,
+    WrongModel,
+    Truncated,
+    Corrupt,
+}
+
+impl fmt::Display for StateError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            StateError::NotAState => write!(formatter, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(formatter, "Save state format {} is not supported", version),
+            StateError::WrongRom => write!(formatter, "Save state belongs to a different ROM"),
+            StateError::WrongModel => write!(formatter, "Save state belongs to a different model"),
+            StateError::Truncated => write!(formatter, "Save state is truncated"),
+            StateError::Corrupt => write!(formatter, "Save state is corrupt")
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Something that can be written into and restored from a save state
+pub trait Snapshot
+{
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter
+{
+    data: Vec<u8>,
+}
+
+impl StateWriter
+{
+    pub fn u8(&mut self, value: u8)
+    {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool)
+    {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32)
+    {
+        self.u32(value.to_bits());
+    }
+
+    /// Writes the bytes as they are, the reader has to know the length
+    pub fn bytes(&mut self, bytes: &[u8])
+    {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes a section with whatever the given function writes as content
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], write: F)
+    {
+        let mut content = StateWriter::default();
+        write(&mut content);
+        self.bytes(tag);
+        self.u32(content.data.len() as u32);
+        self.bytes(&content.data);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8>
+    {
+        self.data
+    }
+}
+
+pub struct StateReader<'a>
+{
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a>
+{
+    pub fn new(data: &'a [u8]) -> StateReader<'a>
+    {
+        StateReader { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError>
+    {
+        if self.data.len() < length
+        {
+            return Err(StateError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.data.is_empty()
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError>
+    {
+        Ok(self.take(1)?[0])
+    }
+
+    /// A u8 that has to be in the given range
+    pub fn u8_in(&mut self, range: RangeInclusive<u8>) -> Result<u8, StateError>
+    {
+        Some(self.u8()?).filter(|value| range.contains(value)).ok_or(StateError::Corrupt)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError>
+    {
+        Ok(self.u8()? > 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError>
+    {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError>
+    {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A u32 that has to be in the given range
+    pub fn u32_in(&mut self, range: RangeInclusive<u32>) -> Result<u32, StateError>
+    {
+        Some(self.u32()?).filter(|value| range.contains(value)).ok_or(StateError::Corrupt)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError>
+    {
+        let mut bytes = [0; 8];
+        self.bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, StateError>
+    {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    /// The next bytes as they are, only if there are enough of them
+    pub fn slice(&mut self, length: usize) -> Result<&'a [u8], StateError>
+    {
+        self.take(length)
+    }
+
+    /// Fills the whole slice
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError>
+    {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    /// The next section, with a reader for its content
+    pub fn section(&mut self) -> Result<([u8; 4], StateReader<'a>), StateError>
+    {
+        let mut tag = [0; 4];
+        self.bytes(&mut tag)?;
+        let length = self.u32()? as usize;
+        Ok((tag, StateReader::new(self.take(length)?)))
+    }
+}
+
+pub struct Header
+{
+    pub format_version: u16,
+    pub emulator_version: String,
+    pub rom_checksum: u32,
+    pub model: u8,
+}
+
+impl Header
+{
+    pub fn new(rom_checksum: u32, model: u8) -> Header
+    {
+        Header { format_version: FORMAT_VERSION, emulator_version: EMULATOR_VERSION.to_string(), rom_checksum, model }
+    }
+
+    pub fn write(&self, state: &mut StateWriter)
+    {
+        state.bytes(MAGIC);
+        state.u16(self.format_version);
+        state.u8(self.emulator_version.len() as u8);
+        state.bytes(self.emulator_version.as_bytes());
+        state.u32(self.rom_checksum);
+        state.u8(self.model);
+    }
+
+    pub fn read(state: &mut StateReader) -> Result<Header, StateError>
+    {
+        let mut magic = [0; 8];
+        state.bytes(&mut magic).map_err(|_| StateError::NotAState)?;
+        if &magic != MAGIC
+        {
+            return Err(StateError::NotAState);
+        }
+        let format_version = state.u16()?;
+        if format_version > FORMAT_VERSION
+        {
+            return Err(StateError::UnsupportedVersion(format_version));
+        }
+        let mut emulator_version = vec![0; state.u8()? as usize];
+        state.bytes(&mut emulator_version)?;
+        Ok(Header {
+            format_version,
+            emulator_version: String::from_utf8_lossy(&emulator_version).to_string(),
+            rom_checksum: state.u32()?,
+            model: state.u8()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter
+    {
+        value: u16,
+        running: bool,
+    }
+
+    impl Snapshot for Counter
+    {
+        fn save(&self, state: &mut StateWriter)
+        {
+            state.u16(self.value);
+            state.bool(self.running);
+        }
+
+        fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+        {
+            self.value = state.u16()?;
+            self.running = state.bool()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn values_survive_a_round_trip()
+    {
+        let mut writer = StateWriter::default();
+        writer.u8(0x12);
+        writer.u32(0xDEADBEEF);
+        writer.u64(0x0123_4567_89AB_CDEF);
+        writer.f32(-0.5);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        let mut bytes = [0; 3];
+
+        assert_eq!(Ok(0x12), reader.u8());
+        assert_eq!(Ok(0xDEADBEEF), reader.u32());
+        assert_eq!(Ok(0x0123_4567_89AB_CDEF), reader.u64());
+        assert_eq!(Ok(-0.5), reader.f32());
+        assert_eq!(Ok(()), reader.bytes(&mut bytes));
+        assert_eq!([1, 2, 3], bytes);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn reading_past_the_end_is_an_error()
+    {
+        let mut reader = StateReader::new(&[1]);
+
+        assert_eq!(Err(StateError::Truncated), reader.u16());
+    }
+
+    #[test]
+    fn sections_skip_what_they_do_not_read()
+    {
+        let mut writer = StateWriter::default();
+        writer.bytes(b"CNTR");
+        writer.u32(5);
+        writer.u16(300);
+        writer.bool(true);
+        // A field appended by a newer version
+        writer.u16(0xFFFF);
+        writer.section(b"NEXT", |state| Counter { value: 7, running: false }.save(state));
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        let mut counter = Counter::default();
+        let (tag, mut content) = reader.section().unwrap();
+        counter.load(&mut content).unwrap();
+        let (next_tag, _) = reader.section().unwrap();
+
+        assert_eq!(b"CNTR", &tag);
+        assert_eq!(300, counter.value);
+        assert!(counter.running);
+        assert_eq!(b"NEXT", &next_tag);
+    }
+
+    #[test]
+    fn header_round_trip()
+    {
+        let mut writer = StateWriter::default();
+        Header::new(0x1234_5678, 1).write(&mut writer);
+        let data = writer.into_bytes();
+
+        let header = Header::read(&mut StateReader::new(&data)).unwrap();
+
+        assert_eq!(FORMAT_VERSION, header.format_version);
+        assert_eq!(EMULATOR_VERSION, header.emulator_version);
+        assert_eq!(0x1234_5678, header.rom_checksum);
+        assert_eq!(1, header.model);
+    }
+
+    #[test]
+    fn newer_format_is_rejected()
+    {
+        let mut writer = StateWriter::default();
+        let mut header = Header::new(0, 0);
+        header.format_version = FORMAT_VERSION + 1;
+        header.write(&mut writer);
+        let data = writer.into_bytes();
+
+        assert_eq!(Some(StateError::UnsupportedVersion(FORMAT_VERSION + 1)), Header::read(&mut StateReader::new(&data)).err());
+        assert_eq!(Some(StateError::NotAState), Header::read(&mut StateReader::new(b"RIFF")).err());
+    }
+}
@@ -0,0 +1,341 @@
+use super::interrupts;
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+Serial port, controlled by the registers:
+  * SB (0xFF01): Data that gets shifted out, MSB first, while the received bits are shifted in
+  * SC (0xFF02): Bit 7 starts a transfer and stays set until it is done,
+                 bit 1 selects the fast clock (CGB only), bit 0 the internal clock
+
+With the internal clock the Game Boy shifts one bit on every falling edge of
+bit 8 of the DIV counter (8192 Hz), or bit 3 with the fast clock (262144 Hz).
+Both run twice as fast in double speed mode, just like DIV. A whole byte
+takes 4096 cycles, the first bit can come earlier depending on DIV.
+
+With the external clock the Game Boy waits until the connected device clocks
+the transfer, which never happens without a cable.
+
+The serial interrupt is requested once all 8 bits are shifted.
+
+*/
+
+// Falling edges of these DIV counter bits clock the transfer
+const NORMAL_CLOCK_BIT: u32 = 8;
+const FAST_CLOCK_BIT: u32 = 3;
+
+/// Something at the other end of the link cable
+pub trait SerialDevice
+{
+    /// The Game Boy starts a transfer with its internal clock. Receives the
+    /// byte the Game Boy sends, returns the byte the device sends back.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Gets called whenever the Game Boy ran for some CPU cycles. While it waits
+    /// for an external clock, waiting holds the byte in SB. Returns the byte the
+    /// device sent if it clocked a transfer.
+    fn tick(&mut self, _cycles: u32, _waiting: Option<u8>) -> Option<u8>
+    {
+        None
+    }
+}
+
+/// No cable, all received bits are 1
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected
+{
+    fn exchange(&mut self, _byte: u8) -> u8
+    {
+        0xFF
+    }
+}
+
+pub struct Serial
+{
+    pub cgb_mode: bool,
+    data: u8,
+    control: u8,
+    incoming: u8,
+    bits_remaining: u8,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Default for Serial {
+    fn default() -> Serial
+    {
+        Serial {
+            cgb_mode: false,
+            data: 0,
+            control: 0,
+            incoming: 0xFF,
+            bits_remaining: 0,
+            device: Box::new(Disconnected),
+        }
+    }
+}
+
+impl Serial
+{
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>)
+    {
+        self.device = device;
+    }
+
+    fn transfer_active(&self) -> bool
+    {
+        self.control & 0x80 > 0
+    }
+
+    fn internal_clock(&self) -> bool
+    {
+        self.control & 0x01 > 0
+    }
+
+    fn clock_bit(&self) -> u32
+    {
+        if self.cgb_mode && self.control & 0x02 > 0 { FAST_CLOCK_BIT } else { NORMAL_CLOCK_BIT }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8
+    {
+        match address {
+            0xFF01 => self.data,
+            0xFF02 if self.cgb_mode => 0x7C | self.control,
+            0xFF02 => 0x7E | (self.control & 0x81),
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8)
+    {
+        match address {
+            0xFF01 => self.data = value,
+            0xFF02 => {
+                self.control = value & 0x83;
+                self.bits_remaining = if self.transfer_active() { 8 } else { 0 };
+                if self.transfer_active() && self.internal_clock()
+                {
+                    self.incoming = self.device.exchange(self.data);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn finish_transfer(&mut self) -> u8
+    {
+        self.control &= !0x80;
+        self.bits_remaining = 0;
+        interrupts::SERIAL
+    }
+
+    /// Advances the transfer by the given amount of CPU cycles, starting at the
+    /// given DIV counter. Returns the interrupts that were raised.
+    pub fn tick(&mut self, counter: u16, cycles: u32) -> u8
+    {
+        let waiting = if self.transfer_active() && !self.internal_clock() { Some(self.data) } else { None };
+        let clocked = self.device.tick(cycles, waiting);
+        if !self.transfer_active()
+        {
+            return 0;
+        }
+        if !self.internal_clock()
+        {
+            return match clocked {
+                Some(byte) => {
+                    self.data = byte;
+                    self.finish_transfer()
+                },
+                None => 0
+            };
+        }
+
+        // Falling edges happen whenever the counter passes a multiple of twice the bit
+        let shift = self.clock_bit() + 1;
+        let start = counter as u32;
+        let edges = ((start + cycles) >> shift) - (start >> shift);
+        for _ in 0..edges
+        {
+            self.data = (self.data << 1) | (self.incoming >> 7);
+            self.incoming <<= 1;
+            self.bits_remaining -= 1;
+            if self.bits_remaining == 0
+            {
+                return self.finish_transfer();
+            }
+        }
+        0
+    }
+}
+
+
+// The connected device is not part of the state, it stays plugged in
+impl Snapshot for Serial
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.cgb_mode);
+        state.bytes(&[self.data, self.control, self.incoming, self.bits_remaining]);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.cgb_mode = state.bool()?;
+        self.data = state.u8()?;
+        self.control = state.u8()? & 0x83;
+        self.incoming = state.u8()?;
+        // A running transfer always has bits left
+        let least_bits = self.transfer_active() as u8;
+        self.bits_remaining = state.u8_in(least_bits..=8)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Partner
+    {
+        reply: u8,
+        received: Rc<RefCell<Vec<u8>>>,
+        clocks: bool,
+    }
+
+    impl SerialDevice for Partner
+    {
+        fn exchange(&mut self, byte: u8) -> u8
+        {
+            self.received.borrow_mut().push(byte);
+            self.reply
+        }
+
+        fn tick(&mut self, _cycles: u32, waiting: Option<u8>) -> Option<u8>
+        {
+            match waiting {
+                Some(byte) if self.clocks => Some(self.exchange(byte)),
+                _ => None
+            }
+        }
+    }
+
+    fn connected_serial(reply: u8, clocks: bool) -> (Serial, Rc<RefCell<Vec<u8>>>)
+    {
+        let received = Rc::new(RefCell::new(vec![]));
+        let mut serial = Serial::default();
+        serial.connect(Box::new(Partner { reply, received: received.clone(), clocks }));
+        (serial, received)
+    }
+
+    #[test]
+    fn internal_clock_transfer_takes_4096_cycles()
+    {
+        let (mut serial, received) = connected_serial(0x5A, false);
+        serial.write_register(0xFF01, 0x42);
+        serial.write_register(0xFF02, 0x81);
+
+        assert_eq!(0, serial.tick(0, 4095));
+        assert_eq!(0xFF, serial.read_register(0xFF02));
+
+        assert_eq!(interrupts::SERIAL, serial.tick(4095, 1));
+        assert_eq!(0x7F, serial.read_register(0xFF02));
+        assert_eq!(0x5A, serial.read_register(0xFF01));
+        assert_eq!(vec![0x42], *received.borrow());
+    }
+
+    #[test]
+    fn bits_are_shifted_one_at_a_time()
+    {
+        let (mut serial, _) = connected_serial(0x00, false);
+        serial.write_register(0xFF01, 0xFF);
+        serial.write_register(0xFF02, 0x81);
+
+        serial.tick(0, 3 * 512);
+
+        assert_eq!(0xF8, serial.read_register(0xFF01));
+    }
+
+    #[test]
+    fn first_bit_depends_on_div()
+    {
+        let (mut serial, _) = connected_serial(0x00, false);
+        serial.write_register(0xFF02, 0x81);
+
+        serial.tick(0x1FC, 4);
+        serial.tick(0x200, 7 * 512 - 4);
+        assert_eq!(0xFF, serial.read_register(0xFF02));
+
+        assert_eq!(interrupts::SERIAL, serial.tick(0x1000 - 4, 4));
+    }
+
+    #[test]
+    fn fast_clock_on_cgb()
+    {
+        let (mut serial, _) = connected_serial(0x00, false);
+        serial.cgb_mode = true;
+        serial.write_register(0xFF02, 0x83);
+
+        assert_eq!(interrupts::SERIAL, serial.tick(0, 8 * 16));
+    }
+
+    #[test]
+    fn fast_clock_is_ignored_on_dmg()
+    {
+        let (mut serial, _) = connected_serial(0x00, false);
+        serial.write_register(0xFF02, 0x83);
+
+        assert_eq!(0, serial.tick(0, 8 * 16));
+        assert_eq!(0xFF, serial.read_register(0xFF02));
+    }
+
+    #[test]
+    fn disconnected_cable_receives_ff()
+    {
+        let mut serial = Serial::default();
+        serial.write_register(0xFF01, 0x12);
+        serial.write_register(0xFF02, 0x81);
+
+        serial.tick(0, 4096);
+
+        assert_eq!(0xFF, serial.read_register(0xFF01));
+    }
+
+    #[test]
+    fn external_clock_waits_for_device()
+    {
+        let mut serial = Serial::default();
+        serial.write_register(0xFF02, 0x80);
+
+        assert_eq!(0, serial.tick(0, 0x10000));
+        assert_eq!(0xFE, serial.read_register(0xFF02));
+    }
+
+    #[test]
+    fn transfer_requests_interrupt_through_gameboy()
+    {
+        let mut gameboy = super::super::gameboy::GameBoy::default();
+        gameboy.write_byte(0xFF01, 0x33);
+        gameboy.write_byte(0xFF02, 0x81);
+
+        gameboy.tick(4096);
+
+        assert_eq!(interrupts::SERIAL, gameboy.read_byte(0xFF0F) & interrupts::SERIAL);
+        assert_eq!(0xFF, gameboy.read_byte(0xFF01));
+    }
+
+    #[test]
+    fn external_clock_from_device()
+    {
+        let (mut serial, received) = connected_serial(0x99, true);
+        serial.write_register(0xFF01, 0x24);
+        serial.write_register(0xFF02, 0x80);
+
+        assert_eq!(interrupts::SERIAL, serial.tick(0, 4));
+        assert_eq!(0x99, serial.read_register(0xFF01));
+        assert_eq!(vec![0x24], *received.borrow());
+    }
+}
@@ -0,0 +1,178 @@
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+CGB speed switch, controlled by KEY1 (0xFF4D):
+  * Bit 0: Prepare speed switch (read / write)
+  * Bit 7: Current speed, 0 = normal, 1 = double (read only)
+
+The switch is executed by a STOP instruction while bit 0 is set.
+In double speed mode the CPU and timer run twice as fast, while the PPU
+and APU keep their speed. Cycle counts handed to the GameBoy are CPU
+cycles, subsystems running at normal speed get them converted to dots.
+
+*/
+
+// The CPU is halted for 2050 M-cycles while switching speed
+pub const SPEED_SWITCH_CYCLES: u32 = 2050 * 4;
+
+#[derive(Default)]
+pub struct SpeedSwitch
+{
+    double_speed: bool,
+    switch_armed: bool,
+    switch_delay: u32,
+}
+
+impl SpeedSwitch
+{
+    pub fn is_double_speed(&self) -> bool
+    {
+        self.double_speed
+    }
+
+    pub fn read_key1(&self) -> u8
+    {
+        let speed = if self.double_speed { 0x80 } else { 0x00 };
+        let armed = if self.switch_armed { 0x01 } else { 0x00 };
+        0x7E | speed | armed
+    }
+
+    pub fn write_key1(&mut self, value: u8)
+    {
+        self.switch_armed = value & 0x01 > 0;
+    }
+
+    /// Executes a prepared speed switch. Returns whether the speed was switched.
+    pub fn stop(&mut self) -> bool
+    {
+        if !self.switch_armed
+        {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.switch_armed = false;
+        self.switch_delay += SPEED_SWITCH_CYCLES;
+        true
+    }
+
+    pub fn take_switch_delay(&mut self) -> u32
+    {
+        let delay = self.switch_delay;
+        self.switch_delay = 0;
+        delay
+    }
+
+    /// Converts CPU cycles to dots of the normal speed clock
+    pub fn cycles_to_dots(&self, cycles: u32) -> u32
+    {
+        if self.double_speed { cycles / 2 } else { cycles }
+    }
+
+    /// Converts dots of the normal speed clock to CPU cycles
+    pub fn dots_to_cycles(&self, dots: u32) -> u32
+    {
+        if self.double_speed { dots * 2 } else { dots }
+    }
+}
+
+
+impl Snapshot for SpeedSwitch
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.double_speed);
+        state.bool(self.switch_armed);
+        state.u32(self.switch_delay);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.double_speed = state.bool()?;
+        self.switch_armed = state.bool()?;
+        self.switch_delay = state.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use super::super::gameboy::GameBoy;
+
+    #[test]
+    fn key1_reports_armed_and_speed()
+    {
+        let mut speed = SpeedSwitch::default();
+        assert_eq!(0x7E, speed.read_key1());
+
+        speed.write_key1(0x01);
+        assert_eq!(0x7F, speed.read_key1());
+
+        assert!(speed.stop());
+        assert_eq!(0xFE, speed.read_key1());
+    }
+
+    #[test]
+    fn stop_without_armed_switch_keeps_speed()
+    {
+        let mut speed = SpeedSwitch::default();
+
+        assert!(!speed.stop());
+        assert!(!speed.is_double_speed());
+        assert_eq!(0, speed.take_switch_delay());
+    }
+
+    #[test]
+    fn switching_back_to_normal_speed()
+    {
+        let mut speed = SpeedSwitch::default();
+
+        speed.write_key1(0x01);
+        speed.stop();
+        speed.write_key1(0x01);
+        speed.stop();
+
+        assert!(!speed.is_double_speed());
+        assert_eq!(2 * SPEED_SWITCH_CYCLES, speed.take_switch_delay());
+    }
+
+    #[test]
+    fn key1_is_only_available_in_cgb_mode()
+    {
+        let mut gameboy = GameBoy::default();
+
+        gameboy.write_byte(0xFF4D, 0x01);
+
+        assert_eq!(0xFF, gameboy.read_byte(0xFF4D));
+        assert!(!gameboy.speed.stop());
+    }
+
+    #[test]
+    fn ppu_runs_at_half_rate_in_double_speed()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.cgb_mode = true;
+        gameboy.write_byte(0xFF4D, 0x01);
+        gameboy.speed.stop();
+        gameboy.speed.take_switch_delay();
+
+        gameboy.tick(456);
+        assert_eq!(0, gameboy.read_byte(0xFF44));
+
+        gameboy.tick(456);
+        assert_eq!(1, gameboy.read_byte(0xFF44));
+    }
+
+    #[test]
+    fn tick_includes_switch_delay()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.cgb_mode = true;
+        gameboy.write_byte(0xFF4D, 0x01);
+        gameboy.speed.stop();
+
+        assert_eq!(4 + SPEED_SWITCH_CYCLES, gameboy.tick(4));
+    }
+}
@@ -0,0 +1,330 @@
+use super::interrupts;
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+The timer is driven by a 16 bit counter that increases every cycle.
+DIV (0xFF04) shows its upper 8 bits, writing to DIV resets the whole counter.
+
+TAC (0xFF07) selects a counter bit and enables the timer:
+  * Bit 0-1: 00 = bit 9 (4096 Hz), 01 = bit 3 (262144 Hz),
+             10 = bit 5 (65536 Hz), 11 = bit 7 (16384 Hz)
+  * Bit 2:   Timer enable
+TIMA (0xFF05) increases whenever the selected bit AND the enable bit
+goes from 1 to 0. This also happens when the counter gets reset by a DIV
+write or when TAC changes, which causes the well known glitch increments.
+
+When TIMA overflows it reads 0 for one M-cycle. Only in the M-cycle after
+that it gets reloaded from TMA (0xFF06) and the timer interrupt is
+requested. Writing TIMA during the delay cancels the reload, writing
+TIMA during the reload cycle is ignored and writing TMA during the
+reload cycle is copied to TIMA as well.
+
+*/
+
+const M_CYCLE: u32 = 4;
+
+#[derive(Default)]
+pub struct Timer
+{
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow_pending: bool,
+    reloading: bool,
+    leftover_cycles: u32,
+}
+
+impl Timer
+{
+    fn selected_bit(&self) -> u16
+    {
+        match self.tac & 0x03 {
+            0x00 => 1 << 9,
+            0x01 => 1 << 3,
+            0x02 => 1 << 5,
+            _ => 1 << 7
+        }
+    }
+
+    fn timer_signal(&self) -> bool
+    {
+        self.tac & 0x04 > 0 && self.counter & self.selected_bit() > 0
+    }
+
+    fn increment_tima(&mut self)
+    {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow
+        {
+            self.overflow_pending = true;
+        }
+    }
+
+    fn detect_falling_edge(&mut self, old_signal: bool)
+    {
+        if old_signal && !self.timer_signal()
+        {
+            self.increment_tima();
+        }
+    }
+
+    fn tick_m_cycle(&mut self) -> u8
+    {
+        let mut interrupts = 0;
+        self.reloading = false;
+        if self.overflow_pending
+        {
+            self.overflow_pending = false;
+            self.tima = self.tma;
+            self.reloading = true;
+            interrupts |= interrupts::TIMER;
+        }
+        let old_signal = self.timer_signal();
+        self.counter = self.counter.wrapping_add(M_CYCLE as u16);
+        self.detect_falling_edge(old_signal);
+        interrupts
+    }
+
+    /// The internal counter, DIV shows its upper 8 bits
+    pub fn counter(&self) -> u16
+    {
+        self.counter
+    }
+
+    /// Advances the timer by the given amount of CPU cycles.
+    /// Returns the interrupts that were raised in the meantime.
+    pub fn tick(&mut self, cycles: u32) -> u8
+    {
+        let mut interrupts = 0;
+        self.leftover_cycles += cycles;
+        while self.leftover_cycles >= M_CYCLE
+        {
+            self.leftover_cycles -= M_CYCLE;
+            interrupts |= self.tick_m_cycle();
+        }
+        interrupts
+    }
+
+    pub fn read_register(&self, address: u16) -> u8
+    {
+        match address {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8)
+    {
+        match address {
+            0xFF04 => {
+                let old_signal = self.timer_signal();
+                self.counter = 0;
+                self.detect_falling_edge(old_signal);
+            },
+            // Ignored while TIMA is being reloaded from TMA
+            0xFF05 if !self.reloading => {
+                self.tima = value;
+                self.overflow_pending = false;
+            },
+            0xFF06 => {
+                self.tma = value;
+                if self.reloading
+                {
+                    self.tima = value;
+                }
+            },
+            0xFF07 => {
+                let old_signal = self.timer_signal();
+                self.tac = value & 0x07;
+                self.detect_falling_edge(old_signal);
+            },
+            _ => ()
+        }
+    }
+}
+
+
+impl Snapshot for Timer
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.u16(self.counter);
+        state.bytes(&[self.tima, self.tma, self.tac]);
+        state.bool(self.overflow_pending);
+        state.bool(self.reloading);
+        state.u32(self.leftover_cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()? & 0x07;
+        self.overflow_pending = state.bool()?;
+        self.reloading = state.bool()?;
+        self.leftover_cycles = state.u32_in(0..=M_CYCLE - 1)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn timer_with_tac(tac: u8) -> Timer
+    {
+        let mut timer = Timer::default();
+        timer.write_register(0xFF07, tac);
+        timer
+    }
+
+    #[test]
+    fn div_counts_upper_bits()
+    {
+        let mut timer = Timer::default();
+
+        timer.tick(255);
+        assert_eq!(0x00, timer.read_register(0xFF04));
+
+        timer.tick(1);
+        assert_eq!(0x01, timer.read_register(0xFF04));
+    }
+
+    #[test]
+    fn div_write_resets_counter()
+    {
+        let mut timer = Timer::default();
+        timer.tick(0x1234);
+
+        timer.write_register(0xFF04, 0x55);
+
+        assert_eq!(0x00, timer.read_register(0xFF04));
+        assert_eq!(0, timer.counter);
+    }
+
+    #[test]
+    fn tima_increments_at_selected_rate()
+    {
+        let mut timer = timer_with_tac(0x05);
+
+        timer.tick(15);
+        assert_eq!(0, timer.read_register(0xFF05));
+
+        timer.tick(1);
+        assert_eq!(1, timer.read_register(0xFF05));
+
+        timer.tick(16 * 9);
+        assert_eq!(10, timer.read_register(0xFF05));
+    }
+
+    #[test]
+    fn tima_is_stopped_when_disabled()
+    {
+        let mut timer = timer_with_tac(0x01);
+
+        timer.tick(1024);
+
+        assert_eq!(0, timer.read_register(0xFF05));
+    }
+
+    #[test]
+    fn overflow_reloads_one_m_cycle_later()
+    {
+        let mut timer = timer_with_tac(0x05);
+        timer.write_register(0xFF05, 0xFF);
+        timer.write_register(0xFF06, 0x42);
+
+        let interrupts = timer.tick(16);
+        assert_eq!(0x00, timer.read_register(0xFF05));
+        assert_eq!(0, interrupts);
+
+        let interrupts = timer.tick(4);
+        assert_eq!(0x42, timer.read_register(0xFF05));
+        assert_eq!(interrupts::TIMER, interrupts);
+    }
+
+    #[test]
+    fn tima_write_during_overflow_delay_cancels_reload()
+    {
+        let mut timer = timer_with_tac(0x05);
+        timer.write_register(0xFF05, 0xFF);
+        timer.write_register(0xFF06, 0x42);
+        timer.tick(16);
+
+        timer.write_register(0xFF05, 0x10);
+        let interrupts = timer.tick(4);
+
+        assert_eq!(0x10, timer.read_register(0xFF05));
+        assert_eq!(0, interrupts);
+    }
+
+    #[test]
+    fn tima_write_during_reload_is_ignored()
+    {
+        let mut timer = timer_with_tac(0x05);
+        timer.write_register(0xFF05, 0xFF);
+        timer.write_register(0xFF06, 0x42);
+        timer.tick(20);
+
+        timer.write_register(0xFF05, 0x10);
+
+        assert_eq!(0x42, timer.read_register(0xFF05));
+    }
+
+    #[test]
+    fn tma_write_during_reload_is_copied_to_tima()
+    {
+        let mut timer = timer_with_tac(0x05);
+        timer.write_register(0xFF05, 0xFF);
+        timer.write_register(0xFF06, 0x42);
+        timer.tick(20);
+
+        timer.write_register(0xFF06, 0x77);
+
+        assert_eq!(0x77, timer.read_register(0xFF05));
+        assert_eq!(0x77, timer.read_register(0xFF06));
+    }
+
+    #[test]
+    fn div_write_with_selected_bit_set_increments_tima()
+    {
+        let mut timer = timer_with_tac(0x05);
+        // Bit 3 of the counter is set after 8 cycles
+        timer.tick(8);
+
+        timer.write_register(0xFF04, 0x00);
+
+        assert_eq!(1, timer.read_register(0xFF05));
+    }
+
+    #[test]
+    fn div_write_with_selected_bit_clear_keeps_tima()
+    {
+        let mut timer = timer_with_tac(0x05);
+        timer.tick(4);
+
+        timer.write_register(0xFF04, 0x00);
+
+        assert_eq!(0, timer.read_register(0xFF05));
+    }
+
+    #[test]
+    fn disabling_timer_with_selected_bit_set_increments_tima()
+    {
+        let mut timer = timer_with_tac(0x05);
+        timer.tick(8);
+
+        timer.write_register(0xFF07, 0x01);
+
+        assert_eq!(1, timer.read_register(0xFF05));
+        assert_eq!(0xF9, timer.read_register(0xFF07));
+    }
+}
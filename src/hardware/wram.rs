@@ -0,0 +1,209 @@
+use super::save_state::{Snapshot, StateWriter, StateReader, StateError};
+
+/*
+
+Work RAM. The DMG has two fixed 4KB banks, the CGB has eight. Bank 0 is
+always mapped to C000 - CFFF, D000 - DFFF shows the bank selected by
+SVBK (0xFF70, bits 0-2). Selecting bank 0 maps bank 1 instead.
+E000 - FDFF mirrors C000 - DDFF, including the selected bank.
+
+*/
+
+pub const BANK_SIZE: usize = 0x1000;
+pub const BANK_COUNT: usize = 8;
+
+pub struct WorkRam
+{
+    pub cgb_mode: bool,
+    banks: [[u8; BANK_SIZE]; BANK_COUNT],
+    selected_bank: u8,
+}
+
+impl Default for WorkRam {
+    fn default() -> WorkRam
+    {
+        WorkRam {
+            cgb_mode: false,
+            banks: [[0; BANK_SIZE]; BANK_COUNT],
+            selected_bank: 0,
+        }
+    }
+}
+
+impl WorkRam
+{
+    fn switchable_bank(&self) -> usize
+    {
+        if self.cgb_mode && self.selected_bank > 0
+        {
+            self.selected_bank as usize
+        }
+        else
+        {
+            1
+        }
+    }
+
+    fn location(&self, address: u16) -> (usize, usize)
+    {
+        // Echo RAM
+        let address = if address >= 0xE000 { address - 0x2000 } else { address };
+        let offset = (address as usize) & (BANK_SIZE - 1);
+        if address < 0xD000
+        {
+            (0, offset)
+        }
+        else
+        {
+            (self.switchable_bank(), offset)
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8
+    {
+        let (bank, offset) = self.location(address);
+        self.banks[bank][offset]
+    }
+
+    pub fn write(&mut self, address: u16, value: u8)
+    {
+        let (bank, offset) = self.location(address);
+        self.banks[bank][offset] = value;
+    }
+
+    pub fn read_svbk(&self) -> u8
+    {
+        if !self.cgb_mode
+        {
+            return 0xFF;
+        }
+        0xF8 | self.selected_bank
+    }
+
+    pub fn write_svbk(&mut self, value: u8)
+    {
+        if self.cgb_mode
+        {
+            self.selected_bank = value & 0x07;
+        }
+    }
+}
+
+
+impl Snapshot for WorkRam
+{
+    fn save(&self, state: &mut StateWriter)
+    {
+        state.bool(self.cgb_mode);
+        for bank in &self.banks
+        {
+            state.bytes(bank);
+        }
+        state.u8(self.selected_bank);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>
+    {
+        self.cgb_mode = state.bool()?;
+        for bank in self.banks.iter_mut()
+        {
+            state.bytes(bank)?;
+        }
+        self.selected_bank = state.u8()? & 0x07;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn cgb_work_ram() -> WorkRam
+    {
+        WorkRam { cgb_mode: true, ..WorkRam::default() }
+    }
+
+    #[test]
+    fn svbk_selects_bank()
+    {
+        let mut wram = cgb_work_ram();
+
+        for bank in 1..8
+        {
+            wram.write_svbk(bank);
+            wram.write(0xD123, bank * 0x10);
+        }
+        wram.write_svbk(3);
+
+        assert_eq!(0x30, wram.read(0xD123));
+        assert_eq!(0xFB, wram.read_svbk());
+        assert_eq!(0x30, wram.banks[3][0x123]);
+    }
+
+    #[test]
+    fn bank_0_selects_bank_1()
+    {
+        let mut wram = cgb_work_ram();
+        wram.write_svbk(1);
+        wram.write(0xD000, 0x42);
+
+        wram.write_svbk(0);
+
+        assert_eq!(0x42, wram.read(0xD000));
+        assert_eq!(0xF8, wram.read_svbk());
+    }
+
+    #[test]
+    fn bank_0_is_fixed()
+    {
+        let mut wram = cgb_work_ram();
+        wram.write(0xC010, 0x42);
+
+        wram.write_svbk(5);
+
+        assert_eq!(0x42, wram.read(0xC010));
+    }
+
+    #[test]
+    fn echo_ram_follows_selected_bank()
+    {
+        let mut wram = cgb_work_ram();
+        wram.write_svbk(2);
+        wram.write(0xD010, 0x22);
+        wram.write_svbk(4);
+        wram.write(0xF010, 0x44);
+
+        assert_eq!(0x44, wram.read(0xD010));
+        wram.write_svbk(2);
+        assert_eq!(0x22, wram.read(0xF010));
+        assert_eq!(0x44, wram.banks[4][0x010]);
+    }
+
+    #[test]
+    fn corrupt_bank_in_state_is_masked()
+    {
+        let mut wram = cgb_work_ram();
+        let mut state = StateWriter::default();
+        wram.save(&mut state);
+        let mut data = state.into_bytes();
+        *data.last_mut().unwrap() = 200;
+
+        wram.load(&mut StateReader::new(&data)).unwrap();
+
+        assert_eq!(0xF8, wram.read_svbk());
+        assert_eq!(0x00, wram.read(0xD000));
+    }
+
+    #[test]
+    fn svbk_is_ignored_on_dmg()
+    {
+        let mut wram = WorkRam::default();
+
+        wram.write_svbk(3);
+        wram.write(0xD000, 0x42);
+
+        assert_eq!(0xFF, wram.read_svbk());
+        assert_eq!(0x42, wram.banks[1][0]);
+    }
+}
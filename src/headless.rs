@@ -0,0 +1,283 @@
+/*
+
+Runs the emulator without a window, for tests and batch jobs on machines
+without a display. It stops at whatever comes first:
+  * The given amount of frames ran
+  * The program counter reached an address, checked before every instruction
+  * A text showed up in the serial output, checked after every frame
+  * The timeout passed, in wall clock time and checked after every frame
+  * The input movie being played back ended
+
+A video can be recorded over a range of frames, with its sound into a WAV
+file of its own.
+
+*/
+
+use std::cell::RefCell;
+use std::io::Result;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioRecorder;
+use crate::hardware::apu::StereoSample;
+use crate::hardware::gameboy::GameBoy;
+use crate::movie::MoviePlayer;
+use crate::video::VideoRecorder;
+
+#[derive(Default)]
+pub struct StopConditions
+{
+    pub frames: Option<u32>,
+    pub program_counter: Option<u16>,
+    /// The text to wait for and the serial console output it shows up in
+    pub serial_text: Option<(String, Rc<RefCell<String>>)>,
+    pub timeout: Option<Duration>,
+}
+
+impl StopConditions
+{
+    /// Whether the run waits for something to happen, instead of only running for a while
+    pub fn waits_for_event(&self) -> bool
+    {
+        self.program_counter.is_some() || self.serial_text.is_some()
+    }
+}
+
+/// A video of some of the frames, with the sound of the same frames
+pub struct VideoCapture
+{
+    pub video: VideoRecorder,
+    pub audio: Option<AudioRecorder>,
+    /// The first and the last frame to record, counting from 0
+    pub frames: RangeInclusive<u32>,
+}
+
+impl VideoCapture
+{
+    fn record(&mut self, frame: u32, gameboy: &mut GameBoy, samples: &[StereoSample], channel_samples: &[[StereoSample; 4]]) -> Result<()>
+    {
+        if !self.frames.contains(&frame)
+        {
+            return Ok(());
+        }
+        self.video.record_frame(gameboy.framebuffer())?;
+        if let Some(audio) = &mut self.audio
+        {
+            audio.record(samples, channel_samples)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()>
+    {
+        self.video.finish()?;
+        if let Some(audio) = self.audio
+        {
+            audio.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome
+{
+    FramesDone,
+    ProgramCounterReached,
+    SerialTextSeen,
+    TimedOut,
+    MovieEnded,
+}
+
+fn run_until(gameboy: &mut GameBoy, conditions: &StopConditions, recorder: &mut Option<AudioRecorder>,
+             mut movie: Option<&mut MoviePlayer>, video: &mut Option<VideoCapture>) -> Result<Outcome>
+{
+    let start = Instant::now();
+    let mut frames = 0;
+    loop
+    {
+        if conditions.frames.is_some_and(|limit| frames >= limit)
+        {
+            return Ok(Outcome::FramesDone);
+        }
+        if conditions.timeout.is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            return Ok(Outcome::TimedOut);
+        }
+        if let Some(player) = &mut movie
+        {
+            if !player.play_frame(gameboy)
+            {
+                return Ok(Outcome::MovieEnded);
+            }
+        }
+
+        let reached = match conditions.program_counter {
+            Some(address) => gameboy.run_frame_until(|gameboy| gameboy.registers.pc == address),
+            None => {
+                gameboy.run_frame();
+                false
+            }
+        };
+        let samples = gameboy.apu.take_samples();
+        let channel_samples = gameboy.apu.take_channel_samples();
+        if let Some(recorder) = recorder
+        {
+            recorder.record(&samples, &channel_samples)?;
+        }
+        if let Some(video) = video
+        {
+            video.record(frames, gameboy, &samples, &channel_samples)?;
+        }
+        frames += 1;
+
+        if reached
+        {
+            return Ok(Outcome::ProgramCounterReached);
+        }
+        if let Some((text, output)) = &conditions.serial_text
+        {
+            if output.borrow().contains(text.as_str())
+            {
+                return Ok(Outcome::SerialTextSeen);
+            }
+        }
+    }
+}
+
+/// Runs until one of the conditions is met, with the input of the movie if there is one
+pub fn run(gameboy: &mut GameBoy, conditions: &StopConditions, mut recorder: Option<AudioRecorder>,
+           movie: Option<&mut MoviePlayer>, mut video: Option<VideoCapture>) -> Result<Outcome>
+{
+    for audio in recorder.iter().chain(video.iter().filter_map(|video| video.audio.as_ref()))
+    {
+        audio.enable_output(&mut gameboy.apu);
+    }
+    let outcome = run_until(gameboy, conditions, &mut recorder, movie, &mut video)?;
+    if let Some(recorder) = recorder
+    {
+        recorder.finish()?;
+    }
+    if let Some(video) = video
+    {
+        video.finish()?;
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::devices::console::SerialConsole;
+    use crate::hardware::gameboy::Model;
+    use crate::movie::MovieRecorder;
+
+    #[test]
+    fn runs_the_given_frames()
+    {
+        let mut gameboy = GameBoy::default();
+        let conditions = StopConditions { frames: Some(1), ..StopConditions::default() };
+
+        let outcome = run(&mut gameboy, &conditions, None, None, None).unwrap();
+
+        assert_eq!(Outcome::FramesDone, outcome);
+        assert_eq!(0x100 + 17556, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn stops_at_program_counter()
+    {
+        let mut gameboy = GameBoy::default();
+        let conditions = StopConditions { frames: Some(1), program_counter: Some(0x180), ..StopConditions::default() };
+
+        let outcome = run(&mut gameboy, &conditions, None, None, None).unwrap();
+
+        assert_eq!(Outcome::ProgramCounterReached, outcome);
+        assert_eq!(0x180, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn stops_when_serial_text_shows_up()
+    {
+        let mut gameboy = GameBoy::default();
+        let console = SerialConsole::new(false, None);
+        let conditions = StopConditions {
+            frames: Some(1),
+            serial_text: Some(("ok".to_string(), console.text())),
+            ..StopConditions::default()
+        };
+        gameboy.connect_serial(Box::new(console));
+        for &byte in b"ok"
+        {
+            gameboy.write_byte(0xFF01, byte);
+            gameboy.write_byte(0xFF02, 0x81);
+        }
+
+        let outcome = run(&mut gameboy, &conditions, None, None, None).unwrap();
+
+        assert_eq!(Outcome::SerialTextSeen, outcome);
+    }
+
+    #[test]
+    fn missing_serial_text_runs_out_of_frames()
+    {
+        let mut gameboy = GameBoy::default();
+        let console = SerialConsole::new(false, None);
+        let conditions = StopConditions {
+            frames: Some(1),
+            serial_text: Some(("Passed".to_string(), console.text())),
+            ..StopConditions::default()
+        };
+        gameboy.connect_serial(Box::new(console));
+
+        assert_eq!(Outcome::FramesDone, run(&mut gameboy, &conditions, None, None, None).unwrap());
+        assert!(conditions.waits_for_event());
+    }
+
+    #[test]
+    fn stops_when_the_movie_ends()
+    {
+        let mut gameboy = GameBoy::default();
+        let mut recorder = MovieRecorder::new(&gameboy);
+        recorder.record_frame(&gameboy);
+        let movie = recorder.finish(&gameboy);
+        let mut player = MoviePlayer::start(movie, &mut gameboy).unwrap();
+        let conditions = StopConditions { frames: Some(2), ..StopConditions::default() };
+
+        let outcome = run(&mut gameboy, &conditions, None, Some(&mut player), None).unwrap();
+
+        assert_eq!(Outcome::MovieEnded, outcome);
+        assert!(player.finished());
+        assert_eq!(0x100 + 17556, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn records_video_of_the_frame_range()
+    {
+        // Jumps to itself forever
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut gameboy = GameBoy::new(&rom, Model::Cgb);
+        let path = std::env::temp_dir().join(format!("rboy-headless-{}.rgb", std::process::id()));
+        let video = VideoCapture { video: VideoRecorder::create(&path, None).unwrap(), audio: None, frames: 1..=2 };
+        let conditions = StopConditions { frames: Some(4), ..StopConditions::default() };
+
+        run(&mut gameboy, &conditions, None, None, Some(video)).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("txt")).unwrap();
+
+        assert_eq!(2 * 160 * 144 * 3, size);
+    }
+
+    #[test]
+    fn times_out()
+    {
+        let mut gameboy = GameBoy::default();
+        let conditions = StopConditions { timeout: Some(Duration::from_secs(0)), ..StopConditions::default() };
+
+        assert_eq!(Outcome::TimedOut, run(&mut gameboy, &conditions, None, None, None).unwrap());
+    }
+}
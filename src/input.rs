@@ -0,0 +1,475 @@
+extern crate piston_window;
+
+use std::collections::HashSet;
+
+use piston_window::Key;
+
+use rboy::Button;
+
+/*
+
+Keyboard mapping for the joypad. The defaults are:
+  * Arrow keys: D-pad
+  * X:          A
+  * Z:          B
+  * Return:     Start
+  * Backspace:  Select
+
+Bindings can be changed with "<button>=<key>", e.g. "a=j" or "start=space".
+The keys of the frontend, like F2 to save a state or R to rewind, can't be
+bound.
+
+Controllers come from the gamepad module and number their buttons like the
+SDL game controller layout. The defaults are:
+  * Buttons 11-14: D-pad
+  * Left stick (axis 0 and 1): D-pad, once it is pushed past the threshold
+  * Button 1 (east): A
+  * Button 0 (south): B
+  * Button 4 (back): Select
+  * Button 6 (start): Start
+
+Controller bindings are changed with "<button>=<number>", e.g. "a=2".
+
+Every input source keeps its own set of held buttons, so releasing a key
+does not release the same button held on a controller. Controllers can be
+plugged in and out while running, unplugging one releases everything it
+held. Losing the window focus releases everything, the key releases would
+get lost otherwise.
+
+*/
+
+pub const DEFAULT_STICK_THRESHOLD: f64 = 0.5;
+
+// Starts and stops recording the sound into a WAV file
+pub const RECORD_AUDIO_KEY: Key = Key::F9;
+// Starts and stops recording a video, with the sound into a WAV file next to it
+pub const RECORD_VIDEO_KEY: Key = Key::F10;
+// Saves the screen as a PNG file
+pub const SCREENSHOT_KEY: Key = Key::F12;
+pub const FULLSCREEN_KEY: Key = Key::F11;
+// Speed controls, fast forward only lasts while the key is held
+pub const FAST_FORWARD_KEY: Key = Key::Tab;
+pub const SLOW_MOTION_KEY: Key = Key::M;
+pub const PAUSE_KEY: Key = Key::P;
+pub const FRAME_ADVANCE_KEY: Key = Key::N;
+// Save states, into the selected slot
+pub const SAVE_STATE_KEY: Key = Key::F2;
+pub const LOAD_STATE_KEY: Key = Key::F4;
+pub const PREVIOUS_SLOT_KEY: Key = Key::F6;
+pub const NEXT_SLOT_KEY: Key = Key::F7;
+// Plays the game backwards while held
+pub const REWIND_KEY: Key = Key::R;
+
+// Keys of the frontend, they can't be bound to buttons. Escape closes the window.
+const HOTKEYS: [Key; 14] = [
+    RECORD_AUDIO_KEY, RECORD_VIDEO_KEY, SCREENSHOT_KEY, FULLSCREEN_KEY,
+    FAST_FORWARD_KEY, SLOW_MOTION_KEY, PAUSE_KEY, FRAME_ADVANCE_KEY,
+    SAVE_STATE_KEY, LOAD_STATE_KEY, PREVIOUS_SLOT_KEY, NEXT_SLOT_KEY,
+    REWIND_KEY, Key::Escape,
+];
+
+pub struct KeyMapping
+{
+    bindings: Vec<(Key, Button)>,
+}
+
+impl Default for KeyMapping {
+    fn default() -> KeyMapping
+    {
+        KeyMapping {
+            bindings: vec![
+                (Key::Right, Button::Right),
+                (Key::Left, Button::Left),
+                (Key::Up, Button::Up),
+                (Key::Down, Button::Down),
+                (Key::X, Button::A),
+                (Key::Z, Button::B),
+                (Key::Backspace, Button::Select),
+                (Key::Return, Button::Start),
+            ],
+        }
+    }
+}
+
+impl KeyMapping
+{
+    pub fn button_for(&self, key: Key) -> Option<Button>
+    {
+        self.bindings.iter()
+            .find(|(bound_key, _)| *bound_key == key)
+            .map(|(_, button)| *button)
+    }
+
+    /// Binds a key to a button, replacing the previous key of that button
+    pub fn bind(&mut self, button: Button, key: Key)
+    {
+        self.bindings.retain(|(bound_key, bound_button)| *bound_button != button && *bound_key != key);
+        self.bindings.push((key, button));
+    }
+
+    /// Applies a binding like "a=j"
+    pub fn apply(&mut self, binding: &str) -> Result<(), String>
+    {
+        let mut parts = binding.splitn(2, '=');
+        let button_name = parts.next().unwrap_or("");
+        let key_name = parts.next().ok_or(format!("Key binding {} is not <button>=<key>", binding))?;
+        let button = Button::from_name(button_name).ok_or(format!("Unknown button {}", button_name))?;
+        let key = parse_key(key_name).ok_or(format!("Unknown key {}", key_name))?;
+        if HOTKEYS.contains(&key)
+        {
+            return Err(format!("Key {} is already used by the frontend", key_name));
+        }
+        self.bind(button, key);
+        Ok(())
+    }
+}
+
+pub fn parse_key(name: &str) -> Option<Key>
+{
+    let name = name.to_lowercase();
+    let key = match name.as_str() {
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "return" | "enter" => Key::Return,
+        "space" => Key::Space,
+        "backspace" => Key::Backspace,
+        "tab" => Key::Tab,
+        "lshift" => Key::LShift,
+        "rshift" => Key::RShift,
+        "lctrl" => Key::LCtrl,
+        "rctrl" => Key::RCtrl,
+        "lalt" => Key::LAlt,
+        "ralt" => Key::RAlt,
+        _ => {
+            let mut characters = name.chars();
+            match (characters.next(), characters.next()) {
+                // Letters and digits use their ASCII code
+                (Some(character), None) if character.is_ascii_alphanumeric() => Key::from(character as u32),
+                (Some('f'), Some(_)) => match name[1..].parse::<u32>() {
+                    Ok(number) if (1..=12).contains(&number) => Key::from(Key::F1 as u32 + number - 1),
+                    _ => return None
+                },
+                _ => return None
+            }
+        }
+    };
+    Some(key)
+}
+
+pub struct ControllerMapping
+{
+    bindings: Vec<(u8, Button)>,
+    pub stick_threshold: f64,
+}
+
+impl Default for ControllerMapping {
+    fn default() -> ControllerMapping
+    {
+        ControllerMapping {
+            bindings: vec![
+                (14, Button::Right),
+                (13, Button::Left),
+                (11, Button::Up),
+                (12, Button::Down),
+                (1, Button::A),
+                (0, Button::B),
+                (4, Button::Select),
+                (6, Button::Start),
+            ],
+            stick_threshold: DEFAULT_STICK_THRESHOLD,
+        }
+    }
+}
+
+impl ControllerMapping
+{
+    pub fn button_for(&self, number: u8) -> Option<Button>
+    {
+        self.bindings.iter()
+            .find(|(bound_number, _)| *bound_number == number)
+            .map(|(_, button)| *button)
+    }
+
+    /// Binds a controller button to a joypad button, replacing the previous binding of that button
+    pub fn bind(&mut self, button: Button, number: u8)
+    {
+        self.bindings.retain(|(bound_number, bound_button)| *bound_button != button && *bound_number != number);
+        self.bindings.push((number, button));
+    }
+
+    /// Applies a binding like "a=2"
+    pub fn apply(&mut self, binding: &str) -> Result<(), String>
+    {
+        let mut parts = binding.splitn(2, '=');
+        let button_name = parts.next().unwrap_or("");
+        let number = parts.next().ok_or(format!("Controller binding {} is not <button>=<number>", binding))?;
+        let button = Button::from_name(button_name).ok_or(format!("Unknown button {}", button_name))?;
+        let number = number.parse::<u8>().map_err(|_| format!("Unknown controller button {}", number))?;
+        self.bind(button, number);
+        Ok(())
+    }
+
+    pub fn button_changes(&self, id: usize, number: u8, pressed: bool) -> Vec<InputChange>
+    {
+        self.button_for(number)
+            .map(|button| vec![(InputSource::ControllerButton(id), button, pressed)])
+            .unwrap_or_default()
+    }
+
+    /// Turns the left stick into D-pad presses, other axes are ignored
+    pub fn axis_changes(&self, id: usize, axis: u8, position: f32) -> Vec<InputChange>
+    {
+        let (negative, positive) = match axis {
+            0 => (Button::Left, Button::Right),
+            1 => (Button::Up, Button::Down),
+            _ => return vec![]
+        };
+        let position = position as f64;
+        let source = InputSource::ControllerStick(id);
+        vec![
+            (source, negative, position <= -self.stick_threshold),
+            (source, positive, position >= self.stick_threshold),
+        ]
+    }
+}
+
+/// What happened on a controller, with the buttons and axes numbered like the SDL layout
+#[derive(Clone, Debug, PartialEq)]
+// Only the gamepad feature reads controllers
+#[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+pub enum ControllerEvent
+{
+    Connected(usize, String),
+    Disconnected(usize),
+    Button(usize, u8, bool),
+    /// Axis position from -1 to 1, right and down are positive
+    Axis(usize, u8, f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InputSource
+{
+    Keyboard,
+    ControllerButton(usize),
+    ControllerStick(usize),
+}
+
+pub type InputChange = (InputSource, Button, bool);
+
+#[derive(Default)]
+pub struct InputState
+{
+    held: HashSet<(InputSource, Button)>,
+    controllers: HashSet<usize>,
+}
+
+impl InputState
+{
+    pub fn is_held(&self, button: Button) -> bool
+    {
+        self.held.iter().any(|(_, held_button)| *held_button == button)
+    }
+
+    /// Updates a button of one source. Returns the new state of the
+    /// button if it changed when combining all sources.
+    pub fn set(&mut self, change: InputChange) -> Option<bool>
+    {
+        let (source, button, pressed) = change;
+        let was_held = self.is_held(button);
+        if pressed
+        {
+            self.held.insert((source, button));
+        }
+        else
+        {
+            self.held.remove(&(source, button));
+        }
+        let held = self.is_held(button);
+        if held != was_held { Some(held) } else { None }
+    }
+
+    /// Releases everything. Returns the buttons that were held.
+    pub fn release_all(&mut self) -> Vec<Button>
+    {
+        let mut buttons: Vec<Button> = self.held.drain().map(|(_, button)| button).collect();
+        buttons.sort_by_key(|button| *button as u8);
+        buttons.dedup();
+        buttons
+    }
+
+    /// Remembers a connected controller. Returns true if it was not connected before.
+    pub fn connect(&mut self, id: usize) -> bool
+    {
+        self.controllers.insert(id)
+    }
+
+    /// Forgets a controller and releases everything it held.
+    /// Returns false if it was not connected.
+    pub fn disconnect(&mut self, id: usize) -> bool
+    {
+        self.held.retain(|(source, _)| *source != InputSource::ControllerButton(id) && *source != InputSource::ControllerStick(id));
+        self.controllers.remove(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn default_mapping()
+    {
+        let mapping = KeyMapping::default();
+
+        assert_eq!(Some(Button::A), mapping.button_for(Key::X));
+        assert_eq!(Some(Button::Up), mapping.button_for(Key::Up));
+        assert_eq!(None, mapping.button_for(Key::Q));
+    }
+
+    #[test]
+    fn rebinding_replaces_old_key()
+    {
+        let mut mapping = KeyMapping::default();
+
+        mapping.apply("a=j").unwrap();
+
+        assert_eq!(Some(Button::A), mapping.button_for(Key::J));
+        assert_eq!(None, mapping.button_for(Key::X));
+    }
+
+    #[test]
+    fn rebinding_a_used_key_moves_it()
+    {
+        let mut mapping = KeyMapping::default();
+
+        mapping.apply("start=x").unwrap();
+
+        assert_eq!(Some(Button::Start), mapping.button_for(Key::X));
+        assert_eq!(None, mapping.button_for(Key::Return));
+    }
+
+    #[test]
+    fn hotkeys_cannot_be_bound()
+    {
+        let mut mapping = KeyMapping::default();
+
+        let rewind = mapping.apply("a=r");
+        let fast_forward = mapping.apply("select=tab");
+
+        assert!(rewind.is_err());
+        assert!(fast_forward.is_err());
+        assert_eq!(Some(Button::A), mapping.button_for(Key::X));
+        assert!(HOTKEYS.iter().all(|&key| KeyMapping::default().button_for(key).is_none()));
+    }
+
+    #[test]
+    fn invalid_bindings()
+    {
+        let mut mapping = KeyMapping::default();
+
+        assert!(mapping.apply("a").is_err());
+        assert!(mapping.apply("turbo=x").is_err());
+        assert!(mapping.apply("a=f13").is_err());
+    }
+
+    #[test]
+    fn controller_rebinding()
+    {
+        let mut mapping = ControllerMapping::default();
+
+        mapping.apply("a=2").unwrap();
+
+        assert_eq!(Some(Button::A), mapping.button_for(2));
+        assert_eq!(None, mapping.button_for(1));
+        assert!(mapping.apply("a=x").is_err());
+        assert!(mapping.apply("a=256").is_err());
+    }
+
+    #[test]
+    fn stick_needs_to_pass_threshold()
+    {
+        let mapping = ControllerMapping::default();
+
+        let changes = mapping.axis_changes(0, 0, 0.4);
+        assert!(changes.iter().all(|(_, _, pressed)| !pressed));
+
+        let changes = mapping.axis_changes(0, 1, -0.6);
+        assert!(changes.contains(&(InputSource::ControllerStick(0), Button::Up, true)));
+        assert!(changes.contains(&(InputSource::ControllerStick(0), Button::Down, false)));
+    }
+
+    #[test]
+    fn other_axes_are_ignored()
+    {
+        let mapping = ControllerMapping::default();
+
+        assert!(mapping.axis_changes(0, 3, 1.0).is_empty());
+    }
+
+    #[test]
+    fn button_stays_held_while_another_source_holds_it()
+    {
+        let mut state = InputState::default();
+        assert_eq!(Some(true), state.set((InputSource::Keyboard, Button::A, true)));
+        assert_eq!(None, state.set((InputSource::ControllerButton(0), Button::A, true)));
+
+        assert_eq!(None, state.set((InputSource::Keyboard, Button::A, false)));
+        assert_eq!(Some(false), state.set((InputSource::ControllerButton(0), Button::A, false)));
+    }
+
+    #[test]
+    fn release_all_returns_held_buttons_once()
+    {
+        let mut state = InputState::default();
+        state.set((InputSource::Keyboard, Button::Up, true));
+        state.set((InputSource::ControllerStick(0), Button::Up, true));
+        state.set((InputSource::ControllerButton(0), Button::Start, true));
+
+        assert_eq!(vec![Button::Up, Button::Start], state.release_all());
+        assert!(!state.is_held(Button::Up));
+    }
+
+    #[test]
+    fn controllers_connect_once()
+    {
+        let mut state = InputState::default();
+
+        assert!(state.connect(3));
+        assert!(!state.connect(3));
+    }
+
+    #[test]
+    fn disconnecting_releases_what_the_controller_held()
+    {
+        let mapping = ControllerMapping::default();
+        let mut state = InputState::default();
+        state.connect(0);
+        state.connect(1);
+        for change in mapping.button_changes(0, 1, true).into_iter()
+            .chain(mapping.axis_changes(0, 0, -1.0))
+            .chain(mapping.button_changes(1, 6, true))
+        {
+            state.set(change);
+        }
+
+        assert!(state.disconnect(0));
+
+        assert!(!state.is_held(Button::A));
+        assert!(!state.is_held(Button::Left));
+        assert!(state.is_held(Button::Start));
+        assert!(!state.disconnect(0));
+    }
+
+    #[test]
+    fn key_names()
+    {
+        assert_eq!(Some(Key::Q), parse_key("Q"));
+        assert_eq!(Some(Key::D5), parse_key("5"));
+        assert_eq!(Some(Key::F10), parse_key("f10"));
+        assert_eq!(Some(Key::Return), parse_key("enter"));
+        assert_eq!(None, parse_key("?"));
+    }
+}
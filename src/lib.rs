@@ -0,0 +1,38 @@
+/*
+
+Game Boy and Game Boy Color emulator core, without any window or input
+handling. The rboy binary is one frontend built on top of it.
+
+The stable part of the API is re-exported here:
+  * GameBoy::new(rom, model) powers on a console with the cartridge inserted
+  * GameBoy::step() runs one instruction, GameBoy::run_frame() one frame
+  * GameBoy::set_button() presses and releases joypad buttons
+  * GameBoy::framebuffer() holds the picture as 160x144 RGB pixels
+  * GameBoy::enable_audio() and GameBoy::take_audio_samples() hand out the sound
+  * GameBoy::connect_serial() plugs a SerialDevice into the link port
+  * GameBoy::save_state() and GameBoy::load_state() take and restore snapshots
+  * movie::MovieRecorder and movie::MoviePlayer record and replay the input
+  * capture::save_screenshot() saves the screen as a PNG file
+  * video::VideoRecorder records the screen as Y4M, raw RGB or GIF
+
+The modules are public as well for tools that need the internals, but
+those can change with every version.
+
+*/
+
+pub mod audio;
+pub mod capture;
+pub mod devices;
+pub mod hardware;
+pub mod headless;
+pub mod movie;
+pub mod rewind;
+pub mod video;
+pub mod wav;
+
+pub use hardware::apu::{StereoSample, SAMPLE_RATE};
+pub use hardware::gameboy::{GameBoy, Model};
+pub use hardware::joypad::Button;
+pub use hardware::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+pub use hardware::save_state::StateError;
+pub use hardware::serial::SerialDevice;
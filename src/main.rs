@@ -1,21 +1,269 @@
+#[cfg(feature = "window")]
 mod core_loop;
-mod hardware;
+#[cfg(feature = "window")]
+mod display;
+#[cfg(feature = "window")]
+mod frame_limiter;
+#[cfg(feature = "window")]
+mod gamepad;
+#[cfg(feature = "window")]
+mod input;
+mod options;
+#[cfg(feature = "window")]
+mod save_slots;
 
 extern crate log;
 extern crate simple_logger;
 
-use log::{info};
+use std::path::{Path, PathBuf};
+
+use log::{info, error};
+
+use rboy::{audio, capture, devices, headless, video, GameBoy};
+use rboy::headless::Outcome;
+use rboy::movie::{Movie, MoviePlayer};
+use rboy::hardware::{compat_palettes, rom_loader};
 
 fn main() {
     simple_logger::init().unwrap();
 
-    let rom = hardware::rom_loader::read_rom("./roms/rom.gbc");
-    let rom_name: String = hardware::rom_loader::get_rom_name(&rom);
+    let options = match options::Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            error!("{message}", message=message);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = rom_loader::read_rom(&options.rom_path);
+    let rom_name: String = rom_loader::get_rom_name(&rom);
+
+    let mut gameboy = GameBoy::new(&rom, options.model);
 
-    let mut gameboy = hardware::gameboy::GameBoy::default();
-    gameboy.map_cartridge(&rom);
+    if let Some(buttons) = &options.palette
+    {
+        match compat_palettes::palette_for_buttons(buttons) {
+            Some(palette) => gameboy.set_compat_palette(&palette),
+            None => error!("Unknown palette {buttons}", buttons=buttons)
+        }
+    }
 
     info!("ROM Name: {name}", name=rom_name);
-    info!("ROM validity: {validity}", validity=hardware::rom_loader::check_valid(&rom));
-    core_loop::draw_loop(&rom_name, &mut gameboy);
-}
\ No newline at end of file
+    info!("ROM validity: {validity}", validity=rom_loader::check_valid(&rom));
+
+    let mut serial_output = None;
+    let link_cable = match (options.link_host, options.link_join) {
+        (Some(port), _) => Some(devices::link_cable::LinkCable::host(port)),
+        (_, Some(port)) => Some(devices::link_cable::LinkCable::join(port)),
+        _ => None
+    };
+    if let Some(link_cable) = link_cable
+    {
+        match link_cable {
+            Ok(link_cable) => gameboy.connect_serial(Box::new(link_cable)),
+            Err(message) => {
+                error!("Could not connect the link cable: {message}", message=message);
+                std::process::exit(1);
+            }
+        }
+    }
+    else if let Some(directory) = &options.printer
+    {
+        if let Err(message) = std::fs::create_dir_all(directory)
+        {
+            error!("Could not create {directory}: {message}", directory=directory, message=message);
+            std::process::exit(1);
+        }
+        gameboy.connect_serial(Box::new(devices::printer::GameBoyPrinter::new(PathBuf::from(directory))));
+    }
+    else if options.serial_console || options.serial_log.is_some() || options.until_serial.is_some()
+    {
+        let log: Option<Box<dyn std::io::Write>> = match &options.serial_log {
+            Some(path) => match std::fs::File::create(path) {
+                Ok(file) => Some(Box::new(file)),
+                Err(message) => {
+                    error!("Could not create {path}: {message}", path=path, message=message);
+                    std::process::exit(1);
+                }
+            },
+            None => None
+        };
+        let console = devices::console::SerialConsole::new(options.serial_console, log);
+        serial_output = Some(console.text());
+        gameboy.connect_serial(Box::new(console));
+    }
+
+    if let Some(path) = &options.load_state
+    {
+        let loaded = std::fs::read(path).map_err(|message| message.to_string())
+            .and_then(|state| gameboy.load_state(&state).map_err(|message| message.to_string()));
+        if let Err(message) = loaded
+        {
+            error!("Could not load {path}: {message}", path=path, message=message);
+            std::process::exit(1);
+        }
+    }
+
+    let mut movie_player = options.play_movie.as_ref().map(|path| {
+        let started = std::fs::read(path).map_err(|message| message.to_string())
+            .and_then(|data| Movie::decode(&data).map_err(|message| message.to_string()))
+            .and_then(|movie| MoviePlayer::start(movie, &mut gameboy).map_err(|message| message.to_string()));
+        match started {
+            Ok(player) => {
+                info!("Playing {frames} frames from {path}", frames=player.frames(), path=path);
+                player
+            },
+            Err(message) => {
+                error!("Could not play {path}: {message}", path=path, message=message);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let recorder = match &options.record_audio {
+        Some(path) => match audio::AudioRecorder::create(Path::new(path), options.record_channels) {
+            Ok(recorder) => Some(recorder),
+            Err(message) => {
+                error!("Could not record to {path}: {message}", path=path, message=message);
+                std::process::exit(1);
+            }
+        },
+        None => None
+    };
+
+    if options.headless()
+    {
+        let conditions = headless::StopConditions {
+            // A range of video frames ends the run after its last frame
+            frames: options.frames.or(options.video_frames.map(|(_, last)| last + 1)),
+            program_counter: options.until_pc,
+            serial_text: options.until_serial.clone().zip(serial_output),
+            timeout: options.timeout,
+        };
+        let result = headless::run(&mut gameboy, &conditions, recorder, movie_player.as_mut(), video_capture(&options));
+        // Also when the run failed, the screen tells what went wrong
+        if let Some(path) = &options.screenshot
+        {
+            if let Err(message) = capture::save_screenshot(&gameboy, Path::new(path), options.screenshot_scale)
+            {
+                error!("Could not save screenshot to {path}: {message}", path=path, message=message);
+                std::process::exit(1);
+            }
+        }
+        match result {
+            Ok(Outcome::MovieEnded) if !movie_player.as_ref().is_some_and(|player| player.in_sync(&gameboy)) => {
+                error!("Movie ended out of sync");
+                std::process::exit(1);
+            },
+            Ok(outcome) if conditions.waits_for_event() && outcome != Outcome::ProgramCounterReached && outcome != Outcome::SerialTextSeen => {
+                error!("Stopped without reaching the condition: {outcome:?}", outcome=outcome);
+                std::process::exit(1);
+            },
+            Ok(outcome) => info!("Stopped: {outcome:?}", outcome=outcome),
+            Err(message) => {
+                error!("{message}", message=message);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    run_window(&options, &rom_name, &mut gameboy, recorder, movie_player);
+}
+
+/// The video to record without a window, with the sound into a WAV file next to it
+fn video_capture(options: &options::Options) -> Option<headless::VideoCapture>
+{
+    let path = Path::new(options.record_video.as_ref()?);
+    let sound_path = path.with_extension("wav");
+    let created = video::VideoRecorder::create(path, Some(&sound_path))
+        .and_then(|video| Ok((video, audio::AudioRecorder::create(&sound_path, options.record_channels)?)));
+    match created {
+        Ok((video, audio)) => Some(headless::VideoCapture {
+            video,
+            audio: Some(audio),
+            frames: options.video_frames.map_or(0..=u32::MAX, |(first, last)| first..=last),
+        }),
+        Err(message) => {
+            error!("Could not record video to {path}: {message}", path=path.display(), message=message);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "window")]
+fn run_window(options: &options::Options, rom_name: &str, gameboy: &mut GameBoy, recorder: Option<audio::AudioRecorder>,
+              movie_player: Option<MoviePlayer>)
+{
+    let mut key_mapping = input::KeyMapping::default();
+    for binding in &options.key_bindings
+    {
+        if let Err(message) = key_mapping.apply(binding)
+        {
+            error!("{message}", message=message);
+            std::process::exit(1);
+        }
+    }
+
+    let mut controller_mapping = input::ControllerMapping::default();
+    if let Some(threshold) = options.stick_threshold
+    {
+        controller_mapping.stick_threshold = threshold;
+    }
+    for binding in &options.controller_bindings
+    {
+        if let Err(message) = controller_mapping.apply(binding)
+        {
+            error!("{message}", message=message);
+            std::process::exit(1);
+        }
+    }
+
+    let output = match audio::AudioOutput::open() {
+        Ok(output) => Some(output),
+        Err(message) => {
+            log::warn!("{message}", message=message);
+            None
+        }
+    };
+    let mut sound = core_loop::Sound::new(output, recorder, options.record_channels);
+    let gamepads = match gamepad::Gamepads::open() {
+        Ok(gamepads) => Some(gamepads),
+        Err(message) => {
+            log::warn!("{message}", message=message);
+            None
+        }
+    };
+    let mut frontend = core_loop::Frontend {
+        speed_control: frame_limiter::SpeedControl::new(options.fast_forward),
+        save_slots: save_slots::SaveSlots::new(Path::new(&options.rom_path)),
+        rewind: rboy::rewind::RewindBuffer::new(options.rewind_interval, options.rewind_depth, options.rewind_memory),
+        movie_recorder: options.record_movie.as_ref().map(|_| rboy::movie::MovieRecorder::new(gameboy)),
+        movie_player,
+        screenshot_scale: options.screenshot_scale,
+        record_video: options.record_video.as_ref().map(PathBuf::from),
+        video_format: options.video_format,
+        window_scale: options.scale,
+        fullscreen: options.fullscreen,
+        gamepads,
+        link_cable: options.link_host.is_some() || options.link_join.is_some(),
+    };
+    core_loop::draw_loop(rom_name, gameboy, &key_mapping, &controller_mapping, &mut sound, &mut frontend);
+
+    if let (Some(recorder), Some(path)) = (frontend.movie_recorder.take(), &options.record_movie)
+    {
+        let frames = recorder.frames();
+        match std::fs::write(path, recorder.finish(gameboy).encode()) {
+            Ok(()) => info!("Saved {frames} frames of input to {path}", frames=frames, path=path),
+            Err(message) => error!("Could not save the movie to {path}: {message}", path=path, message=message)
+        }
+    }
+}
+
+#[cfg(not(feature = "window"))]
+fn run_window(_options: &options::Options, _rom_name: &str, _gameboy: &mut GameBoy, _recorder: Option<audio::AudioRecorder>,
+              _movie_player: Option<MoviePlayer>)
+{
+    error!("rboy was built without the window feature, only running without one works, e.g. with --frames");
+    std::process::exit(1);
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+
+use crate::hardware::gameboy::GameBoy;
+use crate::hardware::joypad::Button;
+use crate::hardware::save_state::{StateError, StateReader, StateWriter};
+
+/*
+
+Input movies, the joypad input of every frame for replaying a run exactly.
+The emulation only depends on the ROM and the input, so the same input from
+the same state always ends in the same state.
+
+A movie starts from a save state, taken right at power on when recording
+from the start, so settings like the DMG palette come along. At the end it
+keeps a hash of the final state, a replay that ends anywhere else desynced.
+
+The file format, little endian:
+  * Magic bytes "RBOYMOVI"
+  * Format version (u16)
+  * Checksum of the ROM (u32) and the model (u8)
+  * Start state, prefixed with its length (u32)
+  * Amount of frames (u32), then one byte per frame with the pressed buttons
+  * Hash of the final state (u64)
+
+*/
+
+const MAGIC: &[u8; 8] = b"RBOYMOVI";
+const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum MovieError
+{
+    NotAMovie,
+    UnsupportedVersion(u16),
+    Truncated,
+    State(StateError),
+}
+
+impl fmt::Display for MovieError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            MovieError::NotAMovie => write!(formatter, "Not an input movie"),
+            MovieError::UnsupportedVersion(version) => write!(formatter, "Movie format {} is not supported", version),
+            MovieError::Truncated => write!(formatter, "Movie is truncated"),
+            MovieError::State(error) => write!(formatter, "Movie can not start: {}", error)
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError
+{
+    fn from(_: StateError) -> MovieError
+    {
+        // Reading the movie itself only ever runs out of data
+        MovieError::Truncated
+    }
+}
+
+/// FNV-1a hash of the whole state of the machine, without the save state header
+pub fn state_hash(gameboy: &GameBoy) -> u64
+{
+    let mut state = StateWriter::default();
+    gameboy.save_sections(&mut state);
+    state.into_bytes().iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Movie
+{
+    pub rom_checksum: u32,
+    pub model: u8,
+    pub start_state: Vec<u8>,
+    /// The pressed buttons of every frame, with the masks of Button
+    pub inputs: Vec<u8>,
+    pub final_hash: u64,
+}
+
+impl Movie
+{
+    pub fn encode(&self) -> Vec<u8>
+    {
+        let mut writer = StateWriter::default();
+        writer.bytes(MAGIC);
+        writer.u16(FORMAT_VERSION);
+        writer.u32(self.rom_checksum);
+        writer.u8(self.model);
+        writer.u32(self.start_state.len() as u32);
+        writer.bytes(&self.start_state);
+        writer.u32(self.inputs.len() as u32);
+        writer.bytes(&self.inputs);
+        writer.u64(self.final_hash);
+        writer.into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Movie, MovieError>
+    {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 8];
+        reader.bytes(&mut magic).map_err(|_| MovieError::NotAMovie)?;
+        if &magic != MAGIC
+        {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = reader.u16()?;
+        if version > FORMAT_VERSION
+        {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_checksum = reader.u32()?;
+        let model = reader.u8()?;
+        // The lengths are checked against the data before anything gets allocated
+        let start_state_length = reader.u32()? as usize;
+        let start_state = reader.slice(start_state_length)?.to_vec();
+        let inputs_length = reader.u32()? as usize;
+        let inputs = reader.slice(inputs_length)?.to_vec();
+        Ok(Movie { rom_checksum, model, start_state, inputs, final_hash: reader.u64()? })
+    }
+}
+
+pub struct MovieRecorder
+{
+    movie: Movie,
+}
+
+impl MovieRecorder
+{
+    /// Starts recording from the current state of the machine
+    pub fn new(gameboy: &GameBoy) -> MovieRecorder
+    {
+        MovieRecorder {
+            movie: Movie {
+                rom_checksum: gameboy.rom_checksum,
+                model: gameboy.model as u8,
+                start_state: gameboy.save_state(),
+                inputs: vec![],
+                final_hash: 0,
+            }
+        }
+    }
+
+    pub fn frames(&self) -> usize
+    {
+        self.movie.inputs.len()
+    }
+
+    /// Call right before every frame
+    pub fn record_frame(&mut self, gameboy: &GameBoy)
+    {
+        self.movie.inputs.push(gameboy.pressed_buttons());
+    }
+
+    pub fn finish(mut self, gameboy: &GameBoy) -> Movie
+    {
+        self.movie.final_hash = state_hash(gameboy);
+        self.movie
+    }
+}
+
+pub struct MoviePlayer
+{
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer
+{
+    /// Puts the machine into the start state of the movie
+    pub fn start(movie: Movie, gameboy: &mut GameBoy) -> Result<MoviePlayer, MovieError>
+    {
+        gameboy.load_state(&movie.start_state).map_err(MovieError::State)?;
+        Ok(MoviePlayer { movie, frame: 0 })
+    }
+
+    pub fn frames(&self) -> usize
+    {
+        self.movie.inputs.len()
+    }
+
+    pub fn finished(&self) -> bool
+    {
+        self.frame >= self.movie.inputs.len()
+    }
+
+    /// Call right before every frame, presses the buttons of the frame.
+    /// False once the movie is over.
+    pub fn play_frame(&mut self, gameboy: &mut GameBoy) -> bool
+    {
+        let pressed = match self.movie.inputs.get(self.frame) {
+            Some(&pressed) => pressed,
+            None => return false
+        };
+        for &button in Button::ALL.iter()
+        {
+            gameboy.set_button(button, pressed & button.mask() > 0);
+        }
+        self.frame += 1;
+        true
+    }
+
+    /// Whether the machine ended up in the same state as when the movie was recorded
+    pub fn in_sync(&self, gameboy: &GameBoy) -> bool
+    {
+        state_hash(gameboy) == self.movie.final_hash
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn record(gameboy: &mut GameBoy, inputs: &[Button]) -> Movie
+    {
+        let mut recorder = MovieRecorder::new(gameboy);
+        for &button in inputs
+        {
+            for &other in Button::ALL.iter()
+            {
+                gameboy.set_button(other, other == button);
+            }
+            recorder.record_frame(gameboy);
+            gameboy.step();
+        }
+        recorder.finish(gameboy)
+    }
+
+    fn play(gameboy: &mut GameBoy, movie: Movie) -> MoviePlayer
+    {
+        let mut player = MoviePlayer::start(movie, gameboy).unwrap();
+        while player.play_frame(gameboy)
+        {
+            gameboy.step();
+        }
+        player
+    }
+
+    #[test]
+    fn file_round_trip()
+    {
+        let movie = record(&mut GameBoy::default(), &[Button::A, Button::Start]);
+
+        let decoded = Movie::decode(&movie.encode()).unwrap();
+
+        assert_eq!(movie, decoded);
+        assert_eq!(vec![Button::A.mask(), Button::Start.mask()], decoded.inputs);
+    }
+
+    #[test]
+    fn replay_ends_in_sync()
+    {
+        let mut gameboy = GameBoy::default();
+        let movie = record(&mut gameboy, &[Button::A, Button::B, Button::Down]);
+
+        let mut replay = GameBoy::default();
+        let player = play(&mut replay, movie);
+
+        assert!(player.finished());
+        assert_eq!(3, player.frames());
+        assert!(player.in_sync(&replay));
+        assert_eq!(state_hash(&gameboy), state_hash(&replay));
+    }
+
+    #[test]
+    fn replay_starts_from_the_recorded_state()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.step();
+        let movie = record(&mut gameboy, &[Button::Up]);
+
+        let mut replay = GameBoy::default();
+        let player = play(&mut replay, movie);
+
+        assert_eq!(0x102, replay.registers.pc);
+        assert!(player.in_sync(&replay));
+    }
+
+    #[test]
+    fn replay_without_sound_stays_in_sync()
+    {
+        let mut gameboy = GameBoy::default();
+        gameboy.enable_audio(true);
+        let movie = record(&mut gameboy, &[Button::A, Button::B, Button::Down]);
+
+        let mut replay = GameBoy::default();
+        let player = play(&mut replay, movie);
+
+        assert!(player.in_sync(&replay));
+    }
+
+    #[test]
+    fn desync_is_detected()
+    {
+        let mut movie = record(&mut GameBoy::default(), &[Button::A]);
+        movie.final_hash ^= 1;
+
+        let mut replay = GameBoy::default();
+        let player = play(&mut replay, movie);
+
+        assert!(!player.in_sync(&replay));
+    }
+
+    #[test]
+    fn invalid_files_are_rejected()
+    {
+        let data = record(&mut GameBoy::default(), &[]).encode();
+
+        assert_eq!(Some(MovieError::NotAMovie), Movie::decode(b"RBOYSTAT").err());
+        assert_eq!(Some(MovieError::Truncated), Movie::decode(&data[..data.len() - 1]).err());
+    }
+
+    #[test]
+    fn huge_lengths_are_truncated()
+    {
+        let mut data = record(&mut GameBoy::default(), &[]).encode();
+        // The length of the start state follows the magic, version, ROM checksum and model
+        data[15..19].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(Some(MovieError::Truncated), Movie::decode(&data).err());
+    }
+}
@@ -0,0 +1,568 @@
+/*
+
+Command line options:
+  rboy [--model <dmg|cgb>] [--palette <buttons>] [--scale <1-8>] [--fullscreen]
+       [--key <button>=<key>]...
+       [--pad <button>=<number>]... [--stick-threshold <0-1>]
+       [--fast-forward <speed|max>] [--load-state <file>]
+       [--rewind-depth <states>] [--rewind-interval <frames>]
+       [--rewind-memory <megabytes>]
+       [--record-movie <file> | --play-movie <file>]
+       [--screenshot <png>] [--screenshot-scale <1-8>]
+       [--record-video <file>] [--video-frames <first>-<last>]
+       [--video-format <y4m|rgb|gif>]
+       [--record-audio <wav>] [--record-channels] [--frames <count>]
+       [--until-pc <address>] [--until-serial <text>] [--timeout <seconds>]
+       [--serial-console] [--serial-log <file>]
+       [--link-host <port> | --link-join <port> | --printer <directory>] [rom]
+
+  --model:   Emulated hardware, defaults to cgb
+  --palette: Colorizes DMG games with the palette selected by the given
+             button combination on the CGB boot logo, e.g. "up+a". Only
+             for the cgb model.
+  --scale:   Size of the window as a multiple of the screen, defaults to 3.
+             Resizing the window keeps the pixels square and the picture
+             centered.
+  --fullscreen:
+             Starts in fullscreen, F11 switches back and forth
+  --key:     Binds a keyboard key to a joypad button, e.g. "a=j"
+  --pad:     Binds a controller button to a joypad button, e.g. "a=2", with
+             the buttons numbered like the SDL game controller layout.
+             Controllers need rboy built with the gamepad feature.
+  --stick-threshold:
+             How far the analog stick has to be pushed to press the D-pad,
+             defaults to 0.5
+  --fast-forward:
+             Speed while the fast forward key is held, e.g. "4" for four
+             times the normal speed. Defaults to max, which is uncapped.
+             The sound skips frames to keep its pitch, uncapped it is muted.
+  --load-state:
+             Starts from the given save state
+  --rewind-depth:
+             How many states are kept for rewinding, defaults to 600. 0
+             turns rewinding off.
+  --rewind-interval:
+             Frames between the states kept for rewinding, defaults to 2
+  --rewind-memory:
+             Most memory the rewind states take, defaults to 32 MB
+  --record-movie:
+             Records the joypad input of every frame into a movie, saved
+             when the window closes
+  --play-movie:
+             Replays a movie and checks that it ends in the recorded state.
+             Without a window the run stops at the end of the movie and
+             exits with an error if it desynced.
+  --screenshot:
+             Saves the screen as a PNG file when a run without a window stops
+  --screenshot-scale:
+             Scales screenshots up by the given factor, defaults to 1
+  --record-video:
+             Records a video from the start, as Y4M, raw RGB or GIF by the
+             extension of the file, e.g. "clip.gif". The sound goes into a
+             WAV file next to it.
+  --video-frames:
+             Runs without a window and only records the given frames into
+             the video, e.g. "600-1200"
+  --video-format:
+             Format of the videos started with F10, defaults to gif
+  --record-audio:
+             Records the sound into a WAV file from the start
+  --record-channels:
+             Records every sound channel into its own WAV file as well
+  --frames:  Runs the given amount of frames without a window and exits
+  --until-pc:
+             Runs without a window until the program counter reaches the
+             given hex address, e.g. "0x0150"
+  --until-serial:
+             Runs without a window until the given text was sent over the
+             link port. Exits with an error if the run stops before that.
+  --timeout: Runs without a window for at most the given amount of seconds
+  --serial-console:
+             Prints everything sent over the link port, for test ROMs
+  --serial-log:
+             Writes everything sent over the link port into a file
+  --link-host:
+             Waits for another instance to connect a link cable on the
+             given port of localhost
+  --link-join:
+             Connects a link cable to the instance hosting on the given port.
+             Pausing, rewinding and loading states are off with a cable.
+  --printer: Plugs in a Game Boy Printer, which saves the printed pictures
+             as PNG files into the given directory
+
+*/
+
+use std::path::Path;
+use std::time::Duration;
+
+use rboy::{capture, rewind, Model};
+use rboy::video::VideoFormat;
+
+const DEFAULT_ROM_PATH: &str = "./roms/rom.gbc";
+const DEFAULT_SCALE: u32 = 3;
+const MAX_SCALE: u32 = 8;
+
+pub struct Options
+{
+    pub rom_path: String,
+    pub model: Model,
+    pub palette: Option<String>,
+    pub scale: u32,
+    pub fullscreen: bool,
+    pub key_bindings: Vec<String>,
+    pub controller_bindings: Vec<String>,
+    pub stick_threshold: Option<f64>,
+    // None is uncapped
+    pub fast_forward: Option<f64>,
+    pub load_state: Option<String>,
+    pub rewind_depth: usize,
+    pub rewind_interval: u32,
+    // In bytes
+    pub rewind_memory: usize,
+    pub record_movie: Option<String>,
+    pub play_movie: Option<String>,
+    pub screenshot: Option<String>,
+    pub screenshot_scale: u32,
+    pub record_video: Option<String>,
+    pub video_frames: Option<(u32, u32)>,
+    pub video_format: VideoFormat,
+    pub record_audio: Option<String>,
+    pub record_channels: bool,
+    pub frames: Option<u32>,
+    pub until_pc: Option<u16>,
+    pub until_serial: Option<String>,
+    pub timeout: Option<Duration>,
+    pub serial_console: bool,
+    pub serial_log: Option<String>,
+    pub link_host: Option<u16>,
+    pub link_join: Option<u16>,
+    pub printer: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options
+    {
+        Options {
+            rom_path: DEFAULT_ROM_PATH.to_string(),
+            model: Model::Cgb,
+            palette: None,
+            scale: DEFAULT_SCALE,
+            fullscreen: false,
+            key_bindings: vec![],
+            controller_bindings: vec![],
+            stick_threshold: None,
+            fast_forward: None,
+            load_state: None,
+            rewind_depth: rewind::DEFAULT_DEPTH,
+            rewind_interval: rewind::DEFAULT_INTERVAL,
+            rewind_memory: rewind::DEFAULT_MEMORY_BUDGET,
+            record_movie: None,
+            play_movie: None,
+            screenshot: None,
+            screenshot_scale: 1,
+            record_video: None,
+            video_frames: None,
+            video_format: VideoFormat::Gif,
+            record_audio: None,
+            record_channels: false,
+            frames: None,
+            until_pc: None,
+            until_serial: None,
+            timeout: None,
+            serial_console: false,
+            serial_log: None,
+            link_host: None,
+            link_join: None,
+            printer: None,
+        }
+    }
+}
+
+impl Options
+{
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String>
+    {
+        let mut options = Options::default();
+        while let Some(argument) = args.next()
+        {
+            match argument.as_str() {
+                "--model" => options.model = match args.next().as_deref() {
+                    Some("dmg") => Model::Dmg,
+                    Some("cgb") => Model::Cgb,
+                    _ => return Err("--model needs to be dmg or cgb".to_string())
+                },
+                "--palette" => options.palette = Some(args.next().ok_or("--palette needs a button combination")?),
+                "--scale" => options.scale = match args.next().and_then(|value| value.parse::<u32>().ok()) {
+                    Some(scale) if (1..=MAX_SCALE).contains(&scale) => scale,
+                    _ => return Err(format!("--scale needs a factor between 1 and {}", MAX_SCALE))
+                },
+                "--fullscreen" => options.fullscreen = true,
+                "--key" => options.key_bindings.push(args.next().ok_or("--key needs a <button>=<key> binding")?),
+                "--pad" => options.controller_bindings.push(args.next().ok_or("--pad needs a <button>=<number> binding")?),
+                "--stick-threshold" => options.stick_threshold = match args.next().and_then(|value| value.parse::<f64>().ok()) {
+                    Some(threshold) if threshold > 0.0 && threshold <= 1.0 => Some(threshold),
+                    _ => return Err("--stick-threshold needs a number between 0 and 1".to_string())
+                },
+                "--fast-forward" => options.fast_forward = match args.next().as_deref() {
+                    Some("max") => None,
+                    Some(value) => match value.parse::<f64>() {
+                        Ok(speed) if speed >= 1.0 => Some(speed),
+                        _ => return Err("--fast-forward needs a speed of at least 1 or max".to_string())
+                    },
+                    None => return Err("--fast-forward needs a speed of at least 1 or max".to_string())
+                },
+                "--load-state" => options.load_state = Some(args.next().ok_or("--load-state needs a file name")?),
+                "--rewind-depth" => options.rewind_depth = args.next()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .ok_or("--rewind-depth needs a number of states")?,
+                "--rewind-interval" => options.rewind_interval = match args.next().and_then(|value| value.parse::<u32>().ok()) {
+                    Some(frames) if frames > 0 => frames,
+                    _ => return Err("--rewind-interval needs a number of frames".to_string())
+                },
+                "--rewind-memory" => options.rewind_memory = args.next()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .map(|megabytes| megabytes * 1024 * 1024)
+                    .ok_or("--rewind-memory needs a number of megabytes")?,
+                "--record-movie" => options.record_movie = Some(args.next().ok_or("--record-movie needs a file name")?),
+                "--play-movie" => options.play_movie = Some(args.next().ok_or("--play-movie needs a file name")?),
+                "--screenshot" => options.screenshot = Some(args.next().ok_or("--screenshot needs a file name")?),
+                "--screenshot-scale" => options.screenshot_scale = match args.next().and_then(|value| value.parse::<u32>().ok()) {
+                    Some(scale) if (1..=capture::MAX_SCALE).contains(&scale) => scale,
+                    _ => return Err(format!("--screenshot-scale needs a factor between 1 and {}", capture::MAX_SCALE))
+                },
+                "--record-video" => options.record_video = Some(args.next()
+                    .filter(|value| VideoFormat::from_path(Path::new(value)).is_some())
+                    .ok_or("--record-video needs a .y4m, .rgb or .gif file name")?),
+                "--video-frames" => options.video_frames = match args.next().as_ref().and_then(|value| value.split_once('-')) {
+                    Some((first, last)) => match (first.parse::<u32>(), last.parse::<u32>()) {
+                        // The run stops after the last frame, which has to be countable
+                        (Ok(first), Ok(last)) if first <= last && last < u32::MAX => Some((first, last)),
+                        _ => return Err("--video-frames needs a range of frames like 600-1200".to_string())
+                    },
+                    None => return Err("--video-frames needs a range of frames like 600-1200".to_string())
+                },
+                "--video-format" => options.video_format = args.next()
+                    .and_then(|value| VideoFormat::from_path(Path::new(&format!("video.{}", value))))
+                    .ok_or("--video-format needs to be y4m, rgb or gif")?,
+                "--record-audio" => options.record_audio = Some(args.next().ok_or("--record-audio needs a file name")?),
+                "--record-channels" => options.record_channels = true,
+                "--frames" => options.frames = Some(args.next()
+                    .and_then(|value| value.parse::<u32>().ok())
+                    .ok_or("--frames needs a number of frames")?),
+                "--until-pc" => options.until_pc = Some(args.next()
+                    .and_then(|value| u16::from_str_radix(value.trim_start_matches("0x"), 16).ok())
+                    .ok_or("--until-pc needs a hex address")?),
+                "--until-serial" => options.until_serial = Some(args.next().ok_or("--until-serial needs a text")?),
+                "--timeout" => options.timeout = match args.next().and_then(|value| value.parse::<f64>().ok()) {
+                    // Rejects negative, infinite and too long timeouts
+                    Some(seconds) => Some(Duration::try_from_secs_f64(seconds).map_err(|_| "--timeout needs a number of seconds".to_string())?),
+                    None => return Err("--timeout needs a number of seconds".to_string())
+                },
+                "--serial-console" => options.serial_console = true,
+                "--serial-log" => options.serial_log = Some(args.next().ok_or("--serial-log needs a file name")?),
+                "--link-host" => options.link_host = Some(args.next()
+                    .and_then(|value| value.parse::<u16>().ok())
+                    .ok_or("--link-host needs a port")?),
+                "--link-join" => options.link_join = Some(args.next()
+                    .and_then(|value| value.parse::<u16>().ok())
+                    .ok_or("--link-join needs a port")?),
+                "--printer" => options.printer = Some(args.next().ok_or("--printer needs a directory")?),
+                _ if argument.starts_with("--") => return Err(format!("Unknown option {}", argument)),
+                _ => options.rom_path = argument
+            }
+        }
+        if [options.link_host.is_some(), options.link_join.is_some(), options.printer.is_some()].iter().filter(|&&used| used).count() > 1
+        {
+            return Err("Only one of --link-host, --link-join and --printer can be used".to_string());
+        }
+        if options.palette.is_some() && options.model == Model::Dmg
+        {
+            return Err("--palette needs the cgb model, a DMG has no colors".to_string());
+        }
+        let link_port_used = options.link_host.is_some() || options.link_join.is_some() || options.printer.is_some();
+        if options.until_serial.is_some() && link_port_used
+        {
+            return Err("--until-serial needs the link port for the serial console".to_string());
+        }
+        if (options.serial_console || options.serial_log.is_some()) && link_port_used
+        {
+            return Err("--serial-console and --serial-log need the link port, it can not have a cable or printer at the same time".to_string());
+        }
+        if options.video_frames.is_some() && options.record_video.is_none()
+        {
+            return Err("--video-frames needs --record-video".to_string());
+        }
+        if options.screenshot.is_some() && !options.headless()
+        {
+            return Err("--screenshot is for runs without a window, the window saves screenshots with F12".to_string());
+        }
+        if options.record_movie.is_some() && options.play_movie.is_some()
+        {
+            return Err("Only one of --record-movie and --play-movie can be used".to_string());
+        }
+        if options.record_movie.is_some() && options.headless()
+        {
+            return Err("--record-movie needs the window for input".to_string());
+        }
+        if (options.record_movie.is_some() || options.play_movie.is_some()) && (options.link_host.is_some() || options.link_join.is_some())
+        {
+            return Err("Movies can not replay what comes over a link cable".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Whether to run without a window
+    pub fn headless(&self) -> bool
+    {
+        self.frames.is_some() || self.until_pc.is_some() || self.until_serial.is_some() || self.timeout.is_some()
+            || self.video_frames.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String>
+    {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_without_arguments()
+    {
+        let options = parse(&[]).unwrap();
+
+        assert_eq!(DEFAULT_ROM_PATH, options.rom_path);
+        assert_eq!(Model::Cgb, options.model);
+        assert_eq!(None, options.palette);
+    }
+
+    #[test]
+    fn palette_and_rom_path()
+    {
+        let options = parse(&["--palette", "up+a", "tetris.gb"]).unwrap();
+
+        assert_eq!("tetris.gb", options.rom_path);
+        assert_eq!(Some("up+a".to_string()), options.palette);
+    }
+
+    #[test]
+    fn dmg_model()
+    {
+        let options = parse(&["--model", "dmg"]).unwrap();
+
+        assert_eq!(Model::Dmg, options.model);
+    }
+
+    #[test]
+    fn window_scale_and_fullscreen()
+    {
+        let options = parse(&["--scale", "5", "--fullscreen"]).unwrap();
+
+        assert_eq!(5, options.scale);
+        assert!(options.fullscreen);
+        assert_eq!(DEFAULT_SCALE, parse(&[]).unwrap().scale);
+        assert!(parse(&["--scale", "0"]).is_err());
+        assert!(parse(&["--scale", "9"]).is_err());
+    }
+
+    #[test]
+    fn palette_needs_cgb()
+    {
+        assert!(parse(&["--model", "dmg", "--palette", "up+a"]).is_err());
+    }
+
+    #[test]
+    fn unknown_model()
+    {
+        assert!(parse(&["--model", "gba"]).is_err());
+    }
+
+    #[test]
+    fn multiple_key_bindings()
+    {
+        let options = parse(&["--key", "a=j", "--key", "b=k"]).unwrap();
+
+        assert_eq!(vec!["a=j", "b=k"], options.key_bindings);
+    }
+
+    #[test]
+    fn controller_bindings_and_threshold()
+    {
+        let options = parse(&["--pad", "a=2", "--stick-threshold", "0.25"]).unwrap();
+
+        assert_eq!(vec!["a=2"], options.controller_bindings);
+        assert_eq!(Some(0.25), options.stick_threshold);
+    }
+
+    #[test]
+    fn invalid_stick_threshold()
+    {
+        assert!(parse(&["--stick-threshold", "2"]).is_err());
+        assert!(parse(&["--stick-threshold", "far"]).is_err());
+    }
+
+    #[test]
+    fn headless_audio_recording()
+    {
+        let options = parse(&["--record-audio", "out.wav", "--record-channels", "--frames", "600"]).unwrap();
+
+        assert_eq!(Some("out.wav".to_string()), options.record_audio);
+        assert!(options.record_channels);
+        assert_eq!(Some(600), options.frames);
+    }
+
+    #[test]
+    fn invalid_frame_count()
+    {
+        assert!(parse(&["--frames", "-1"]).is_err());
+    }
+
+    #[test]
+    fn fast_forward_speed()
+    {
+        assert_eq!(None, parse(&[]).unwrap().fast_forward);
+        assert_eq!(Some(4.0), parse(&["--fast-forward", "4"]).unwrap().fast_forward);
+        assert_eq!(None, parse(&["--fast-forward", "max"]).unwrap().fast_forward);
+        assert!(parse(&["--fast-forward", "0.5"]).is_err());
+    }
+
+    #[test]
+    fn load_state_file()
+    {
+        let options = parse(&["--load-state", "tetris.ss1"]).unwrap();
+
+        assert_eq!(Some("tetris.ss1".to_string()), options.load_state);
+    }
+
+    #[test]
+    fn rewind_settings()
+    {
+        let options = parse(&["--rewind-depth", "100", "--rewind-interval", "4", "--rewind-memory", "8"]).unwrap();
+
+        assert_eq!(100, options.rewind_depth);
+        assert_eq!(4, options.rewind_interval);
+        assert_eq!(8 * 1024 * 1024, options.rewind_memory);
+        assert!(parse(&["--rewind-interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn movies()
+    {
+        assert_eq!(Some("run.movie".to_string()), parse(&["--record-movie", "run.movie"]).unwrap().record_movie);
+        assert_eq!(Some("run.movie".to_string()), parse(&["--play-movie", "run.movie", "--timeout", "60"]).unwrap().play_movie);
+        assert!(parse(&["--record-movie", "a.movie", "--play-movie", "b.movie"]).is_err());
+        assert!(parse(&["--record-movie", "run.movie", "--frames", "60"]).is_err());
+        assert!(parse(&["--play-movie", "run.movie", "--link-join", "5000"]).is_err());
+    }
+
+    #[test]
+    fn screenshots()
+    {
+        let options = parse(&["--frames", "60", "--screenshot", "end.png", "--screenshot-scale", "3"]).unwrap();
+
+        assert_eq!(Some("end.png".to_string()), options.screenshot);
+        assert_eq!(3, options.screenshot_scale);
+        assert!(parse(&["--screenshot-scale", "9"]).is_err());
+        assert!(parse(&["--screenshot", "end.png"]).is_err());
+    }
+
+    #[test]
+    fn video_recording()
+    {
+        let options = parse(&["--record-video", "clip.y4m", "--video-frames", "600-1200", "--video-format", "rgb"]).unwrap();
+
+        assert_eq!(Some("clip.y4m".to_string()), options.record_video);
+        assert_eq!(Some((600, 1200)), options.video_frames);
+        assert_eq!(VideoFormat::RawRgb, options.video_format);
+        assert!(options.headless());
+    }
+
+    #[test]
+    fn invalid_video_options()
+    {
+        assert!(parse(&["--record-video", "clip.mp4"]).is_err());
+        assert!(parse(&["--record-video", "clip.gif", "--video-frames", "1200-600"]).is_err());
+        assert!(parse(&["--video-frames", "0-10"]).is_err());
+        assert!(parse(&["--record-video", "clip.gif", "--video-frames", "0-4294967295"]).is_err());
+        assert!(parse(&["--video-format", "webm"]).is_err());
+    }
+
+    #[test]
+    fn headless_stop_conditions()
+    {
+        let options = parse(&["--until-pc", "0x0150", "--until-serial", "Passed", "--timeout", "2.5"]).unwrap();
+
+        assert_eq!(Some(0x0150), options.until_pc);
+        assert_eq!(Some("Passed".to_string()), options.until_serial);
+        assert_eq!(Some(Duration::from_millis(2500)), options.timeout);
+        assert!(options.headless());
+        assert!(!parse(&[]).unwrap().headless());
+    }
+
+    #[test]
+    fn invalid_stop_conditions()
+    {
+        assert!(parse(&["--until-pc", "10000"]).is_err());
+        assert!(parse(&["--timeout", "-1"]).is_err());
+        assert!(parse(&["--timeout", "inf"]).is_err());
+        assert!(parse(&["--timeout", "1e30"]).is_err());
+        assert!(parse(&["--until-serial", "Passed", "--printer", "prints"]).is_err());
+    }
+
+    #[test]
+    fn serial_console_and_log()
+    {
+        let options = parse(&["--serial-console", "--serial-log", "serial.txt"]).unwrap();
+
+        assert!(options.serial_console);
+        assert_eq!(Some("serial.txt".to_string()), options.serial_log);
+    }
+
+    #[test]
+    fn link_cable_ports()
+    {
+        let host = parse(&["--link-host", "5000"]).unwrap();
+        let join = parse(&["--link-join", "5000"]).unwrap();
+
+        assert_eq!(Some(5000), host.link_host);
+        assert_eq!(Some(5000), join.link_join);
+    }
+
+    #[test]
+    fn link_cable_can_not_host_and_join()
+    {
+        assert!(parse(&["--link-host", "5000", "--link-join", "5001"]).is_err());
+        assert!(parse(&["--link-host", "http"]).is_err());
+    }
+
+    #[test]
+    fn serial_console_needs_the_link_port()
+    {
+        assert!(parse(&["--serial-console", "--link-host", "5000"]).is_err());
+        assert!(parse(&["--serial-log", "serial.txt", "--printer", "prints"]).is_err());
+    }
+
+    #[test]
+    fn printer_directory()
+    {
+        let options = parse(&["--printer", "prints"]).unwrap();
+
+        assert_eq!(Some("prints".to_string()), options.printer);
+        assert!(parse(&["--printer", "prints", "--link-join", "5000"]).is_err());
+    }
+
+    #[test]
+    fn palette_without_value()
+    {
+        assert!(parse(&["--palette"]).is_err());
+    }
+
+    #[test]
+    fn unknown_option()
+    {
+        assert!(parse(&["--fast"]).is_err());
+    }
+}
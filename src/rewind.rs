@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+
+use crate::hardware::gameboy::GameBoy;
+
+/*
+
+Rewinding keeps a save state every few frames in a ring buffer and loads
+them back newest first.
+
+Only the newest state is kept whole. Every older one is stored as the XOR
+against the state that came after it, which is zero for almost all of the
+memory, and the zeros are run length encoded. Going back one step XORs the
+newest state with its delta, and the oldest delta gets dropped whenever the
+buffer runs out of depth or over its memory budget.
+
+A delta is a sequence of pairs, each a run of zeros and a run of literal
+bytes with their lengths as u16, followed by the literal bytes.
+
+*/
+
+pub const DEFAULT_INTERVAL: u32 = 2;
+pub const DEFAULT_DEPTH: usize = 600;
+pub const DEFAULT_MEMORY_BUDGET: usize = 32 * 1024 * 1024;
+
+fn compress(data: &[u8]) -> Vec<u8>
+{
+    let mut compressed = vec![];
+    let mut position = 0;
+    while position < data.len()
+    {
+        let zeros = data[position..].iter().take(u16::MAX as usize).take_while(|&&byte| byte == 0).count();
+        position += zeros;
+        let literals = data[position..].iter().take(u16::MAX as usize).take_while(|&&byte| byte != 0).count();
+        compressed.extend_from_slice(&(zeros as u16).to_le_bytes());
+        compressed.extend_from_slice(&(literals as u16).to_le_bytes());
+        compressed.extend_from_slice(&data[position..position + literals]);
+        position += literals;
+    }
+    compressed
+}
+
+/// XORs the compressed delta into the data, growing or shrinking it to the length of the delta
+fn apply(data: &mut Vec<u8>, compressed: &[u8])
+{
+    let mut position = 0;
+    let mut delta = compressed;
+    while delta.len() >= 4
+    {
+        let zeros = u16::from_le_bytes([delta[0], delta[1]]) as usize;
+        let literals = u16::from_le_bytes([delta[2], delta[3]]) as usize;
+        position += zeros;
+        if data.len() < position + literals
+        {
+            data.resize(position + literals, 0);
+        }
+        for (byte, change) in data[position..].iter_mut().zip(&delta[4..4 + literals])
+        {
+            *byte ^= change;
+        }
+        position += literals;
+        delta = &delta[4 + literals..];
+    }
+    data.truncate(position);
+}
+
+/// The XOR of two states, compressed. The length is the one of the older state.
+fn delta(older: &[u8], newer: &[u8]) -> Vec<u8>
+{
+    let xor: Vec<u8> = older.iter().enumerate()
+        .map(|(index, byte)| byte ^ newer.get(index).unwrap_or(&0))
+        .collect();
+    compress(&xor)
+}
+
+pub struct RewindBuffer
+{
+    interval: u32,
+    depth: usize,
+    memory_budget: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    // Oldest first, each one leads from the state after it back to its own
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl RewindBuffer
+{
+    /// Keeps a state every interval frames, at most depth of them in at most memory_budget bytes
+    pub fn new(interval: u32, depth: usize, memory_budget: usize) -> RewindBuffer
+    {
+        RewindBuffer {
+            interval: interval.max(1),
+            depth,
+            memory_budget,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// The amount of states that can be gone back to
+    pub fn len(&self) -> usize
+    {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.newest.is_none()
+    }
+
+    /// The memory used by the stored states in bytes
+    pub fn memory_used(&self) -> usize
+    {
+        self.newest.as_ref().map_or(0, Vec::len) + self.delta_bytes
+    }
+
+    /// Call after every emulated frame, keeps a state every interval frames
+    pub fn record_frame(&mut self, gameboy: &GameBoy)
+    {
+        if self.depth == 0
+        {
+            return;
+        }
+        self.frames += 1;
+        if self.frames >= self.interval
+        {
+            self.frames = 0;
+            self.push(gameboy.save_state());
+        }
+    }
+
+    /// Call instead of running a frame while rewinding, goes back to the previous
+    /// state every interval frames. False once there is nothing left.
+    pub fn rewind_frame(&mut self, gameboy: &mut GameBoy) -> bool
+    {
+        if self.is_empty()
+        {
+            return false;
+        }
+        self.frames += 1;
+        if self.frames >= self.interval
+        {
+            self.frames = 0;
+            if let Some(state) = self.pop()
+            {
+                // The states come from this machine, they always load
+                gameboy.load_state(&state).ok();
+            }
+        }
+        true
+    }
+
+    fn push(&mut self, state: Vec<u8>)
+    {
+        if let Some(newest) = self.newest.take()
+        {
+            let delta = delta(&newest, &state);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(state);
+        while self.len() > self.depth || (self.memory_used() > self.memory_budget && !self.deltas.is_empty())
+        {
+            if let Some(oldest) = self.deltas.pop_front()
+            {
+                self.delta_bytes -= oldest.len();
+            }
+            else
+            {
+                self.newest = None;
+            }
+        }
+    }
+
+    /// Takes the newest state, the one before it becomes the newest
+    fn pop(&mut self) -> Option<Vec<u8>>
+    {
+        let newest = self.newest.take()?;
+        if let Some(delta) = self.deltas.pop_back()
+        {
+            self.delta_bytes -= delta.len();
+            let mut previous = newest.clone();
+            apply(&mut previous, &delta);
+            self.newest = Some(previous);
+        }
+        Some(newest)
+    }
+}
+
+impl Default for RewindBuffer {
+    fn default() -> RewindBuffer
+    {
+        RewindBuffer::new(DEFAULT_INTERVAL, DEFAULT_DEPTH, DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn delta_restores_the_older_state()
+    {
+        let older = vec![1, 0, 0, 5, 6, 0, 0, 0];
+        let newer = vec![1, 0, 2, 5, 7, 0, 9, 0, 3];
+
+        let mut restored = newer.clone();
+        apply(&mut restored, &delta(&older, &newer));
+
+        assert_eq!(older, restored);
+    }
+
+    #[test]
+    fn long_runs_are_split()
+    {
+        let mut older = vec![0; 200_000];
+        older[100_000] = 1;
+        let newer = vec![0; 150_000];
+
+        let compressed = delta(&older, &newer);
+        let mut restored = newer.clone();
+        apply(&mut restored, &compressed);
+
+        assert_eq!(older, restored);
+        assert!(compressed.len() < 40, "{}", compressed.len());
+    }
+
+    #[test]
+    fn rewinds_to_earlier_frames()
+    {
+        let mut gameboy = GameBoy::default();
+        let mut rewind = RewindBuffer::new(1, 10, DEFAULT_MEMORY_BUDGET);
+        let mut program_counters = vec![];
+        for _ in 0..3
+        {
+            gameboy.step();
+            gameboy.step();
+            rewind.record_frame(&gameboy);
+            program_counters.push(gameboy.registers.pc);
+        }
+
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert_eq!(program_counters[2], gameboy.registers.pc);
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert_eq!(program_counters[1], gameboy.registers.pc);
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert_eq!(program_counters[0], gameboy.registers.pc);
+        assert!(!rewind.rewind_frame(&mut gameboy));
+    }
+
+    #[test]
+    fn states_are_kept_every_interval()
+    {
+        let gameboy = GameBoy::default();
+        let mut rewind = RewindBuffer::new(4, 10, DEFAULT_MEMORY_BUDGET);
+
+        for _ in 0..9
+        {
+            rewind.record_frame(&gameboy);
+        }
+
+        assert_eq!(2, rewind.len());
+    }
+
+    #[test]
+    fn depth_drops_the_oldest_states()
+    {
+        let mut gameboy = GameBoy::default();
+        let mut rewind = RewindBuffer::new(1, 3, DEFAULT_MEMORY_BUDGET);
+
+        for _ in 0..5
+        {
+            gameboy.step();
+            rewind.record_frame(&gameboy);
+        }
+
+        assert_eq!(3, rewind.len());
+        for _ in 0..3
+        {
+            rewind.rewind_frame(&mut gameboy);
+        }
+        assert_eq!(0x103, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn memory_budget_drops_the_oldest_states()
+    {
+        let mut gameboy = GameBoy::default();
+        let state_size = gameboy.save_state().len();
+        let mut rewind = RewindBuffer::new(1, 100, state_size + 10);
+
+        for _ in 0..5
+        {
+            gameboy.step();
+            rewind.record_frame(&gameboy);
+        }
+
+        assert!(rewind.memory_used() <= state_size + 10);
+        assert!(rewind.len() < 5);
+    }
+
+    #[test]
+    fn zero_depth_keeps_nothing()
+    {
+        let mut gameboy = GameBoy::default();
+        let mut rewind = RewindBuffer::new(1, 0, DEFAULT_MEMORY_BUDGET);
+
+        rewind.record_frame(&gameboy);
+
+        assert!(rewind.is_empty());
+        assert!(!rewind.rewind_frame(&mut gameboy));
+    }
+}
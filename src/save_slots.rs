@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use rboy::GameBoy;
+
+/*
+
+Numbered save state slots, kept next to the ROM as <rom>.ss0 to <rom>.ss9.
+
+*/
+
+const SLOT_COUNT: u8 = 10;
+
+pub struct SaveSlots
+{
+    rom_path: PathBuf,
+    selected: u8,
+}
+
+impl SaveSlots
+{
+    pub fn new(rom_path: &Path) -> SaveSlots
+    {
+        SaveSlots { rom_path: rom_path.to_path_buf(), selected: 0 }
+    }
+
+    pub fn selected(&self) -> u8
+    {
+        self.selected
+    }
+
+    pub fn select_next(&mut self)
+    {
+        self.selected = (self.selected + 1) % SLOT_COUNT;
+    }
+
+    pub fn select_previous(&mut self)
+    {
+        self.selected = (self.selected + SLOT_COUNT - 1) % SLOT_COUNT;
+    }
+
+    pub fn path(&self) -> PathBuf
+    {
+        self.rom_path.with_extension(format!("ss{}", self.selected))
+    }
+
+    pub fn save(&self, gameboy: &GameBoy) -> std::io::Result<()>
+    {
+        std::fs::write(self.path(), gameboy.save_state())
+    }
+
+    pub fn load(&self, gameboy: &mut GameBoy) -> Result<(), Box<dyn Error>>
+    {
+        let state = std::fs::read(self.path())?;
+        gameboy.load_state(&state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn slots_are_named_after_the_rom()
+    {
+        let mut slots = SaveSlots::new(Path::new("roms/tetris.gb"));
+        assert_eq!(Path::new("roms/tetris.ss0"), slots.path());
+
+        slots.select_previous();
+
+        assert_eq!(9, slots.selected());
+        assert_eq!(Path::new("roms/tetris.ss9"), slots.path());
+        slots.select_next();
+        assert_eq!(0, slots.selected());
+    }
+
+    #[test]
+    fn state_goes_through_the_file()
+    {
+        let directory = std::env::temp_dir().join(format!("rboy-slots-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let slots = SaveSlots::new(&directory.join("game.gb"));
+        let mut gameboy = GameBoy::default();
+        gameboy.registers.pc = 0x1234;
+
+        slots.save(&gameboy).unwrap();
+        gameboy.registers.pc = 0x100;
+        let result = slots.load(&mut gameboy);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(0x1234, gameboy.registers.pc);
+    }
+
+    #[test]
+    fn missing_slot_is_an_error()
+    {
+        let slots = SaveSlots::new(Path::new("/nonexistent/game.gb"));
+
+        assert!(slots.load(&mut GameBoy::default()).is_err());
+    }
+}
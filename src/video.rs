@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hardware::ppu::{FRAME_DOTS, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/*
+
+Video recording of the screen, one picture per emulated frame. The format
+comes from the file extension:
+  * .y4m: YUV4MPEG2 with full chroma, plays in most video tools as it is
+  * .rgb: Raw RGB24 frames, lossless bit for bit. A sidecar <name>.txt next
+    to it holds the ffmpeg command that converts it, together with the WAV
+    recorded next to the video if there is one. The command quotes the paths
+    for a POSIX shell.
+  * .gif: Animated GIF for short clips, with every second frame. GIF delays
+    are in hundredths of a second, so they alternate between 3 and 4 to
+    keep the speed right.
+
+Every GIF frame gets its own palette with exactly the colors on screen. That
+usually fits, only palette changes in the middle of a frame can show more
+than 256 colors, then the frame gets quantized.
+
+*/
+
+const DOTS_PER_SECOND: u32 = 4_194_304;
+const GIF_FRAME_STEP: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VideoFormat
+{
+    Y4m,
+    RawRgb,
+    Gif,
+}
+
+impl VideoFormat
+{
+    pub fn from_path(path: &Path) -> Option<VideoFormat>
+    {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "y4m" => Some(VideoFormat::Y4m),
+            "rgb" => Some(VideoFormat::RawRgb),
+            "gif" => Some(VideoFormat::Gif),
+            _ => None
+        }
+    }
+
+    pub fn extension(self) -> &'static str
+    {
+        match self {
+            VideoFormat::Y4m => "y4m",
+            VideoFormat::RawRgb => "rgb",
+            VideoFormat::Gif => "gif"
+        }
+    }
+}
+
+/// Converts to BT.601 YCbCr with studio swing, what players expect from Y4M
+fn to_ycbcr(pixel: &[u8]) -> (u8, u8, u8)
+{
+    let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+    let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+    let cb = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+    let cr = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+    (y.round() as u8, cb.round() as u8, cr.round() as u8)
+}
+
+/// Hundredths of a second from the start to the given frame
+fn centiseconds(frame: u32) -> u64
+{
+    frame as u64 * FRAME_DOTS as u64 * 100 / DOTS_PER_SECOND as u64
+}
+
+/// The colors of the frame as palette indices, None if there are more than 256
+fn palette_pixels(framebuffer: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>
+{
+    let mut palette = vec![];
+    let mut indices = HashMap::new();
+    let mut pixels = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+    for pixel in framebuffer.chunks(3)
+    {
+        let next_index = indices.len();
+        let index = *indices.entry([pixel[0], pixel[1], pixel[2]]).or_insert(next_index);
+        if index == next_index
+        {
+            if index == 256
+            {
+                return None;
+            }
+            palette.extend_from_slice(pixel);
+        }
+        pixels.push(index as u8);
+    }
+    Some((palette, pixels))
+}
+
+/// Quotes a path for a POSIX shell
+fn shell_quote(path: &Path) -> String
+{
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
+/// The ffmpeg command converting raw RGB frames, with the sound if it was recorded
+fn ffmpeg_command(path: &Path, sound: Option<&Path>) -> String
+{
+    let sound_input = sound.map(|sound| format!(" -i {}", shell_quote(sound))).unwrap_or_default();
+    format!(
+        "ffmpeg -f rawvideo -pixel_format rgb24 -video_size {}x{} -framerate {}/{} -i {}{} -vf scale=iw*4:ih*4:flags=neighbor {}\n",
+        SCREEN_WIDTH, SCREEN_HEIGHT, DOTS_PER_SECOND, FRAME_DOTS, shell_quote(path), sound_input,
+        shell_quote(&path.with_extension("mp4")))
+}
+
+enum Output
+{
+    Y4m(BufWriter<File>),
+    RawRgb(BufWriter<File>),
+    Gif(gif::Encoder<BufWriter<File>>),
+}
+
+pub struct VideoRecorder
+{
+    path: PathBuf,
+    output: Output,
+    frames: u32,
+}
+
+impl VideoRecorder
+{
+    /// Starts a video, the sound recorded next to it goes into the .rgb sidecar
+    pub fn create(path: &Path, sound: Option<&Path>) -> io::Result<VideoRecorder>
+    {
+        let format = VideoFormat::from_path(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Videos need to be .y4m, .rgb or .gif files"))?;
+        let output = match format {
+            VideoFormat::Y4m => {
+                let mut file = BufWriter::new(File::create(path)?);
+                writeln!(file, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", SCREEN_WIDTH, SCREEN_HEIGHT, DOTS_PER_SECOND, FRAME_DOTS)?;
+                Output::Y4m(file)
+            },
+            VideoFormat::RawRgb => {
+                std::fs::write(path.with_extension("txt"), ffmpeg_command(path, sound))?;
+                Output::RawRgb(BufWriter::new(File::create(path)?))
+            },
+            VideoFormat::Gif => {
+                let file = BufWriter::new(File::create(path)?);
+                let mut encoder = gif::Encoder::new(file, SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &[])?;
+                gif::SetParameter::set(&mut encoder, gif::Repeat::Infinite)?;
+                Output::Gif(encoder)
+            }
+        };
+        Ok(VideoRecorder { path: path.to_path_buf(), output, frames: 0 })
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    pub fn frames(&self) -> u32
+    {
+        self.frames
+    }
+
+    /// Adds a frame, the framebuffer holds RGB pixels
+    pub fn record_frame(&mut self, framebuffer: &[u8]) -> io::Result<()>
+    {
+        let frame = self.frames;
+        self.frames += 1;
+        match &mut self.output {
+            Output::Y4m(file) => {
+                let pixels: Vec<(u8, u8, u8)> = framebuffer.chunks(3).map(to_ycbcr).collect();
+                file.write_all(b"FRAME\n")?;
+                file.write_all(&pixels.iter().map(|pixel| pixel.0).collect::<Vec<u8>>())?;
+                file.write_all(&pixels.iter().map(|pixel| pixel.1).collect::<Vec<u8>>())?;
+                file.write_all(&pixels.iter().map(|pixel| pixel.2).collect::<Vec<u8>>())
+            },
+            Output::RawRgb(file) => file.write_all(framebuffer),
+            Output::Gif(encoder) => {
+                if frame % GIF_FRAME_STEP != 0
+                {
+                    return Ok(());
+                }
+                let mut gif_frame = match palette_pixels(framebuffer) {
+                    Some((palette, pixels)) => gif::Frame::from_palette_pixels(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &pixels, &palette, None),
+                    None => {
+                        let mut rgba: Vec<u8> = framebuffer.chunks(3).flat_map(|pixel| vec![pixel[0], pixel[1], pixel[2], 0xFF]).collect();
+                        gif::Frame::from_rgba_speed(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &mut rgba, 10)
+                    }
+                };
+                gif_frame.delay = (centiseconds(frame + GIF_FRAME_STEP) - centiseconds(frame)) as u16;
+                encoder.write_frame(&gif_frame)
+            }
+        }
+    }
+
+    /// Writes what is still buffered. The gif encoder writes the end of a GIF
+    /// when it gets dropped and has no way to report errors from that, so a
+    /// GIF can end up without its last bytes unnoticed.
+    pub fn finish(self) -> io::Result<()>
+    {
+        match self.output {
+            Output::Y4m(mut file) | Output::RawRgb(mut file) => file.flush(),
+            Output::Gif(encoder) => {
+                // Writes the end of the GIF and flushes the file
+                drop(encoder);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf
+    {
+        std::env::temp_dir().join(format!("rboy-{}-{}", std::process::id(), name))
+    }
+
+    fn framebuffer(color: [u8; 3]) -> Vec<u8>
+    {
+        color.iter().cloned().cycle().take(SCREEN_WIDTH * SCREEN_HEIGHT * 3).collect()
+    }
+
+    #[test]
+    fn format_from_extension()
+    {
+        assert_eq!(Some(VideoFormat::Y4m), VideoFormat::from_path(Path::new("clip.y4m")));
+        assert_eq!(Some(VideoFormat::Gif), VideoFormat::from_path(Path::new("clip.GIF")));
+        assert_eq!(None, VideoFormat::from_path(Path::new("clip.mp4")));
+        assert_eq!("rgb", VideoFormat::RawRgb.extension());
+        assert!(VideoRecorder::create(Path::new("clip.mp4"), None).is_err());
+    }
+
+    #[test]
+    fn white_and_black_in_studio_swing()
+    {
+        assert_eq!((235, 128, 128), to_ycbcr(&[0xFF, 0xFF, 0xFF]));
+        assert_eq!((16, 128, 128), to_ycbcr(&[0, 0, 0]));
+    }
+
+    #[test]
+    fn y4m_frames()
+    {
+        let path = temp_path("video.y4m");
+        let mut recorder = VideoRecorder::create(&path, None).unwrap();
+
+        recorder.record_frame(&framebuffer([0xFF, 0xFF, 0xFF])).unwrap();
+        recorder.record_frame(&framebuffer([0, 0, 0])).unwrap();
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"YUV4MPEG2 W160 H144 F4194304:70224 Ip A1:1 C444\nFRAME\n";
+        assert_eq!(&header[..], &data[..header.len()]);
+        assert_eq!(235, data[header.len()]);
+        assert_eq!(header.len() + 6 + 2 * 3 * SCREEN_WIDTH * SCREEN_HEIGHT, data.len());
+    }
+
+    #[test]
+    fn raw_frames_with_sidecar()
+    {
+        let path = temp_path("video.rgb");
+        let mut recorder = VideoRecorder::create(&path, Some(&path.with_extension("wav"))).unwrap();
+
+        recorder.record_frame(&framebuffer([1, 2, 3])).unwrap();
+        recorder.finish().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let sidecar = std::fs::read_to_string(path.with_extension("txt")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("txt")).unwrap();
+
+        assert_eq!(framebuffer([1, 2, 3]), data);
+        assert!(sidecar.contains("-pixel_format rgb24 -video_size 160x144 -framerate 4194304/70224"), "{}", sidecar);
+        assert!(sidecar.contains(&format!(" -i '{}' ", path.with_extension("wav").display())), "{}", sidecar);
+    }
+
+    #[test]
+    fn sidecar_without_sound()
+    {
+        let command = ffmpeg_command(Path::new("clip.rgb"), None);
+
+        assert!(command.contains("-i 'clip.rgb' -vf"), "{}", command);
+        assert!(!command.contains("wav"), "{}", command);
+    }
+
+    #[test]
+    fn sidecar_quotes_paths()
+    {
+        assert_eq!("'my clip.rgb'", shell_quote(Path::new("my clip.rgb")));
+        assert_eq!("'it'\\''s.rgb'", shell_quote(Path::new("it's.rgb")));
+    }
+
+    #[test]
+    fn gif_keeps_every_second_frame()
+    {
+        let path = temp_path("video.gif");
+        let mut recorder = VideoRecorder::create(&path, None).unwrap();
+
+        for color in 0..4
+        {
+            recorder.record_frame(&framebuffer([color * 60, 0, 0])).unwrap();
+        }
+        recorder.finish().unwrap();
+        let file = File::open(&path).unwrap();
+        let mut decoder = gif::Decoder::new(file).read_info().unwrap();
+        let mut delays = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap()
+        {
+            delays.push(frame.delay);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(vec![3, 3], delays);
+    }
+
+    #[test]
+    fn gif_delays_keep_the_speed()
+    {
+        let total: u64 = (0..60).step_by(GIF_FRAME_STEP as usize)
+            .map(|frame| centiseconds(frame + GIF_FRAME_STEP) - centiseconds(frame))
+            .sum();
+
+        assert_eq!(centiseconds(60), total);
+        assert_eq!(100, centiseconds(60));
+    }
+
+    #[test]
+    fn palette_has_exact_colors()
+    {
+        let mut framebuffer = framebuffer([1, 2, 3]);
+        framebuffer[3..6].copy_from_slice(&[4, 5, 6]);
+
+        let (palette, pixels) = palette_pixels(&framebuffer).unwrap();
+
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], palette);
+        assert_eq!(&[0, 1, 0], &pixels[..3]);
+    }
+}
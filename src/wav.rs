@@ -0,0 +1,101 @@
+use std::io::{Result, Seek, SeekFrom, Write};
+
+/*
+
+Writes 16 bit stereo PCM WAV files. The sizes in the RIFF and data chunk
+headers are only known at the end, they get patched in by finish().
+
+*/
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+pub struct WavWriter<W: Write + Seek>
+{
+    output: W,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W>
+{
+    pub fn new(mut output: W, sample_rate: u32) -> Result<WavWriter<W>>
+    {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        output.write_all(b"RIFF")?;
+        output.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        output.write_all(b"WAVE")?;
+        output.write_all(b"fmt ")?;
+        output.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        output.write_all(&1u16.to_le_bytes())?;
+        output.write_all(&CHANNELS.to_le_bytes())?;
+        output.write_all(&sample_rate.to_le_bytes())?;
+        output.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        output.write_all(&block_align.to_le_bytes())?;
+        output.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        output.write_all(b"data")?;
+        output.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { output, data_size: 0 })
+    }
+
+    /// Writes interleaved stereo samples in the range -1.0 to 1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()>
+    {
+        for sample in samples
+        {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.output.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    /// Patches the chunk sizes and hands back the output
+    pub fn finish(mut self) -> Result<W>
+    {
+        self.output.seek(SeekFrom::Start(4))?;
+        self.output.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.output.seek(SeekFrom::Start(40))?;
+        self.output.write_all(&self.data_size.to_le_bytes())?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_describes_16_bit_stereo()
+    {
+        let writer = WavWriter::new(Cursor::new(vec![]), 44100).unwrap();
+
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(44, data.len());
+        assert_eq!(b"RIFF", &data[0..4]);
+        assert_eq!(&[36, 0, 0, 0], &data[4..8]);
+        assert_eq!(&[2, 0], &data[22..24]);
+        assert_eq!(&44100u32.to_le_bytes(), &data[24..28]);
+        assert_eq!(&(44100u32 * 4).to_le_bytes(), &data[28..32]);
+        assert_eq!(&[16, 0], &data[34..36]);
+    }
+
+    #[test]
+    fn samples_are_converted_and_sizes_patched()
+    {
+        let mut writer = WavWriter::new(Cursor::new(vec![]), 48000).unwrap();
+
+        writer.write_samples(&[1.0, -2.0, 0.0, 0.5]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(&[44, 0, 0, 0], &data[4..8]);
+        assert_eq!(&[8, 0, 0, 0], &data[40..44]);
+        assert_eq!(&[0xFF, 0x7F, 0x01, 0x80, 0x00, 0x00, 0xFF, 0x3F], &data[44..]);
+    }
+}